@@ -0,0 +1,106 @@
+//! Benchmarks for the announce hot path: parsing the query, updating the swarm, picking peers and
+//! encoding the response.
+//!
+//! Run with `cargo bench`. Every case announces into a swarm that was filled up beforehand, with
+//! a mix of seeders and leechers from distinct addresses, like a real torrent would have.
+use bittorrent::tracker::Tracker;
+use bittorrent::Opt;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use structopt::StructOpt;
+
+const INFO_HASH: &str = "abcdefghijklmnopqrst";
+const SWARM_SIZES: [u32; 3] = [10, 1_000, 10_000];
+const NUMWANTS: [u32; 3] = [0, 50, 200];
+const THREADS: u32 = 4;
+const ANNOUNCES_PER_THREAD: u32 = 1_000;
+
+fn peer_ip(n: u32) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n))
+}
+
+/// Every fourth peer is a seeder.
+fn peer_left(n: u32) -> u32 {
+    if n.is_multiple_of(4) {
+        0
+    } else {
+        1 << 20
+    }
+}
+
+fn announce_query(info_hash: &str, n: u32, left: u32, numwant: u32) -> String {
+    format!(
+        "info_hash={}&peer_id={:020}&port={}&uploaded=0&downloaded=0&left={}&compact=1&numwant={}",
+        info_hash,
+        n,
+        6881 + n % 1000,
+        left,
+        numwant
+    )
+}
+
+/// A tracker with `size` peers in one swarm.
+fn populated_tracker(size: u32) -> Tracker {
+    let tracker = Tracker::new(Opt::from_iter(&["tracker", "--root", "."]));
+    for n in 0..size {
+        tracker
+            .announce(&announce_query(INFO_HASH, n, peer_left(n), 0), peer_ip(n))
+            .unwrap();
+    }
+    tracker
+}
+
+fn announce(c: &mut Criterion) {
+    let mut group = c.benchmark_group("announce");
+    for &size in SWARM_SIZES.iter() {
+        let tracker = populated_tracker(size);
+        for &numwant in NUMWANTS.iter() {
+            // a leecher that's already in the swarm re-announcing, the most common request
+            let query = announce_query(INFO_HASH, 1, peer_left(1), numwant);
+            group.bench_with_input(
+                BenchmarkId::new(format!("numwant={}", numwant), size),
+                &query,
+                |b, query| b.iter(|| tracker.announce(query, peer_ip(1)).unwrap()),
+            );
+        }
+    }
+    group.finish();
+}
+
+fn concurrent_announce(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_announce");
+    group.throughput(Throughput::Elements(u64::from(
+        THREADS * ANNOUNCES_PER_THREAD,
+    )));
+    for &size in SWARM_SIZES.iter() {
+        let tracker = Arc::new(populated_tracker(size));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                let threads: Vec<_> = (0..THREADS)
+                    .map(|t| {
+                        let tracker = tracker.clone();
+                        thread::spawn(move || {
+                            // each thread re-announces its own slice of the swarm
+                            for i in 0..ANNOUNCES_PER_THREAD {
+                                let n = (t * ANNOUNCES_PER_THREAD + i) % size;
+                                let query = announce_query(INFO_HASH, n, peer_left(n), 50);
+                                tracker.announce(&query, peer_ip(n)).unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                for thread in threads {
+                    thread.join().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, announce, concurrent_announce);
+criterion_main!(benches);
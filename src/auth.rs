@@ -0,0 +1,231 @@
+//! Shared-secret signatures for the admin endpoints.
+//!
+//! An admin request carries an `X-Auth` header holding the unix time it was signed at and an
+//! HMAC-SHA256 of its method, path, query string (empty if there is none) and that time, keyed
+//! with the secret given to `--admin-secret`:
+//!
+//! ```text
+//! X-Auth: <unix seconds>:<hex HMAC-SHA256(secret, "<method>\n<path>\n<query>\n<unix seconds>")>
+//! ```
+//!
+//! Signatures are only accepted within a window around our own clock, so a captured request can't
+//! be replayed later on.
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const AUTH_HEADER: &str = "x-auth";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    // The request has no `X-Auth` header.
+    Missing,
+    // The header isn't `<timestamp>:<hex signature>`.
+    Malformed,
+    // The request was signed too long ago, or too far in the future.
+    Expired,
+    // The signature doesn't match the request.
+    BadSignature,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Missing => write!(f, "missing X-Auth header"),
+            Self::Malformed => write!(f, "X-Auth header isn't '<timestamp>:<signature>'"),
+            Self::Expired => write!(f, "X-Auth timestamp is too far from the current time"),
+            Self::BadSignature => write!(f, "X-Auth signature doesn't match"),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+fn mac(secret: &[u8], method: &str, path: &str, query: &str, timestamp: u64) -> HmacSha256 {
+    // HMAC takes keys of any length
+    let mut mac = HmacSha256::new_from_slice(secret).expect("any key length is valid");
+    for part in [method, path, query].iter() {
+        mac.update(part.as_bytes());
+        mac.update(b"\n");
+    }
+    mac.update(timestamp.to_string().as_bytes());
+    mac
+}
+
+/// Returns the `X-Auth` header value for a `method` request to `path` with `query` made at
+/// `timestamp`.
+pub fn sign(secret: &[u8], method: &str, path: &str, query: &str, timestamp: u64) -> String {
+    let signature = mac(secret, method, path, query, timestamp)
+        .finalize()
+        .into_bytes();
+    format!("{}:{}", timestamp, hex::encode(signature))
+}
+
+/// Whether `timestamp`, in unix seconds, is at most `max_skew` away from `now`. Clocks drift both
+/// ways, so every timestamped token is checked with this: a client whose clock runs a little fast
+/// is treated just like one whose clock runs a little slow.
+pub fn within_skew(timestamp: u64, now: SystemTime, max_skew: Duration) -> bool {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    now.abs_diff(timestamp) <= max_skew.as_secs()
+}
+
+/// Checks the `X-Auth` header of a `method` request to `path` with `query`, accepting timestamps
+/// up to `window` away from `now`, see `within_skew`.
+pub fn verify(
+    secret: &[u8],
+    method: &str,
+    path: &str,
+    query: &str,
+    header: Option<&str>,
+    now: SystemTime,
+    window: Duration,
+) -> Result<(), AuthError> {
+    let header = header.ok_or(AuthError::Missing)?;
+    let mut parts = header.splitn(2, ':');
+    let timestamp: u64 = parts
+        .next()
+        .and_then(|timestamp| timestamp.parse().ok())
+        .ok_or(AuthError::Malformed)?;
+    let signature = parts
+        .next()
+        .and_then(|signature| hex::decode(signature).ok())
+        .ok_or(AuthError::Malformed)?;
+
+    if !within_skew(timestamp, now, window) {
+        return Err(AuthError::Expired);
+    }
+    // compares in constant time, so the signature can't be guessed byte by byte
+    mac(secret, method, path, query, timestamp)
+        .verify_slice(&signature)
+        .map_err(|_| AuthError::BadSignature)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECRET: &[u8] = b"hunter2";
+    const WINDOW: Duration = Duration::from_secs(300);
+
+    fn at(timestamp: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(timestamp)
+    }
+
+    #[test]
+    fn valid_signature() {
+        let header = sign(SECRET, "POST", "/admin/reap", "", 1_000_000);
+        assert!(header.starts_with("1000000:"));
+        let verify_at = |now| {
+            verify(
+                SECRET,
+                "POST",
+                "/admin/reap",
+                "",
+                Some(&header),
+                at(now),
+                WINDOW,
+            )
+        };
+        assert_eq!(verify_at(1_000_000), Ok(()));
+        // a little clock skew in either direction is fine
+        assert_eq!(verify_at(1_000_300), Ok(()));
+        assert_eq!(verify_at(999_700), Ok(()));
+    }
+
+    #[test]
+    fn expired_timestamp() {
+        let header = sign(SECRET, "POST", "/admin/reap", "", 1_000_000);
+        let verify_at = |now| {
+            verify(
+                SECRET,
+                "POST",
+                "/admin/reap",
+                "",
+                Some(&header),
+                at(now),
+                WINDOW,
+            )
+        };
+        assert_eq!(verify_at(1_000_301), Err(AuthError::Expired));
+        assert_eq!(verify_at(999_699), Err(AuthError::Expired));
+    }
+
+    #[test]
+    fn clock_skew() {
+        let skew = Duration::from_secs(30);
+        let header = sign(SECRET, "POST", "/admin/reap", "", 1_000_000);
+        let verify_at = |now| {
+            verify(
+                SECRET,
+                "POST",
+                "/admin/reap",
+                "",
+                Some(&header),
+                at(now),
+                skew,
+            )
+        };
+        // our clock is behind the client's, then ahead of it
+        assert_eq!(verify_at(999_970), Ok(()));
+        assert_eq!(verify_at(1_000_030), Ok(()));
+        assert_eq!(verify_at(999_969), Err(AuthError::Expired));
+        assert_eq!(verify_at(1_000_031), Err(AuthError::Expired));
+
+        assert!(within_skew(
+            1_000_000,
+            at(1_000_000),
+            Duration::from_secs(0)
+        ));
+        assert!(!within_skew(1_000_000, at(999_999), Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn bad_signature() {
+        let now = at(1_000_000);
+        let header = sign(SECRET, "POST", "/admin/reap", "", 1_000_000);
+        let verify = |secret, method, path, query, header| {
+            verify(secret, method, path, query, header, now, WINDOW)
+        };
+
+        assert_eq!(
+            verify(b"hunter3", "POST", "/admin/reap", "", Some(&header)),
+            Err(AuthError::BadSignature)
+        );
+        // a signature is only good for the path it was made for
+        assert_eq!(
+            verify(SECRET, "POST", "/admin/torrents", "", Some(&header)),
+            Err(AuthError::BadSignature)
+        );
+        // or for its method and query string
+        assert_eq!(
+            verify(SECRET, "DELETE", "/admin/reap", "", Some(&header)),
+            Err(AuthError::BadSignature)
+        );
+        assert_eq!(
+            verify(SECRET, "POST", "/admin/reap", "limit=10", Some(&header)),
+            Err(AuthError::BadSignature)
+        );
+        // nor can it be moved to another time
+        let moved = header.replacen("1000000", "1000001", 1);
+        assert_eq!(
+            verify(SECRET, "POST", "/admin/reap", "", Some(&moved)),
+            Err(AuthError::BadSignature)
+        );
+
+        assert_eq!(
+            verify(SECRET, "POST", "/admin/reap", "", None),
+            Err(AuthError::Missing)
+        );
+        for header in ["", "1000000", "abc:00", "1000000:xyz"].iter() {
+            assert_eq!(
+                verify(SECRET, "POST", "/admin/reap", "", Some(header)),
+                Err(AuthError::Malformed)
+            );
+        }
+    }
+}
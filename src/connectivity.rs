@@ -0,0 +1,184 @@
+//! Optional active checks that a peer really accepts incoming connections on the ip/port it
+//! announced, so that we only hand out peers that other clients can actually reach.
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpStream;
+
+use crate::tracker::lock;
+
+// How long to remember that a peer was reachable before checking it again. Peers that weren't are
+// checked again sooner, after the cache's `retry_after`.
+const RESULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub type ConnectFuture = Pin<Box<dyn Future<Output = bool> + Send>>;
+
+/// Tells us whether a peer accepts connections. This is a trait so tests can swap in a stub
+/// instead of opening real sockets.
+pub trait Connector: Send + Sync {
+    fn connect(&self, addr: SocketAddr) -> ConnectFuture;
+}
+
+/// Attempts a plain TCP handshake with the peer.
+pub struct TcpConnector {
+    timeout: Duration,
+}
+
+impl TcpConnector {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl Connector for TcpConnector {
+    fn connect(&self, addr: SocketAddr) -> ConnectFuture {
+        let timeout = self.timeout;
+        Box::pin(async move {
+            matches!(
+                tokio::time::timeout(timeout, TcpStream::connect(addr)).await,
+                Ok(Ok(_))
+            )
+        })
+    }
+}
+
+/// Remembers the outcome of recent connection checks and limits how many new checks we start
+/// each second, since every check costs us an outgoing connection.
+pub struct ConnectCache {
+    // whether each peer accepted our connection, and when we found out
+    results: Mutex<HashMap<SocketAddr, (bool, Instant)>>,
+    // start of the current one second window, and the number of checks started within it
+    window: Mutex<(Instant, u32)>,
+    max_per_sec: u32,
+    // how long to wait before checking again a peer that wasn't reachable, or is being checked
+    retry_after: Duration,
+}
+
+impl ConnectCache {
+    pub fn new(max_per_sec: u32, retry_after: Duration) -> Self {
+        Self {
+            results: Mutex::new(HashMap::new()),
+            window: Mutex::new((Instant::now(), 0)),
+            max_per_sec,
+            retry_after,
+        }
+    }
+
+    // Whether a result from `checked` still holds at `now`.
+    fn is_fresh(&self, reachable: bool, checked: Instant, now: Instant) -> bool {
+        let ttl = if reachable {
+            RESULT_TTL
+        } else {
+            self.retry_after
+        };
+        now.duration_since(checked) < ttl
+    }
+
+    /// Returns whether the peer is known to accept connections. Peers that haven't been checked
+    /// yet, or are still being checked, are not.
+    pub fn is_reachable(&self, addr: &SocketAddr, now: Instant) -> bool {
+        let results = lock(&self.results);
+        match results.get(addr) {
+            Some((reachable, checked)) => *reachable && self.is_fresh(true, *checked, now),
+            None => false,
+        }
+    }
+
+    /// Decides whether to start a new check for this peer: we don't have a fresh result for it and
+    /// we're still under the rate limit. When this returns true the caller must follow up with
+    /// `record`. Peers on port 0 are never checked, nothing can connect to them.
+    pub fn begin_check(&self, addr: SocketAddr, now: Instant) -> bool {
+        if addr.port() == 0 {
+            return false;
+        }
+        let mut results = lock(&self.results);
+        if let Some((reachable, checked)) = results.get(&addr) {
+            if self.is_fresh(*reachable, *checked, now) {
+                return false;
+            }
+        }
+
+        let mut window = lock(&self.window);
+        if now.duration_since(window.0) >= Duration::from_secs(1) {
+            *window = (now, 0);
+        }
+        if window.1 >= self.max_per_sec {
+            // try again the next time this peer announces
+            return false;
+        }
+        window.1 += 1;
+
+        // a placeholder so the peer isn't checked twice at once; unreachable until proven otherwise
+        results.insert(addr, (false, now));
+        true
+    }
+
+    pub fn record(&self, addr: SocketAddr, reachable: bool, now: Instant) {
+        let mut results = lock(&self.results);
+        results.insert(addr, (reachable, now));
+    }
+
+    /// Forgets results that no longer hold, which would otherwise pile up for every peer that ever
+    /// announced.
+    pub fn reap(&self, now: Instant) {
+        let mut results = lock(&self.results);
+        results.retain(|_, (reachable, checked)| self.is_fresh(*reachable, *checked, now));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn port_zero_not_checked() {
+        let cache = ConnectCache::new(10, RETRY_AFTER);
+        let now = Instant::now();
+        assert!(!cache.begin_check("192.168.0.1:0".parse().unwrap(), now));
+        assert!(cache.begin_check("192.168.0.1:1000".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn reap() {
+        let cache = ConnectCache::new(10, RETRY_AFTER);
+        let addr = "192.168.0.1:1000".parse().unwrap();
+        let start = Instant::now();
+        cache.record(addr, true, start);
+
+        cache.reap(start + RESULT_TTL / 2);
+        assert!(cache.is_reachable(&addr, start + RESULT_TTL / 2));
+        assert_eq!(lock(&cache.results).len(), 1);
+
+        cache.reap(start + RESULT_TTL);
+        assert!(lock(&cache.results).is_empty());
+    }
+
+    #[test]
+    fn failures_retried_sooner() {
+        let cache = ConnectCache::new(10, RETRY_AFTER);
+        let addr = "192.168.0.1:1000".parse().unwrap();
+        let start = Instant::now();
+        assert!(cache.begin_check(addr, start));
+        // the check is still running
+        assert!(!cache.begin_check(addr, start + RETRY_AFTER / 2));
+        cache.record(addr, false, start);
+
+        assert!(!cache.begin_check(addr, start + RETRY_AFTER / 2));
+        assert!(cache.begin_check(addr, start + RETRY_AFTER));
+        cache.record(addr, true, start + RETRY_AFTER);
+        assert!(cache.is_reachable(&addr, start + RETRY_AFTER * 2));
+        // successes hold for much longer
+        assert!(!cache.begin_check(addr, start + RETRY_AFTER * 2));
+
+        // failed peers are reaped once they're due for another check
+        cache.record(addr, false, start);
+        cache.reap(start + RETRY_AFTER);
+        assert!(lock(&cache.results).is_empty());
+    }
+}
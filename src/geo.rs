@@ -0,0 +1,123 @@
+//! Coarse geolocation of peers, so that with `--geo-aware` clients are handed peers from their own
+//! region first.
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A coarse region name, like a continent or a datacenter. Shared by every peer in the region.
+pub type Region = Arc<str>;
+
+/// Tells us which region an address is in. This is a trait so that any geoip database can be
+/// plugged in, and so tests can use a stub.
+pub trait GeoLookup: Send + Sync {
+    fn region(&self, ip: IpAddr) -> Option<Region>;
+}
+
+/// Knows no regions at all, which turns geo-aware peer selection into plain random selection.
+pub struct NoLookup;
+
+impl GeoLookup for NoLookup {
+    fn region(&self, _ip: IpAddr) -> Option<Region> {
+        None
+    }
+}
+
+/// Maps address prefixes to regions, using the longest matching prefix.
+pub struct PrefixTable {
+    prefixes: Vec<(IpAddr, u8, Region)>,
+}
+
+impl PrefixTable {
+    /// Reads a table with one `<address>/<prefix length> <region>` entry per line, for example
+    /// `10.0.0.0/8 eu-west`. Blank lines and lines starting with `#` are skipped.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut prefixes = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let prefix = parse_prefix(line).ok_or_else(|| {
+                let msg = format!("line {}: expected '<address>/<length> <region>'", i + 1);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?;
+            prefixes.push(prefix);
+        }
+        Ok(Self { prefixes })
+    }
+}
+
+impl GeoLookup for PrefixTable {
+    fn region(&self, ip: IpAddr) -> Option<Region> {
+        self.prefixes
+            .iter()
+            .filter(|(net, len, _)| in_prefix(ip, *net, *len))
+            .max_by_key(|(_, len, _)| *len)
+            .map(|(_, _, region)| region.clone())
+    }
+}
+
+fn parse_prefix(line: &str) -> Option<(IpAddr, u8, Region)> {
+    let mut fields = line.split_whitespace();
+    let mut prefix = fields.next()?.splitn(2, '/');
+    let net: IpAddr = prefix.next()?.parse().ok()?;
+    let len: u8 = prefix.next()?.parse().ok()?;
+    let region = fields.next()?;
+    let max_len = if net.is_ipv4() { 32 } else { 128 };
+    if len > max_len || fields.next().is_some() {
+        return None;
+    }
+    Some((net, len, region.into()))
+}
+
+fn in_prefix(ip: IpAddr, net: IpAddr, len: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let table = PrefixTable {
+            prefixes: [
+                "10.0.0.0/8 eu",
+                "10.1.0.0/16 us",
+                "2001:db8::/32 ap",
+                "0.0.0.0/0 other",
+            ]
+            .iter()
+            .map(|line| parse_prefix(line).unwrap())
+            .collect(),
+        };
+
+        let region = |ip: &str| table.region(ip.parse().unwrap());
+        assert_eq!(region("10.2.3.4").as_deref(), Some("eu"));
+        assert_eq!(region("10.1.3.4").as_deref(), Some("us"));
+        assert_eq!(region("192.168.0.1").as_deref(), Some("other"));
+        assert_eq!(region("2001:db8::1").as_deref(), Some("ap"));
+        assert_eq!(region("2001:db9::1"), None);
+    }
+
+    #[test]
+    fn invalid_prefixes() {
+        assert!(parse_prefix("10.0.0.0 eu").is_none());
+        assert!(parse_prefix("10.0.0.0/33 eu").is_none());
+        assert!(parse_prefix("10.0.0.0/8").is_none());
+        assert!(parse_prefix("10.0.0.0/8 eu extra").is_none());
+    }
+}
@@ -0,0 +1,477 @@
+//! Bittorrent tracker
+pub mod auth;
+mod connectivity;
+pub mod geo;
+pub mod limiter;
+pub mod metainfo;
+mod snapshot;
+#[cfg(test)]
+mod test_util;
+pub mod tracker;
+pub mod udp;
+use tracker::IntervalOverride;
+
+use std::ffi::OsString;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+// Config file keys are spelled the same as the command line flags, e.g. `peer-timeout = 60`.
+#[derive(Debug, StructOpt, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Opt {
+    /// Read options from a TOML config file. Flags given on the command line override the file.
+    #[structopt(long, parse(from_os_str))]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// Pass in a file or directory to serve.
+    #[structopt(long, parse(from_os_str), default_value = ".")]
+    pub root: PathBuf,
+
+    /// The number of peers to respond with.
+    #[structopt(long, default_value = "50")]
+    pub peers: u32,
+
+    /// Never send more than this many peers in one response, whatever numwant a client asks for.
+    /// Clients that asked for more are warned that their peer list was cut short.
+    #[structopt(long)]
+    pub max_response_peers: Option<u32>,
+
+    /// Never send more than this fraction (0 to 1) of a swarm's peers in one response, so that no
+    /// single announce lists everyone in a small swarm. Unlike `max_response_peers`, clients
+    /// aren't warned when this cuts their list short.
+    #[structopt(long)]
+    pub max_swarm_share: Option<f64>,
+
+    /// A name for this tracker node, advertised in /stats responses to tell apart the nodes of a
+    /// load-balanced deployment.
+    #[structopt(long)]
+    pub tracker_name: Option<String>,
+
+    /// Seconds clients should wait between regular announces.
+    #[structopt(long, default_value = "900")]
+    pub interval: u32,
+
+    /// Seconds seeders should wait between regular announces, instead of `interval`. Seeders only
+    /// announce to stay listed, so they can be asked to come back less often than leechers, and
+    /// aren't asked to come back sooner when they get fewer peers than they wanted.
+    #[structopt(long)]
+    pub seeder_interval: Option<u32>,
+
+    /// Stretch the interval of torrents that get more than this many announces per second
+    /// (averaged over about a minute) in proportion to how far over they are, up to 4 times the
+    /// usual interval.
+    #[structopt(long)]
+    pub busy_announce_rate: Option<f64>,
+
+    /// Use a different announce interval for a torrent, given as `<hex info_hash>=<seconds>`. May
+    /// be repeated.
+    #[structopt(long, number_of_values = 1)]
+    #[serde(default)]
+    pub interval_override: Vec<IntervalOverride>,
+
+    /// Seconds clients must wait at least between announces. Clients that got fewer peers than
+    /// they asked for are told to come back sooner than `interval`, but never sooner than this.
+    #[structopt(long, default_value = "60")]
+    pub min_interval: u32,
+
+    /// Once more than this many torrents are tracked, raise `min_interval` in proportion to how
+    /// many there are (twice as many torrents, twice the minimum), up to `interval`. Every interval
+    /// handed out is at least that long, so many small swarms together can't announce too often.
+    #[structopt(long)]
+    pub interval_floor_torrents: Option<usize>,
+
+    /// Seconds without an announce after which a peer is dropped from its swarm.
+    #[structopt(long, default_value = "1800")]
+    pub peer_timeout: u64,
+
+    /// Seconds to hold on to a peer that sent `stopped`, so that a client restarting right away
+    /// gets its old record back instead of counting as leaving and joining again. The peer isn't
+    /// handed out or counted meanwhile, and is dropped by the first sweep after this long.
+    #[structopt(long)]
+    pub stop_grace_period: Option<u64>,
+
+    /// Seconds to keep serving a computed scrape response to identical scrape requests. 0 disables
+    /// the cache.
+    #[structopt(long, default_value = "0")]
+    pub scrape_cache_ttl: u64,
+
+    /// Only hand out peers that accept a TCP connection on the ip/port they announced. Each new
+    /// peer costs an outgoing connection attempt.
+    #[structopt(long)]
+    pub check_connectable: bool,
+
+    /// The maximum number of connectability checks to start per second.
+    #[structopt(long, default_value = "10")]
+    pub connect_checks_per_sec: u32,
+
+    /// Seconds to wait before checking again a peer that didn't accept our connection. Clients
+    /// often announce before they're listening, so this is much shorter than the hour a
+    /// successful check holds for.
+    #[structopt(long, default_value = "60")]
+    pub connect_retry_interval: u64,
+
+    /// Periodically save the tracker's state to this file, and restore it from there on startup.
+    #[structopt(long, parse(from_os_str))]
+    pub snapshot: Option<PathBuf>,
+
+    /// Pre-populate swarms on startup from this file of `<info_hash> <ip> <port>` lines.
+    #[structopt(long, parse(from_os_str))]
+    pub seed_file: Option<PathBuf>,
+
+    /// Accept announces with `dryrun=1`, which report the response the client would get without
+    /// registering it. Meant for client developers testing their announces.
+    #[structopt(long)]
+    pub allow_dry_run: bool,
+
+    /// The path clients announce to. Some deployments hide it, like `/<random>/announce`, so
+    /// that only clients with the torrent's announce URL can use the tracker.
+    #[structopt(long, default_value = "/announce")]
+    pub announce_path: String,
+
+    /// Also treat requests for `/` that have a query string as announces, for old torrents whose
+    /// announce URL has no path.
+    #[structopt(long)]
+    pub announce_on_root: bool,
+
+    /// Most connections to serve at once. Further connections wait to be accepted until an open
+    /// one is closed.
+    #[structopt(long, default_value = "1024")]
+    pub max_connections: usize,
+
+    /// Close connections that have been idle for this many seconds, rather than letting clients
+    /// keep them open indefinitely.
+    #[structopt(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// Close a connection after serving this many requests on it. Clients reconnect for their next
+    /// request.
+    #[structopt(long)]
+    pub max_requests_per_connection: Option<usize>,
+
+    /// Hand out the most recently seen peers instead of a random sample, so that identical
+    /// announces get identical responses, which caches well.
+    #[structopt(long)]
+    pub stable_peers: bool,
+
+    /// Hand out peers from the client's own region first. Regions come from `--geo-prefixes`.
+    #[structopt(long)]
+    pub geo_aware: bool,
+
+    /// File mapping address prefixes to regions for `--geo-aware`, with one
+    /// `<address>/<prefix length> <region>` entry per line.
+    #[structopt(long, parse(from_os_str))]
+    pub geo_prefixes: Option<PathBuf>,
+
+    /// Refuse to hand peers to leechers that have uploaded less than this many times what they've
+    /// downloaded, summed over their announces. Seeders are exempt. Meant for private trackers.
+    #[structopt(long)]
+    pub min_ratio: Option<f64>,
+
+    /// Fill this fraction (0 to 1) of every peer list with seeders and the rest with leechers,
+    /// instead of sampling the whole swarm at random. Whichever side runs short is made up for by
+    /// the other.
+    #[structopt(long)]
+    pub seeder_share: Option<f64>,
+
+    /// Add a non-standard `client` key to dictionary model peers, naming the client software
+    /// their peer_id says they run. Meant for looking into the makeup of a swarm.
+    #[structopt(long)]
+    pub debug_peer_clients: bool,
+
+    /// Answer announces that we turn away because we're rate limiting or at capacity (see
+    /// `max_ip_announce_rate` and `max_peers`) with an empty peer list and this many seconds as the
+    /// interval, rather than with a failure.
+    #[structopt(long)]
+    pub soft_fail_interval: Option<u32>,
+
+    /// Turn away announces from an address that averages more than this many announces per second
+    /// (over about a minute). Turned away announces don't count, so a client that slows down gets
+    /// back in.
+    #[structopt(long)]
+    pub max_ip_announce_rate: Option<f64>,
+
+    /// Turn away announces from new peers once this many peers are registered across all
+    /// torrents. Peers we already know can keep announcing, and can always leave.
+    #[structopt(long)]
+    pub max_peers: Option<usize>,
+
+    /// Add a warning to announces that got fewer peers than they asked for from a swarm with
+    /// fewer than this many peers, telling the client to try again later.
+    #[structopt(long)]
+    pub sparse_swarm_size: Option<usize>,
+
+    /// Serve the admin endpoints under /admin/, for requests signed with this secret. See the
+    /// `auth` module for how to sign a request.
+    #[structopt(long)]
+    pub admin_secret: Option<String>,
+
+    /// The most seconds a signed admin request's timestamp may be off from our clock, in either
+    /// direction: it bounds both how long a signature can be replayed for and how much clock skew
+    /// between the signer and the tracker is tolerated.
+    #[structopt(long, default_value = "300")]
+    pub admin_auth_window: u64,
+
+    /// Reject scrapes that ask about more than this many torrents.
+    #[structopt(long, default_value = "200")]
+    pub max_scrape_hashes: usize,
+
+    /// Reject scrapes that don't name any torrents, which would otherwise report on every torrent
+    /// the tracker knows. Private trackers don't want to hand out their whole catalog.
+    #[structopt(long)]
+    pub disable_full_scrape: bool,
+
+    /// Reject requests whose URI is longer than this many bytes without parsing them.
+    #[structopt(long, default_value = "16384")]
+    pub max_uri_length: usize,
+
+    /// Don't count leechers that announce with `numwant=0` as incomplete. These are usually stat
+    /// collectors that want to show up in the swarm without downloading anything.
+    #[structopt(long)]
+    pub numwant_zero_observers: bool,
+
+    /// Mask the last octet of IPv4 addresses (and the last 80 bits of IPv6 addresses) in logs.
+    /// Peers are still stored and handed out with their full address.
+    #[structopt(long)]
+    pub anonymize_ips: bool,
+
+    /// Send `Access-Control-Allow-Origin` with this value on /stats and /scrape responses, and
+    /// answer CORS preflight requests for them, so browser dashboards can read them.
+    #[structopt(long)]
+    pub cors_origin: Option<String>,
+
+    /// Register peers at the address they ask for with the `ip` parameter. Otherwise peers are
+    /// always registered at the address their request came from, so they can't point other
+    /// clients at someone else. Only enable this on trusted networks.
+    #[structopt(long)]
+    pub trust_ip_param: bool,
+
+    /// The address of a reverse proxy in front of the tracker. Requests coming from one are taken
+    /// to be from the client their `X-Forwarded-For` header names. May be repeated.
+    #[structopt(long, number_of_values = 1)]
+    #[serde(default)]
+    pub trusted_proxy: Vec<IpAddr>,
+
+    /// Accept announces without a `peer_id`, making one up from the client's address, port and
+    /// `key` instead. The made up peer_id stays the same from one announce to the next, as long as
+    /// the client doesn't move.
+    #[structopt(long)]
+    pub anonymous_announces: bool,
+
+    /// Tell clients the address their announce came from, with the BEP 24 `external ip` key, so
+    /// clients behind NAT can learn their public address.
+    #[structopt(long)]
+    pub external_ip: bool,
+
+    /// Only hand out peers in the address family a client can be reached over, when it's only
+    /// reachable over one of them (including BEP 7 `ipv4`/`ipv6` addresses). Clients can always
+    /// ask for one family with `family=ipv4` or `family=ipv6`.
+    #[structopt(long)]
+    pub match_address_family: bool,
+
+    /// Hand out hosts that registered both an IPv4 and an IPv6 address (with BEP 7) only once, at
+    /// the address in the family of the client asking.
+    #[structopt(long)]
+    pub collapse_dual_stack: bool,
+
+    /// Leave the client making an announce out of the peers it gets back, at every address it's
+    /// registered at.
+    #[structopt(long)]
+    pub exclude_requester: bool,
+
+    /// Reject announces whose parameters contradict each other, like a `completed` event with
+    /// bytes left to download. Otherwise they're handled as usual, with a warning for the client.
+    #[structopt(long)]
+    pub reject_contradictory: bool,
+
+    /// Don't count a `completed` event from a peer the tracker has no record of (say, after a
+    /// restart without a snapshot), since its download was never tracked. That announce doesn't
+    /// register the peer either, and it's warned; its next announce registers it as usual.
+    /// Otherwise such peers are registered and their completion counted.
+    #[structopt(long)]
+    pub ignore_unknown_completions: bool,
+}
+
+impl Opt {
+    /// Parses the command line, then fills in everything that wasn't explicitly passed on it from
+    /// the `--config` file, if there is one.
+    pub fn from_args_and_config<I>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString> + Clone,
+    {
+        let matches = Self::clap().get_matches_from(args);
+        let cli = Self::from_clap(&matches);
+        let path = match &cli.config {
+            Some(path) => path,
+            None => return Ok(cli),
+        };
+
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("couldn't read {}: {}", path.display(), err))?;
+        let file: toml::value::Table = toml::from_str(&contents)
+            .map_err(|err| format!("couldn't parse {}: {}", path.display(), err))?;
+
+        // start from the command line (which includes all the defaults), and let the file replace
+        // any value that wasn't given explicitly
+        let mut merged = match toml::Value::try_from(&cli) {
+            Ok(toml::Value::Table(table)) => table,
+            _ => unreachable!("Opt always serializes to a table"),
+        };
+        for (key, value) in file {
+            if matches.occurrences_of(&key) == 0 {
+                merged.insert(key, value);
+            }
+        }
+
+        toml::Value::Table(merged)
+            .try_into()
+            .map_err(|err| format!("invalid config in {}: {}", path.display(), err))
+    }
+}
+
+/// What the binary was asked to do. Running the tracker is the default, so `tracker --peers 20`
+/// is the same as `tracker serve --peers 20`.
+#[derive(Debug)]
+pub enum Command {
+    Serve(Box<Opt>),
+    Create(CreateOpt),
+    Info(InfoOpt),
+}
+
+impl Command {
+    /// Parses the command line, picking the subcommand by its first argument.
+    pub fn from_args<I>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString> + Clone,
+    {
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        // each subcommand parses the arguments after its name, as if it were its own program
+        match args.get(1).and_then(|arg| arg.to_str()) {
+            Some("serve") => {
+                Opt::from_args_and_config(&args[1..]).map(|opt| Self::Serve(opt.into()))
+            }
+            Some("create") => Ok(Self::Create(CreateOpt::from_iter(&args[1..]))),
+            Some("info") => Ok(Self::Info(InfoOpt::from_iter(&args[1..]))),
+            _ => Opt::from_args_and_config(args).map(|opt| Self::Serve(opt.into())),
+        }
+    }
+}
+
+/// Make a .torrent file to share a file or directory.
+#[derive(Debug, StructOpt)]
+pub struct CreateOpt {
+    /// The file or directory to share.
+    #[structopt(parse(from_os_str))]
+    pub path: PathBuf,
+
+    /// The URL of the tracker to announce to.
+    #[structopt(long)]
+    pub announce: String,
+
+    /// The length of each piece, in bytes.
+    #[structopt(long, default_value = "262144")]
+    pub piece_length: u64,
+
+    /// Where to write the torrent. Defaults to the name of what's shared, with `.torrent` added,
+    /// in the current directory.
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+
+    /// Hash pieces on every core.
+    #[structopt(long)]
+    pub parallel: bool,
+
+    /// Leave out files and directories matching this glob pattern, like `.DS_Store`. Can be given
+    /// more than once.
+    #[structopt(long, number_of_values = 1)]
+    pub exclude: Vec<glob::Pattern>,
+}
+
+/// Print what's in a .torrent file.
+#[derive(Debug, StructOpt)]
+pub struct InfoOpt {
+    /// The .torrent file to read.
+    #[structopt(parse(from_os_str))]
+    pub path: PathBuf,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn config_file_with_cli_override() {
+        let path = env::temp_dir().join(format!("tracker-config-{}.toml", process::id()));
+        fs::write(
+            &path,
+            "peers = 20\npeer-timeout = 60\ntracker-name = \"from-file\"\ncheck-connectable = true\n",
+        )
+        .unwrap();
+        let config = path.to_str().unwrap();
+
+        let opt = Opt::from_args_and_config(&["tracker", "--config", config]).unwrap();
+        assert_eq!(opt.peers, 20);
+        assert_eq!(opt.peer_timeout, 60);
+        assert_eq!(opt.tracker_name.as_deref(), Some("from-file"));
+        assert!(opt.check_connectable);
+        // not in the file, so the default is kept
+        assert_eq!(opt.scrape_cache_ttl, 0);
+
+        let args = ["tracker", "--config", config, "--peer-timeout", "5"];
+        let opt = Opt::from_args_and_config(&args).unwrap();
+        assert_eq!(opt.peers, 20);
+        assert_eq!(opt.peer_timeout, 5);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn config_file_unknown_key() {
+        let path = env::temp_dir().join(format!("tracker-bad-config-{}.toml", process::id()));
+        fs::write(&path, "peer-timeuot = 60\n").unwrap();
+
+        let opt = Opt::from_args_and_config(&["tracker", "--config", path.to_str().unwrap()]);
+        assert!(opt.is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn subcommands() {
+        match Command::from_args(&["tracker", "--peers", "20"]).unwrap() {
+            Command::Serve(opt) => assert_eq!(opt.peers, 20),
+            command => panic!("expected serve, got {:?}", command),
+        }
+        match Command::from_args(&["tracker", "serve", "--peers", "20"]).unwrap() {
+            Command::Serve(opt) => assert_eq!(opt.peers, 20),
+            command => panic!("expected serve, got {:?}", command),
+        }
+
+        let args = [
+            "tracker",
+            "create",
+            "dir",
+            "--announce",
+            "http://t/",
+            "--exclude",
+            ".git",
+        ];
+        match Command::from_args(&args).unwrap() {
+            Command::Create(opt) => {
+                assert_eq!(opt.path, PathBuf::from("dir"));
+                assert_eq!(opt.announce, "http://t/");
+                assert_eq!(opt.piece_length, 262144);
+                assert_eq!(opt.exclude, [glob::Pattern::new(".git").unwrap()]);
+            }
+            command => panic!("expected create, got {:?}", command),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Caps how many connections the server handles at once, so a flood of clients can't make us run
+//! out of file descriptors.
+//!
+//! Every accepted connection holds a permit from a semaphore until it's closed. Once all the
+//! permits are taken we stop accepting, and new connections wait in the listen backlog until an
+//! older one goes away.
+//!
+//! Connections can also be closed once they've sat idle for a while, so a client can't hold on to
+//! a permit forever.
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::stream::{self, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Delay, Instant};
+
+/// How much of the server a single client gets to hold on to.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionLimits {
+    /// Most connections to have open at once.
+    pub max_connections: usize,
+    /// How long a connection may go without sending or receiving anything before it's closed.
+    pub idle_timeout: Option<Duration>,
+    /// Most requests to serve on one connection before closing it.
+    pub max_requests: Option<usize>,
+}
+
+/// A connection that counts against the limit for as long as it's open.
+pub struct LimitedStream {
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    // fires once the connection has been idle for the timeout, which is also kept for resetting it
+    idle: Option<(Duration, Delay)>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl LimitedStream {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Pushes the idle timeout back, since the connection is in use.
+    fn touch(&mut self) {
+        if let Some((timeout, delay)) = &mut self.idle {
+            delay.reset(Instant::now() + *timeout);
+        }
+    }
+
+    fn is_idle(&mut self, cx: &mut Context) -> bool {
+        match &mut self.idle {
+            Some((_, delay)) => Pin::new(delay).poll(cx).is_ready(),
+            None => false,
+        }
+    }
+}
+
+/// Accepts connections from `listener`, but no more than `limits.max_connections` open at a time.
+pub fn incoming(
+    listener: TcpListener,
+    limits: ConnectionLimits,
+) -> impl Stream<Item = io::Result<LimitedStream>> {
+    let permits = Arc::new(Semaphore::new(limits.max_connections));
+    stream::unfold(
+        (listener, permits),
+        move |(mut listener, permits)| async move {
+            // wait for a free slot before accepting, rather than accepting and hanging up right away
+            let permit = permits.clone().acquire_owned().await;
+            let conn = listener
+                .accept()
+                .await
+                .map(|(stream, remote_addr)| LimitedStream {
+                    stream,
+                    remote_addr,
+                    idle: limits
+                        .idle_timeout
+                        .map(|timeout| (timeout, time::delay_for(timeout))),
+                    _permit: permit,
+                });
+            Some((conn, (listener, permits)))
+        },
+    )
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.stream).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                self.touch();
+                Poll::Ready(result)
+            }
+            // an idle connection looks to the server as if the client hung up
+            Poll::Pending if self.is_idle(cx) => Poll::Ready(Ok(0)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if written.is_ready() {
+            self.touch();
+        }
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::StreamExt;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn caps_open_connections() {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = ConnectionLimits {
+            max_connections: 2,
+            idle_timeout: None,
+            max_requests: None,
+        };
+        let mut incoming = Box::pin(incoming(listener, limits));
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+
+        let first = incoming.next().await.unwrap().unwrap();
+        let _second = incoming.next().await.unwrap().unwrap();
+        let third = tokio::time::timeout(Duration::from_millis(50), incoming.next()).await;
+        assert!(third.is_err(), "accepted a connection over the limit");
+
+        // closing a connection makes room for the one that was waiting
+        drop(first);
+        let third = tokio::time::timeout(Duration::from_secs(5), incoming.next()).await;
+        assert!(third.unwrap().unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = ConnectionLimits {
+            max_connections: 2,
+            idle_timeout: Some(Duration::from_millis(100)),
+            max_requests: None,
+        };
+        let mut incoming = Box::pin(incoming(listener, limits));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut conn = incoming.next().await.unwrap().unwrap();
+        client.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        conn.read_exact(&mut buf).await.unwrap();
+
+        // the client went quiet, so the read ends as if it hung up
+        let read = tokio::time::timeout(Duration::from_secs(5), conn.read(&mut buf)).await;
+        assert_eq!(read.unwrap().unwrap(), 0);
+    }
+}
@@ -1,39 +1,221 @@
 //! Bittorrent tracker
-mod metainfo;
-mod tracker;
-use tracker::Tracker;
+use bittorrent::geo::PrefixTable;
+use bittorrent::limiter::{self, ConnectionLimits, LimitedStream};
+use bittorrent::metainfo::{CreateOptions, OwnedMetaInfo};
+use bittorrent::tracker::Tracker;
+use bittorrent::{Command, CreateOpt, InfoOpt, Opt};
 
 use std::convert::Infallible;
+use std::ffi::OsString;
+use std::fs;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use hyper::header::{HeaderValue, CONNECTION};
+use hyper::server::accept;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
-use structopt::StructOpt;
-
-use serde_bencode;
+use hyper::{Body, Request, Response, Server, StatusCode};
+use tokio::net::TcpListener;
 
 const ADDR: [u8; 4] = [127, 0, 0, 1];
 const PORT: u16 = 6969;
+// How often to sweep the tracker for peers that stopped announcing.
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+// How often to save a snapshot of the tracker's state, if `--snapshot` is set.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
-#[derive(Debug, StructOpt, Clone)]
-pub struct Opt {
-    /// Pass in a file or directory to serve.
-    #[structopt(long, parse(from_os_str))]
-    root: PathBuf,
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let command = Command::from_args(std::env::args_os()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    match command {
+        Command::Serve(opt) => run_tracker(*opt).await,
+        Command::Create(opt) => match create(&opt) {
+            Ok(path) => println!("wrote {}", path.display()),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        },
+        Command::Info(opt) => match info(&opt) {
+            Ok(description) => print!("{}", description),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        },
+    }
+}
 
-    /// The number of peers to respond with.
-    #[structopt(long, default_value = "50")]
-    peers: u32,
+/// Makes a torrent as `opt` asks, and returns where it was written.
+fn create(opt: &CreateOpt) -> Result<PathBuf, String> {
+    let options = CreateOptions {
+        parallel: opt.parallel,
+        exclude: opt.exclude.clone(),
+        ..CreateOptions::default()
+    };
+    let is_dir = fs::metadata(&opt.path)
+        .map_err(|err| format!("couldn't read {}: {}", opt.path.display(), err))?
+        .is_dir();
+    let metainfo = if is_dir {
+        OwnedMetaInfo::from_dir(&opt.path, &opt.announce, opt.piece_length, options)
+    } else {
+        OwnedMetaInfo::from_file(&opt.path, &opt.announce, opt.piece_length, options)
+    }
+    .map_err(|err| format!("couldn't make a torrent of {}: {}", opt.path.display(), err))?;
+
+    let output = opt.output.clone().unwrap_or_else(|| {
+        let mut name = OsString::from(&metainfo.info.name);
+        name.push(".torrent");
+        PathBuf::from(name)
+    });
+    let bytes = metainfo.to_bytes().map_err(|err| err.to_string())?;
+    fs::write(&output, bytes)
+        .map_err(|err| format!("couldn't write {}: {}", output.display(), err))?;
+    Ok(output)
 }
 
-#[tokio::main]
-async fn main() {
-    let opt = Opt::from_args();
+/// Describes the torrent at `opt.path`, a field per line.
+fn info(opt: &InfoOpt) -> Result<String, String> {
+    let bytes = fs::read(&opt.path)
+        .map_err(|err| format!("couldn't read {}: {}", opt.path.display(), err))?;
+    let metainfo = OwnedMetaInfo::from_bytes(&bytes)
+        .map_err(|err| format!("{} isn't a valid torrent: {}", opt.path.display(), err))?;
+    let info = &metainfo.info;
+
+    let mut lines = vec![
+        format!("info_hash: {}", hex::encode(metainfo.info_hash())),
+        format!("name: {}", info.name),
+        format!("size: {}", human_size(info.total_length())),
+        format!(
+            "pieces: {} of {}",
+            info.pieces.len() / 20,
+            human_size(info.piece_length)
+        ),
+    ];
+    if let Some(files) = &info.files {
+        lines.push(format!("files: {}", files.len()));
+    }
+    for url in metainfo.announce_urls() {
+        lines.push(format!("announce: {}", url));
+    }
+    Ok(lines.iter().map(|line| format!("{}\n", line)).collect())
+}
+
+/// `bytes` in the largest binary unit it makes at least one of, along with the exact count.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = None;
+    for next in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = Some(next);
+    }
+    match unit {
+        Some(unit) => format!("{:.1} {} ({} bytes)", size, unit, bytes),
+        None => format!("{} bytes", bytes),
+    }
+}
+
+async fn run_tracker(opt: Opt) {
+    let opt = Arc::new(opt);
     let addr = SocketAddr::from((ADDR, PORT));
-    let tracker = Arc::new(Tracker::new(opt));
+    // one tracker for the whole server, shared by every connection
+    let mut tracker = Tracker::new(opt.clone());
+    if let Some(path) = &opt.geo_prefixes {
+        let table = PrefixTable::from_file(path).unwrap_or_else(|err| {
+            eprintln!("couldn't load {}: {}", path.display(), err);
+            process::exit(1);
+        });
+        tracker = tracker.with_geo(Arc::new(table));
+    }
+    if let Err(err) = tracker.load_torrents(&opt.root) {
+        eprintln!(
+            "couldn't load torrents from {}: {}",
+            opt.root.display(),
+            err
+        );
+        process::exit(1);
+    }
+    let tracker = Arc::new(tracker);
+
+    if let Some(path) = opt.snapshot.clone() {
+        if path.exists() {
+            if let Err(err) = tracker.load_snapshot(&path) {
+                eprintln!("couldn't restore {}: {}", path.display(), err);
+                process::exit(1);
+            }
+        } else {
+            // nothing saved yet, so there's nothing to wait for
+            tracker.mark_ready();
+        }
+
+        let saver = tracker.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+            loop {
+                interval.tick().await;
+                // writing the file blocks, so do it on the blocking pool instead of the thread
+                // that serves requests
+                let (saver, path) = (saver.clone(), path.clone());
+                let _ = tokio::task::spawn_blocking(move || {
+                    if let Err(err) = saver.save_snapshot(&path) {
+                        eprintln!("couldn't save {}: {}", path.display(), err);
+                    }
+                })
+                .await;
+            }
+        });
+    }
+
+    if let Some(path) = &opt.seed_file {
+        if let Err(err) = tracker.seed_from_file(path) {
+            eprintln!("couldn't seed peers from {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    }
+
+    let reaper = tracker.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            reaper.reap_expired(Instant::now());
+        }
+    });
 
+    // bind and accept new connections, but only as many at a time as we're allowed to
+    let listener = TcpListener::bind(&addr).await.unwrap_or_else(|err| {
+        eprintln!("couldn't listen on {}: {}", addr, err);
+        process::exit(1);
+    });
+
+    let limits = ConnectionLimits {
+        max_connections: opt.max_connections,
+        idle_timeout: opt.idle_timeout.map(Duration::from_secs),
+        max_requests: opt.max_requests_per_connection,
+    };
+    if let Err(e) = serve(tracker, listener, limits).await {
+        eprintln!("server error: {}", e);
+    }
+}
+
+/// Serves requests from `listener` until the server fails. Every connection shares `tracker`.
+async fn serve(
+    tracker: Arc<Tracker>,
+    listener: TcpListener,
+    limits: ConnectionLimits,
+) -> hyper::Result<()> {
     // futures have to have 'static lifetimes, so they can only hold references to things owned
     // by the future itself
     // async blocks can play the role of a safe 'static-maker
@@ -41,46 +223,306 @@ async fn main() {
     // 'static overall and thus spawnable on a thread pool or other executor, (by holding the data
     // while the future executes?)
 
+    // connections are numbered in the order they're accepted, so that the log lines of requests
+    // on the same connection can be told apart from those of other connections
+    let next_connection_id = AtomicU64::new(0);
+
     // make_service_fn is called for each connection received
     // service_fn is called for each request in that connection
-    let make_service = make_service_fn(|_conn| {
-        // when a new connection appears, clone opt (whose lifetime is longer than that of the
-        // closure) so the connection owns a copy
-        //
-        // the closure object is created on the stack so references to opt are still alive and able
-        // to be cloned.
+    let make_service = make_service_fn(move |conn: &LimitedStream| {
+        // when a new connection appears, clone the Arc (which outlives this closure) so the
+        // connection owns a handle on the tracker
         //
-        // we can't just move opt into this closure because we move opt into a brand new nested
-        // closure that is constructed every time a new connection appears. calling this closure
-        // more than once would mean we move at least twice.
+        // we can't just move the tracker into this closure because we move it into a brand new
+        // nested closure that is constructed every time a new connection appears. calling this
+        // closure more than once would mean we move at least twice.
         let tracker = tracker.clone();
+        let remote_addr = conn.remote_addr();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let id = next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let span = tracing::info_span!("connection", id, remote = %remote_addr);
+        span.in_scope(|| tracing::debug!("accepted"));
 
-        async {
+        async move {
             // this same closure object created here gets called for every request on a single
             // connection
             Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                // move opt into this closure contained within this async block, and service a
-                // request on this connection
+                // move the tracker into this closure contained within this async block, and
+                // service a request on this connection
 
                 // we need to clone this a second time so that the async block below can own its
                 // own copy, otherwise we "leak" a reference to a local of this closure by returning
                 // it in the future created by async.
                 let tracker = tracker.clone();
+                let span = span.clone();
+                let served = requests.fetch_add(1, Ordering::Relaxed) + 1;
 
                 async move {
-                    let response = tracker.handle_session(req);
-                    Ok::<_, Infallible>(Response::new(Body::from(
-                        serde_bencode::to_string(&response).unwrap(),
-                    )))
+                    let mut response = if tracker.store_may_block() {
+                        // keep a store that waits on I/O from holding up the runtime's threads
+                        let serve =
+                            move || span.in_scope(|| tracker.handle_session(req, remote_addr));
+                        tokio::task::spawn_blocking(serve)
+                            .await
+                            .unwrap_or_else(|err| {
+                                tracing::error!(%err, "request handler failed");
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                response
+                            })
+                    } else {
+                        span.in_scope(|| tracker.handle_session(req, remote_addr))
+                    };
+                    // hyper closes the connection after sending a response that says so
+                    if limits.max_requests.is_some_and(|max| served >= max) {
+                        let close = HeaderValue::from_static("close");
+                        response.headers_mut().insert(CONNECTION, close);
+                    }
+                    Ok::<_, Infallible>(response)
                 }
             }))
         }
     });
 
-    // bind and accept new connections
-    let server = Server::bind(&addr).serve(make_service);
+    let incoming = limiter::incoming(listener, limits);
+    Server::builder(accept::from_stream(incoming))
+        .serve(make_service)
+        .await
+}
 
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bittorrent::geo::Region;
+    use bittorrent::tracker::store::{InMemoryStore, PeerStore, Selection, SwarmInfo};
+    use bittorrent::tracker::{InfoHash, Peer, PeerState};
+    use structopt::StructOpt;
+
+    #[tokio::test]
+    async fn connections_share_swarms() {
+        use hyper::Client;
+
+        let opt = Opt::from_iter(&["tracker", "--root", "."]);
+        let tracker = Arc::new(Tracker::new(opt));
+        let listener = TcpListener::bind(SocketAddr::from((ADDR, 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = ConnectionLimits {
+            max_connections: 16,
+            idle_timeout: None,
+            max_requests: None,
+        };
+        tokio::spawn(serve(tracker, listener, limits));
+
+        let announce = |peer_id: &'static str| {
+            let uri = format!(
+                "http://{}/announce?info_hash=abcdefghijklmnopqrst&peer_id={}&port=1000",
+                addr, peer_id
+            );
+            async move {
+                // a new client per announce, so they don't share a connection
+                let response = Client::new().get(uri.parse().unwrap()).await.unwrap();
+                assert!(response.status().is_success());
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            }
+        };
+
+        announce("abcdefghijklmnopqrst").await;
+        let body = announce("bbcdefghijklmnopqrst").await;
+        let find = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+        assert!(find(b"20:abcdefghijklmnopqrst"));
+        assert!(find(b"20:bbcdefghijklmnopqrst"));
+    }
+
+    #[tokio::test]
+    async fn max_requests_per_connection() {
+        use hyper::Client;
+
+        let opt = Opt::from_iter(&["tracker", "--root", "."]);
+        let tracker = Arc::new(Tracker::new(opt));
+        let listener = TcpListener::bind(SocketAddr::from((ADDR, 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = ConnectionLimits {
+            max_connections: 16,
+            idle_timeout: None,
+            max_requests: Some(2),
+        };
+        tokio::spawn(serve(tracker, listener, limits));
+
+        let client = Client::new();
+        let uri: hyper::Uri = format!("http://{}/stats", addr).parse().unwrap();
+        let first = client.get(uri.clone()).await.unwrap();
+        assert!(first.headers().get(CONNECTION).is_none());
+        hyper::body::to_bytes(first.into_body()).await.unwrap();
+        let second = client.get(uri).await.unwrap();
+        assert_eq!(second.headers()[CONNECTION], "close");
+    }
+
+    /// The in-memory store, but claiming to block, like a networked store would. Written against
+    /// the public API only, the way a store outside this crate would be, and only implementing the
+    /// methods a store has to.
+    #[derive(Default)]
+    struct BlockingStore {
+        inner: InMemoryStore,
+        calls: AtomicUsize,
+    }
+
+    impl BlockingStore {
+        fn call(&self) {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl PeerStore for BlockingStore {
+        fn may_block(&self) -> bool {
+            true
+        }
+
+        fn register(
+            &self,
+            info_hash: InfoHash,
+            peer: Peer,
+            update: &dyn Fn(Option<&PeerState>) -> PeerState,
+        ) {
+            self.call();
+            self.inner.register(info_hash, peer, update)
+        }
+
+        fn remove(&self, info_hash: &InfoHash, peer: &Peer, now: Instant) -> bool {
+            self.call();
+            self.inner.remove(info_hash, peer, now)
+        }
+
+        fn sample(
+            &self,
+            info_hash: &InfoHash,
+            count: usize,
+            selection: Selection,
+            region: Option<&Region>,
+            eligible: &dyn Fn(&Peer, &PeerState) -> bool,
+        ) -> Vec<Peer> {
+            self.call();
+            self.inner
+                .sample(info_hash, count, selection, region, eligible)
+        }
+
+        fn stats(&self, info_hash: &InfoHash, now: Instant) -> Option<SwarmInfo> {
+            self.call();
+            self.inner.stats(info_hash, now)
+        }
+
+        fn reap(&self, now: Instant, timeout: Duration) {
+            self.call();
+            self.inner.reap(now, timeout)
+        }
+    }
+
+    #[tokio::test]
+    async fn blocking_store() {
+        use hyper::Client;
+
+        let store = Arc::new(BlockingStore::default());
+        let opt = Opt::from_iter(&["tracker", "--root", "."]);
+        let tracker = Arc::new(Tracker::new(opt).with_store(store.clone()));
+        assert!(tracker.store_may_block());
+        let listener = TcpListener::bind(SocketAddr::from((ADDR, 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = ConnectionLimits {
+            max_connections: 16,
+            idle_timeout: None,
+            max_requests: None,
+        };
+        tokio::spawn(serve(tracker, listener, limits));
+
+        for peer_id in ["abcdefghijklmnopqrst", "bbcdefghijklmnopqrst"] {
+            let uri = format!(
+                "http://{}/announce?info_hash=abcdefghijklmnopqrst&peer_id={}&port=1000",
+                addr, peer_id
+            );
+            let response = Client::new().get(uri.parse().unwrap()).await.unwrap();
+            assert!(response.status().is_success());
+        }
+        assert!(store.calls.load(Ordering::Relaxed) > 0);
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        assert_eq!(
+            store.inner.stats(&info_hash, Instant::now()).unwrap().peers,
+            2
+        );
+    }
+
+    /// Collects everything logged while it's the default subscriber.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_ids() {
+        use hyper::Client;
+
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        // the test runtime runs the server on this same thread, so it logs here too
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let opt = Opt::from_iter(&["tracker", "--root", "."]);
+        let tracker = Arc::new(Tracker::new(opt));
+        let listener = TcpListener::bind(SocketAddr::from((ADDR, 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = ConnectionLimits {
+            max_connections: 16,
+            idle_timeout: None,
+            max_requests: None,
+        };
+        tokio::spawn(serve(tracker, listener, limits));
+
+        let uri: hyper::Uri = format!(
+            "http://{}/announce?info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst\
+            &port=1000",
+            addr
+        )
+        .parse()
+        .unwrap();
+        // two requests on one connection, then one on another
+        let client = Client::new();
+        for client in [&client, &client, &Client::new()] {
+            let response = client.get(uri.clone()).await.unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let ids: Vec<&str> = logs
+            .lines()
+            .filter(|line| line.contains(" announce "))
+            .map(|line| {
+                let start = line
+                    .find("connection{id=")
+                    .expect("logged in a connection span");
+                line[start..].split_whitespace().next().unwrap()
+            })
+            .collect();
+        assert_eq!(ids.len(), 3, "{}", logs);
+        assert_eq!(ids[0], ids[1]);
+        assert_ne!(ids[1], ids[2]);
     }
 }
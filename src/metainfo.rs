@@ -1,8 +1,82 @@
 //! This module can be used to generate metainfo (.torrent) files, as specified in
 //! [BEP 0003](https://www.bittorrent.org/beps/bep_0003.html) and
 //! [BitTorrentSpecification](https://wiki.theory.org/index.php/BitTorrentSpecification)
-use serde::Serialize;
-use serde_bencode;
+use glob::{MatchOptions, Pattern};
+use rayon::prelude::*;
+use serde::de::Error as _;
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// The fewest pieces a new torrent is split into, unless its content is too small to be. With
+/// fewer, peers can't download different parts of it from several others at once.
+const MIN_PIECES: u64 = 8;
+/// The most pieces a new torrent is split into. Each one adds 20 bytes to the info dictionary,
+/// which every peer has to fetch before it can start.
+const MAX_PIECES: u64 = 1 << 18;
+/// The shortest pieces `MIN_PIECES` asks for: a single block request, which is all some clients
+/// can handle.
+const MIN_PIECE_LENGTH: u64 = 1 << 14;
+/// How much of a file is read at a time while hashing it. Content is never held in memory all at
+/// once, so torrents can be made of files larger than it.
+const READ_BUFFER_LEN: usize = 1 << 16;
+/// About how much content is gathered up before it's hashed, when hashing in parallel. Batches are
+/// made of whole pieces, so there's always at least one.
+const PARALLEL_BATCH_LEN: u64 = 1 << 26;
+
+/// Everything that can go wrong while building a torrent.
+#[derive(Debug)]
+pub enum MetaInfoError {
+    Io(io::Error),
+    // There's no content to make a torrent of: an empty file, or a directory without any files in
+    // it that have something in them.
+    Empty,
+    // The piece length doesn't split the content into a sensible number of pieces.
+    InvalidPieceLength(String),
+    // The torrent couldn't be encoded, or didn't decode back into one.
+    Bencode(serde_bencode::Error),
+}
+
+impl fmt::Display for MetaInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "couldn't read content: {}", err),
+            Self::Empty => write!(f, "there's no content to make a torrent of"),
+            Self::InvalidPieceLength(msg) => write!(f, "{}", msg),
+            Self::Bencode(err) => write!(f, "torrent isn't valid bencode: {}", err),
+        }
+    }
+}
+
+impl Error for MetaInfoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Bencode(err) => Some(err),
+            Self::Empty | Self::InvalidPieceLength(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for MetaInfoError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_bencode::Error> for MetaInfoError {
+    fn from(err: serde_bencode::Error) -> Self {
+        Self::Bencode(err)
+    }
+}
 
 #[derive(Serialize)]
 pub struct MetaInfo<'a> {
@@ -30,19 +104,24 @@ pub enum InfoInner<'a> {
         pieces: &'a str,
         // length of the file in bytes
         length: u64,
-        // md5sum of the file
-        md5sum: Option<&'a [u8; 32]>,
+        // md5sum of the file, as 32 hex digits
+        md5sum: Option<&'a str>,
+        // the tracker the torrent was made for, so the same content gets a different info_hash on
+        // every private tracker it's cross-seeded to
+        source: Option<&'a str>,
     },
     MultipleFile {
         // directory name
         name: &'a str,
         // same as in SingleFile
         #[serde(rename = "piece length")]
-        piece_length: u32,
+        piece_length: u64,
         // same as in SingleFile
         pieces: &'a str,
         // list of files to distribute
         files: Vec<MetaInfoFile<'a>>,
+        // same as in SingleFile
+        source: Option<&'a str>,
     },
 }
 
@@ -52,18 +131,1285 @@ pub struct MetaInfoFile<'a> {
     pub length: u64,
     // path to the file, each element is a directory except for the last, which is a filename
     pub path: Vec<&'a str>,
-    pub md5sum: Option<&'a [u8; 32]>,
+    pub md5sum: Option<&'a str>,
 }
 
 impl<'a> MetaInfo<'a> {
     pub fn bencode(&self) -> serde_bencode::Result<String> {
         serde_bencode::to_string(self)
     }
+
+    /// Marks the torrent as made for the tracker called `source`. This goes in the info
+    /// dictionary, so it changes the info_hash.
+    pub fn with_source(mut self, source: &'a str) -> Self {
+        match &mut self.info {
+            InfoInner::SingleFile { source: field, .. }
+            | InfoInner::MultipleFile { source: field, .. } => *field = Some(source),
+        }
+        self
+    }
+
+    /// The SHA1 hash of the info dictionary, which identifies the torrent to trackers and peers.
+    pub fn info_hash(&self) -> serde_bencode::Result<[u8; 20]> {
+        Ok(Sha1::digest(serde_bencode::to_bytes(&self.info)?).into())
+    }
+}
+
+/// A metainfo file that was read in, as opposed to `MetaInfo` which is only used to write new ones.
+///
+/// Everything in the file is kept, including keys we don't know about, so that it can be written
+/// back out byte for byte. The info dictionary in particular is kept exactly as it was read, since
+/// the torrent is identified by its hash.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OwnedMetaInfo {
+    pub info: Info,
+    // the info dictionary exactly as it appeared in the file
+    raw_info: Vec<u8>,
+    // every other key of the top level dictionary, such as 'announce' or 'creation date'
+    fields: HashMap<Vec<u8>, Value>,
+}
+
+/// The parts of the info dictionary the tracker understands. Other keys (like 'private') are only
+/// kept in the raw info dictionary.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Info {
+    pub name: String,
+    #[serde(rename = "piece length")]
+    pub piece_length: u64,
+    // concatenation of the 20-byte SHA1 hash of each piece
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    // only present in single file torrents
+    pub length: Option<u64>,
+    // only present in multiple file torrents
+    pub files: Option<Vec<InfoFile>>,
+    // the tracker the torrent was made for, if any
+    pub source: Option<String>,
+}
+
+impl Info {
+    /// How many bytes of content the torrent has, over all of its files.
+    pub fn total_length(&self) -> u64 {
+        match &self.files {
+            Some(files) => files.iter().map(|file| file.length).sum(),
+            None => self.length.unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct InfoFile {
+    pub length: u64,
+    pub path: Vec<String>,
+}
+
+impl OwnedMetaInfo {
+    pub fn from_bytes(bytes: &[u8]) -> serde_bencode::Result<Self> {
+        let mut fields = match serde_bencode::from_bytes(bytes)? {
+            Value::Dict(fields) => fields,
+            _ => return Err(serde_bencode::Error::custom("metainfo is not a dictionary")),
+        };
+        fields.remove(&b"info"[..]);
+
+        let raw_info = find_info(bytes)
+            .ok_or_else(|| serde_bencode::Error::missing_field("info"))?
+            .to_vec();
+        let info = serde_bencode::from_bytes(&raw_info)?;
+        Ok(Self {
+            info,
+            raw_info,
+            fields,
+        })
+    }
+
+    pub fn to_bytes(&self) -> serde_bencode::Result<Vec<u8>> {
+        let mut entries = vec![(&b"info"[..], self.raw_info.clone())];
+        for (key, value) in &self.fields {
+            entries.push((key, serde_bencode::to_bytes(value)?));
+        }
+        // bencoded dictionaries are sorted by their raw keys
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut bytes = vec![b'd'];
+        for (key, value) in entries {
+            bytes.extend_from_slice(format!("{}:", key.len()).as_bytes());
+            bytes.extend_from_slice(key);
+            bytes.extend_from_slice(&value);
+        }
+        bytes.push(b'e');
+        Ok(bytes)
+    }
+
+    pub fn announce(&self) -> Option<&str> {
+        match self.fields.get(&b"announce"[..]) {
+            Some(Value::Bytes(announce)) => std::str::from_utf8(announce).ok(),
+            _ => None,
+        }
+    }
+
+    /// Every tracker the torrent can be announced to: `announce`, then the tiers of its BEP 12
+    /// `announce-list` in order, each URL only once.
+    pub fn announce_urls(&self) -> Vec<&str> {
+        let mut urls: Vec<&str> = self.announce().into_iter().collect();
+        let tiers = match self.fields.get(&b"announce-list"[..]) {
+            Some(Value::List(tiers)) => tiers,
+            _ => return urls,
+        };
+        for tier in tiers {
+            let tier = match tier {
+                Value::List(tier) => tier,
+                _ => continue,
+            };
+            for url in tier {
+                if let Value::Bytes(url) = url {
+                    match std::str::from_utf8(url) {
+                        Ok(url) if !urls.contains(&url) => urls.push(url),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        urls
+    }
+
+    /// The info dictionary exactly as it appeared in the file.
+    pub fn raw_info(&self) -> &[u8] {
+        &self.raw_info
+    }
+
+    /// The SHA1 hash of the info dictionary, which identifies the torrent to trackers and peers.
+    pub fn info_hash(&self) -> [u8; 20] {
+        Sha1::digest(&self.raw_info).into()
+    }
+
+    /// Builds a single file torrent of the file at `path`, split into pieces of `piece_length`
+    /// bytes, to be announced to `announce`.
+    pub fn from_file(
+        path: &Path,
+        announce: &str,
+        piece_length: u64,
+        options: CreateOptions,
+    ) -> Result<Self, MetaInfoError> {
+        let length = fs::metadata(path)?.len();
+        check_piece_length(length, piece_length)?;
+        let mut pieces = PieceHasher::new(piece_length, length, options);
+        pieces.read_file(path, length)?;
+
+        let mut info = HashMap::new();
+        info.insert(b"name".to_vec(), bytes(file_name(path)?));
+        info.insert(b"length".to_vec(), int(length));
+        Self::build(info, pieces.finish(), announce, piece_length)
+    }
+
+    /// Builds a multiple file torrent of every file under the directory at `path` that isn't
+    /// excluded by `options`, split into pieces of `piece_length` bytes, to be announced to
+    /// `announce`. Pieces run on from the end of one file into the next, in the order of the file
+    /// list.
+    ///
+    /// The order of the file list changes the info_hash, so it doesn't depend on the order the
+    /// filesystem lists directories in: files are sorted by `compare_paths`, and the same content
+    /// always makes the same torrent.
+    pub fn from_dir(
+        path: &Path,
+        announce: &str,
+        piece_length: u64,
+        options: CreateOptions,
+    ) -> Result<Self, MetaInfoError> {
+        let mut files = Vec::new();
+        walk(path, &options.exclude, &mut Vec::new(), &mut files)?;
+        files.sort_by(|(a, _), (b, _)| compare_paths(a, b));
+        let total = files.iter().map(|(_, length)| length).sum();
+        check_piece_length(total, piece_length)?;
+
+        let mut pieces = PieceHasher::new(piece_length, total, options);
+        let mut list = Vec::new();
+        for (components, length) in files {
+            let file = components
+                .iter()
+                .fold(path.to_path_buf(), |file, c| file.join(c));
+            pieces.read_file(&file, length)?;
+            let mut entry = HashMap::new();
+            entry.insert(b"length".to_vec(), int(length));
+            let components = components.into_iter().map(bytes).collect();
+            entry.insert(b"path".to_vec(), Value::List(components));
+            list.push(Value::Dict(entry));
+        }
+
+        let mut info = HashMap::new();
+        info.insert(b"name".to_vec(), bytes(file_name(path)?));
+        info.insert(b"files".to_vec(), Value::List(list));
+        Self::build(info, pieces.finish(), announce, piece_length)
+    }
+
+    /// Hashes the torrent's content as found under `data_root`, where a client would have
+    /// downloaded it to, and checks it against the torrent's pieces. Files that are missing or
+    /// too short only spoil the pieces they're in.
+    pub fn verify_data(&self, data_root: &Path) -> Result<VerifyReport, MetaInfoError> {
+        let piece_length = self.info.piece_length;
+        if piece_length == 0 {
+            let msg = "piece length must be positive".to_string();
+            return Err(MetaInfoError::InvalidPieceLength(msg));
+        }
+        let root = torrent_path(data_root, std::slice::from_ref(&self.info.name))?;
+        let files = match (&self.info.files, self.info.length) {
+            (Some(files), _) => files
+                .iter()
+                .map(|file| Ok((torrent_path(&root, &file.path)?, file.length)))
+                .collect::<io::Result<Vec<_>>>()?,
+            (None, Some(length)) => vec![(root, length)],
+            (None, None) => return Err(MetaInfoError::Empty),
+        };
+
+        let total = files.iter().map(|(_, length)| length).sum();
+        let mut hasher = PieceHasher::new(piece_length, total, CreateOptions::default());
+        let mut bad_pieces = BTreeSet::new();
+        let mut offset = 0;
+        for (path, length) in files {
+            let read = match fs::File::open(&path) {
+                Ok(file) => hasher.read(file.take(length))?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+                Err(err) => return Err(err.into()),
+            };
+            if read < length {
+                // zeros stand in for what's missing, so that the pieces after it still line up
+                hasher.read(io::repeat(0).take(length - read))?;
+                let (start, end) = (offset + read, offset + length);
+                bad_pieces.extend(start / piece_length..=(end - 1) / piece_length);
+            }
+            offset += length;
+        }
+
+        let hashes = hasher.finish();
+        let pieces = self.info.pieces.len() / 20;
+        for (i, expected) in self.info.pieces.chunks_exact(20).enumerate() {
+            if hashes.get(i * 20..(i + 1) * 20) != Some(expected) {
+                bad_pieces.insert(i as u64);
+            }
+        }
+        Ok(VerifyReport {
+            pieces,
+            bad_pieces: bad_pieces
+                .into_iter()
+                .map(|i| i as usize)
+                .filter(|&i| i < pieces)
+                .collect(),
+        })
+    }
+
+    /// Finishes off a new torrent's `info` dictionary with its concatenated piece hashes, and
+    /// wraps it up with its announce URL.
+    fn build(
+        mut info: HashMap<Vec<u8>, Value>,
+        pieces: Vec<u8>,
+        announce: &str,
+        piece_length: u64,
+    ) -> Result<Self, MetaInfoError> {
+        info.insert(b"piece length".to_vec(), int(piece_length));
+        info.insert(b"pieces".to_vec(), Value::Bytes(pieces));
+
+        let mut metainfo = HashMap::new();
+        metainfo.insert(b"announce".to_vec(), bytes(announce.to_string()));
+        metainfo.insert(b"info".to_vec(), Value::Dict(info));
+        let encoded = serde_bencode::to_bytes(&Value::Dict(metainfo))?;
+        Ok(Self::from_bytes(&encoded)?)
+    }
+}
+
+/// How well the content on disk matches a torrent, from `OwnedMetaInfo::verify_data`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyReport {
+    /// How many pieces the torrent has.
+    pub pieces: usize,
+    /// The indices of the pieces whose content doesn't match its hash, in order.
+    pub bad_pieces: Vec<usize>,
+}
+
+impl VerifyReport {
+    /// Whether every piece matches.
+    pub fn is_complete(&self) -> bool {
+        self.bad_pieces.is_empty()
+    }
+}
+
+/// How a new torrent is made, as opposed to what goes in it.
+#[derive(Default)]
+pub struct CreateOptions<'a> {
+    /// Hash pieces on every core instead of one at a time. The torrent comes out the same.
+    pub parallel: bool,
+    /// Called with how many bytes have been hashed so far and how many there are in all, every
+    /// so often while hashing, the last time with the two equal. It's called from the thread that
+    /// builds the torrent, never from the pool hashing in parallel, and without any locks held.
+    pub progress: Option<&'a mut dyn FnMut(u64, u64)>,
+    /// Files and directories to leave out of a torrent made from a directory, like `.DS_Store` or
+    /// `.git`. A pattern matches either an entry's name or its path within the directory, like
+    /// `sub/*.tmp`. Excluding a directory leaves out everything in it.
+    pub exclude: Vec<Pattern>,
+}
+
+/// Hashes a piece. Torrents always use SHA1, but going through this instead of `Sha1` itself lets
+/// tests check how content is split into pieces with hashes they can read, and leaves room for a
+/// faster SHA1 than the one we depend on.
+trait Hasher: Default {
+    /// Adds `data` to the piece being hashed.
+    fn update(&mut self, data: &[u8]);
+    /// The hash of the piece, after which the hasher starts on a new one.
+    fn finish(&mut self) -> [u8; 20];
+}
+
+impl Hasher for Sha1 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(&mut self) -> [u8; 20] {
+        self.finalize_reset().into()
+    }
+}
+
+/// Hashes content into pieces as it's read. Pieces don't care where one file ends and the next
+/// begins, so the hash of a piece is carried over from one read to the next.
+struct PieceHasher<'a, H = Sha1> {
+    piece_length: u64,
+    // the piece being read, and how many of its bytes have been hashed so far
+    piece: H,
+    piece_read: u64,
+    // when hashing in parallel, content is gathered here instead, and hashed once there's
+    // `batch_len` of it
+    batch: Option<Vec<u8>>,
+    batch_len: u64,
+    pieces: Vec<u8>,
+    // how much content has been hashed, out of `total`
+    hashed: u64,
+    total: u64,
+    progress: Option<&'a mut dyn FnMut(u64, u64)>,
+}
+
+impl<'a> PieceHasher<'a> {
+    /// A SHA1 hasher for `total` bytes of content.
+    fn new(piece_length: u64, total: u64, options: CreateOptions<'a>) -> Self {
+        Self::with_hasher(piece_length, total, options)
+    }
+}
+
+impl<'a, H: Hasher> PieceHasher<'a, H> {
+    fn with_hasher(piece_length: u64, total: u64, options: CreateOptions<'a>) -> Self {
+        Self {
+            piece_length,
+            piece: H::default(),
+            piece_read: 0,
+            batch: Some(Vec::new()).filter(|_| options.parallel),
+            batch_len: (PARALLEL_BATCH_LEN / piece_length).max(1) * piece_length,
+            pieces: Vec::new(),
+            hashed: 0,
+            total,
+            progress: options.progress,
+        }
+    }
+
+    /// Counts `len` more bytes as hashed, and says so.
+    fn hashed(&mut self, len: u64) {
+        self.hashed += len;
+        if let Some(progress) = &mut self.progress {
+            progress(self.hashed, self.total);
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        if let Some(mut batch) = self.batch.take() {
+            while !data.is_empty() {
+                let left = self.batch_len - batch.len() as u64;
+                let (head, rest) = data.split_at(data.len().min(left as usize));
+                batch.extend_from_slice(head);
+                if batch.len() as u64 == self.batch_len {
+                    hash_batch::<H>(&mut batch, self.piece_length, &mut self.pieces);
+                    self.hashed(self.batch_len);
+                }
+                data = rest;
+            }
+            self.batch = Some(batch);
+            return;
+        }
+        let len = data.len() as u64;
+        while !data.is_empty() {
+            let left = self.piece_length - self.piece_read;
+            let (head, rest) = data.split_at(data.len().min(left as usize));
+            self.piece.update(head);
+            self.piece_read += head.len() as u64;
+            if self.piece_read == self.piece_length {
+                self.pieces.extend_from_slice(&self.piece.finish());
+                self.piece_read = 0;
+            }
+            data = rest;
+        }
+        self.hashed(len);
+    }
+
+    /// Hashes everything `reader` has to give through a fixed size buffer, returning how many
+    /// bytes that was.
+    fn read(&mut self, mut reader: impl Read) -> io::Result<u64> {
+        let mut buf = vec![0; READ_BUFFER_LEN];
+        let mut total = 0;
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(total),
+                Ok(n) => {
+                    self.update(&buf[..n]);
+                    total += n as u64;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Hashes the file at `path`, which was `length` bytes long when its entry was made.
+    fn read_file(&mut self, path: &Path, length: u64) -> io::Result<()> {
+        let read = self.read(fs::File::open(path)?.take(length))?;
+        if read != length {
+            let msg = format!("{} changed while it was being hashed", path.display());
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg));
+        }
+        Ok(())
+    }
+
+    /// The hashes of every piece, the last one being as long as what's left over.
+    fn finish(mut self) -> Vec<u8> {
+        if let Some(mut batch) = self.batch.take() {
+            let len = batch.len() as u64;
+            hash_batch::<H>(&mut batch, self.piece_length, &mut self.pieces);
+            if len > 0 {
+                self.hashed(len);
+            }
+        }
+        if self.piece_read > 0 {
+            self.pieces.extend_from_slice(&self.piece.finish());
+        }
+        self.pieces
+    }
+}
+
+/// Hashes the pieces in `batch` on every core, adding their hashes to `pieces` in order, and
+/// empties it for the next batch.
+fn hash_batch<H: Hasher>(batch: &mut Vec<u8>, piece_length: u64, pieces: &mut Vec<u8>) {
+    let hashes: Vec<_> = batch
+        .par_chunks(piece_length as usize)
+        .map(|piece| {
+            let mut hasher = H::default();
+            hasher.update(piece);
+            hasher.finish()
+        })
+        .collect();
+    pieces.extend(hashes.into_iter().flatten());
+    batch.clear();
+}
+
+/// Checks that there's content at all, and that `piece_length` splits its `length` bytes into a
+/// sensible number of pieces, between `MIN_PIECES` and `MAX_PIECES`.
+fn check_piece_length(length: u64, piece_length: u64) -> Result<(), MetaInfoError> {
+    let invalid = |msg: String| Err(MetaInfoError::InvalidPieceLength(msg));
+    if length == 0 {
+        return Err(MetaInfoError::Empty);
+    }
+    if piece_length == 0 {
+        return invalid("piece length must be positive".to_string());
+    }
+    let pieces = length.div_ceil(piece_length);
+    if pieces > MAX_PIECES {
+        return invalid(format!(
+            "piece length {} splits {} bytes into {} pieces, more than {}; use longer pieces",
+            piece_length, length, pieces, MAX_PIECES
+        ));
+    }
+    // small enough content can't be split into more pieces without making them tiny
+    if pieces < MIN_PIECES && length >= MIN_PIECES * MIN_PIECE_LENGTH {
+        return invalid(format!(
+            "piece length {} splits {} bytes into only {} pieces, fewer than {}; use shorter \
+             pieces",
+            piece_length, length, pieces, MIN_PIECES
+        ));
+    }
+    Ok(())
+}
+
+/// Collects every file under `dir`, which is at `prefix` in the torrent, into `files` along with
+/// its length, skipping anything matching one of the `exclude` patterns.
+fn walk(
+    dir: &Path,
+    exclude: &[Pattern],
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(Vec<String>, u64)>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        prefix.push(file_name(&path)?);
+        if is_excluded(exclude, prefix) {
+            prefix.pop();
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            walk(&path, exclude, prefix, files)?;
+        } else {
+            files.push((prefix.clone(), metadata.len()));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// Orders paths in a torrent one component at a time, comparing components as raw bytes. That's
+/// the same on every platform and in every locale: uppercase comes before lowercase, and a
+/// directory's files come right after its name, before any sibling that extends that name (`a/b`
+/// before `a-b`).
+fn compare_paths(a: &[String], b: &[String]) -> Ordering {
+    a.iter()
+        .map(String::as_bytes)
+        .cmp(b.iter().map(String::as_bytes))
+}
+
+/// Whether the entry at `components` in a torrent matches one of the `exclude` patterns, by name
+/// or by path.
+fn is_excluded(exclude: &[Pattern], components: &[String]) -> bool {
+    let name = components.last().map_or("", String::as_str);
+    let path = components.join("/");
+    // so that `*` in a path pattern stays within one directory
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    exclude
+        .iter()
+        .any(|pattern| pattern.matches(name) || pattern.matches_with(&path, options))
+}
+
+/// Where the file at `components` in a torrent is under `root`. Torrents come from anywhere, so
+/// components that would lead outside of `root` aren't allowed.
+fn torrent_path(root: &Path, components: &[String]) -> io::Result<PathBuf> {
+    let mut path = root.to_path_buf();
+    for component in components {
+        if component.is_empty()
+            || component == "."
+            || component == ".."
+            || component.contains(['/', '\\'])
+        {
+            let msg = format!("invalid path component in torrent: {:?}", component);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        path.push(component);
+    }
+    Ok(path)
+}
+
+/// The last component of `path`, which has to be valid utf-8 to go in a torrent.
+fn file_name(path: &Path) -> io::Result<String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| {
+            let msg = format!("{} has no utf-8 file name", path.display());
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        })
+}
+
+fn bytes(string: String) -> Value {
+    Value::Bytes(string.into_bytes())
+}
+
+fn int(n: u64) -> Value {
+    Value::Int(n as i64)
+}
+
+/// Finds the value of the 'info' key in a bencoded top level dictionary.
+fn find_info(bytes: &[u8]) -> Option<&[u8]> {
+    if bytes.first() != Some(&b'd') {
+        return None;
+    }
+
+    let mut pos = 1;
+    while bytes.get(pos) != Some(&b'e') {
+        let key_end = value_end(bytes, pos)?;
+        let key = &bytes[pos..key_end];
+        let end = value_end(bytes, key_end)?;
+        if key == b"4:info" {
+            return Some(&bytes[key_end..end]);
+        }
+        pos = end;
+    }
+    None
+}
+
+/// Returns the offset just past the bencoded value that starts at `pos`. Nested lists and
+/// dictionaries are tracked with a counter rather than recursion, so deeply nested input can't
+/// overflow the stack.
+fn value_end(bytes: &[u8], mut pos: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    loop {
+        match *bytes.get(pos)? {
+            b'i' => {
+                let len = bytes[pos..].iter().position(|&b| b == b'e')?;
+                pos += len + 1;
+            }
+            b'l' | b'd' => {
+                depth += 1;
+                pos += 1;
+                continue;
+            }
+            b'e' => {
+                depth = depth.checked_sub(1)?;
+                pos += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = pos + bytes[pos..].iter().position(|&b| b == b':')?;
+                let len: usize = std::str::from_utf8(&bytes[pos..colon]).ok()?.parse().ok()?;
+                pos = colon.checked_add(1)?.checked_add(len)?;
+                if pos > bytes.len() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(pos);
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_util::assert_sorted_keys;
+    use std::convert::TryInto;
+
+    // The layouts of the single and multiple file info dictionaries described in BEP 3.
+    #[test]
+    fn bep3_info_dicts() {
+        let single = MetaInfo {
+            announce: "http://tracker.example.org:6969/announce",
+            info: InfoInner::SingleFile {
+                name: "a.txt",
+                piece_length: 262144,
+                pieces: "aaaaaaaaaaaaaaaaaaaa",
+                length: 1024,
+                md5sum: Some("0123456789abcdef0123456789abcdef"),
+                source: None,
+            },
+        };
+        assert_eq!(
+            single.bencode().unwrap(),
+            "d8:announce40:http://tracker.example.org:6969/announce4:infod6:lengthi1024e\
+             6:md5sum32:0123456789abcdef0123456789abcdef4:name5:a.txt12:piece lengthi262144e\
+             6:pieces20:aaaaaaaaaaaaaaaaaaaaee"
+        );
+
+        let multi = MetaInfo {
+            announce: "http://tracker.example.org:6969/announce",
+            info: InfoInner::MultipleFile {
+                name: "dir",
+                piece_length: 1 << 32,
+                pieces: "aaaaaaaaaaaaaaaaaaaa",
+                files: vec![
+                    MetaInfoFile {
+                        length: 10,
+                        path: vec!["sub", "b.txt"],
+                        md5sum: None,
+                    },
+                    MetaInfoFile {
+                        length: 20,
+                        path: vec!["c.txt"],
+                        md5sum: None,
+                    },
+                ],
+                source: None,
+            },
+        };
+        assert_eq!(
+            multi.bencode().unwrap(),
+            "d8:announce40:http://tracker.example.org:6969/announce4:infod5:filesl\
+             d6:lengthi10e4:pathl3:sub5:b.txtee\
+             d6:lengthi20e4:pathl5:c.txtee\
+             e4:name3:dir12:piece lengthi4294967296e6:pieces20:aaaaaaaaaaaaaaaaaaaaee"
+        );
+
+        for metainfo in [single, multi] {
+            assert_sorted_keys(metainfo.bencode().unwrap().as_bytes());
+        }
+    }
+
+    const FIXTURES: &[(&str, &[u8])] = &[
+        (
+            "single-file",
+            include_bytes!("../tests/fixtures/single-file.torrent"),
+        ),
+        (
+            "multi-file",
+            include_bytes!("../tests/fixtures/multi-file.torrent"),
+        ),
+    ];
+
+    #[test]
+    fn fixtures_round_trip() {
+        for (name, bytes) in FIXTURES {
+            let metainfo = OwnedMetaInfo::from_bytes(bytes).unwrap();
+            assert_eq!(metainfo.to_bytes().unwrap(), *bytes, "{}", name);
+            assert_sorted_keys(&metainfo.to_bytes().unwrap());
+            assert_eq!(
+                metainfo.announce(),
+                Some("http://tracker.example.org:6969/announce")
+            );
+            assert_eq!(metainfo.info.pieces.len() % 20, 0);
+            // the info dictionary is kept exactly, including keys we don't parse
+            let raw = metainfo.raw_info();
+            assert!(bytes.windows(raw.len()).any(|w| w == raw), "{}", name);
+        }
+    }
+
+    #[test]
+    fn fixture_contents() {
+        let single = OwnedMetaInfo::from_bytes(FIXTURES[0].1).unwrap();
+        assert_eq!(single.info.name, "release-notes.txt");
+        assert_eq!(single.info.length, Some(100000));
+        assert_eq!(single.info.files, None);
+        assert_eq!(single.info.source.as_deref(), Some("EXAMPLE"));
+        assert_eq!(single.info.total_length(), 100000);
+        // announce is also the first URL of the announce-list
+        assert_eq!(
+            single.announce_urls(),
+            [
+                "http://tracker.example.org:6969/announce",
+                "udp://backup.example.net:1337/announce"
+            ]
+        );
+        assert!(single
+            .raw_info()
+            .ends_with(b"7:privatei1e6:source7:EXAMPLEe"));
+
+        let multi = OwnedMetaInfo::from_bytes(FIXTURES[1].1).unwrap();
+        assert_eq!(multi.info.name, "example-dataset");
+        assert_eq!(multi.info.length, None);
+        let files = multi.info.files.as_ref().unwrap();
+        let paths: Vec<_> = files.iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(paths, vec!["docs/manual.pdf", "bin/tool", "README"]);
+        assert_eq!(files.iter().map(|f| f.length).sum::<u64>(), 110006);
+        assert_eq!(multi.info.total_length(), 110006);
+        assert_eq!(
+            multi.announce_urls(),
+            ["http://tracker.example.org:6969/announce"]
+        );
+    }
+
+    #[test]
+    fn not_a_torrent() {
+        assert!(OwnedMetaInfo::from_bytes(b"").is_err());
+        assert!(OwnedMetaInfo::from_bytes(b"li1ee").is_err());
+        assert!(OwnedMetaInfo::from_bytes(b"d8:announce3:urle").is_err());
+        assert!(OwnedMetaInfo::from_bytes(b"d4:infod4:name1:xee").is_err());
+    }
+
+    /// A fresh, empty directory for a test to create torrents from.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("metainfo-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn from_file() {
+        let dir = temp_dir("from-file");
+        let path = dir.join("data.bin");
+        let data = content(100_000);
+        fs::write(&path, &data).unwrap();
+
+        let metainfo = OwnedMetaInfo::from_file(
+            &path,
+            "http://tracker/announce",
+            16384,
+            CreateOptions::default(),
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(metainfo.announce(), Some("http://tracker/announce"));
+        assert_eq!(metainfo.info.name, "data.bin");
+        assert_eq!(metainfo.info.length, Some(100_000));
+        assert_eq!(metainfo.info.piece_length, 16384);
+        // the last piece is a short one
+        assert_eq!(metainfo.info.pieces.len(), 7 * 20);
+        assert_eq!(metainfo.info.pieces[..20], Sha1::digest(&data[..16384])[..]);
+        assert_eq!(
+            metainfo.info.pieces[120..],
+            Sha1::digest(&data[98304..])[..]
+        );
+        assert_sorted_keys(&metainfo.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn from_dir() {
+        let dir = temp_dir("from-dir");
+        let root = dir.join("dataset");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let data = content(50_000);
+        fs::write(root.join("sub/a.bin"), &data[20_000..]).unwrap();
+        fs::write(root.join("b.bin"), &data[..20_000]).unwrap();
+
+        let metainfo = OwnedMetaInfo::from_dir(
+            &root,
+            "http://tracker/announce",
+            16384,
+            CreateOptions::default(),
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(metainfo.info.name, "dataset");
+        assert_eq!(metainfo.info.length, None);
+        let files = metainfo.info.files.as_ref().unwrap();
+        let paths: Vec<_> = files.iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(paths, ["b.bin", "sub/a.bin"]);
+        assert_eq!(files[0].length, 20_000);
+        // the second piece runs from the end of b.bin into sub/a.bin
+        assert_eq!(metainfo.info.pieces.len(), 4 * 20);
+        assert_eq!(
+            metainfo.info.pieces[20..40],
+            Sha1::digest(&data[16384..32768])[..]
+        );
+        assert_sorted_keys(&metainfo.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn streaming_pieces() {
+        /// Hands out its content a few bytes at a time, the way a pipe or socket might.
+        struct Trickle<'a>(&'a [u8]);
+
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = buf.len().min(self.0.len()).min(1000);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        // several buffers' worth, so pieces straddle both reads and buffers
+        let data = content(3 * READ_BUFFER_LEN + 12345);
+        let piece_length = 20_000;
+        let expected: Vec<u8> = data.chunks(piece_length).flat_map(Sha1::digest).collect();
+
+        let mut hasher = PieceHasher::new(
+            piece_length as u64,
+            data.len() as u64,
+            CreateOptions::default(),
+        );
+        assert_eq!(hasher.read(Trickle(&data)).unwrap(), data.len() as u64);
+        assert_eq!(hasher.finish(), expected);
+
+        // the same pieces when the content is split over several readers, like files in a torrent
+        let mut hasher = PieceHasher::new(
+            piece_length as u64,
+            data.len() as u64,
+            CreateOptions::default(),
+        );
+        for part in data.chunks(15_001) {
+            hasher.read(Trickle(part)).unwrap();
+        }
+        assert_eq!(hasher.finish(), expected);
+
+        // and from a file on disk, larger than both a piece and the read buffer
+        let dir = temp_dir("streaming");
+        let path = dir.join("data.bin");
+        fs::write(&path, &data).unwrap();
+        let metainfo = OwnedMetaInfo::from_file(
+            &path,
+            "http://t/",
+            piece_length as u64,
+            CreateOptions::default(),
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(metainfo.info.pieces, expected);
+    }
+
+    #[test]
+    fn progress() {
+        let dir = temp_dir("progress");
+        let root = dir.join("dataset");
+        fs::create_dir_all(&root).unwrap();
+        // several read buffers' worth, over a couple of files
+        let data = content(3 * READ_BUFFER_LEN);
+        fs::write(root.join("a.bin"), &data[..100_000]).unwrap();
+        fs::write(root.join("b.bin"), &data[100_000..]).unwrap();
+        let total = data.len() as u64;
+
+        let mut calls = Vec::new();
+        for parallel in [false, true] {
+            calls.clear();
+            let mut progress = |hashed, total| calls.push((hashed, total));
+            let options = CreateOptions {
+                parallel,
+                progress: Some(&mut progress),
+                ..CreateOptions::default()
+            };
+            OwnedMetaInfo::from_dir(&root, "http://t/", 16384, options).unwrap();
+            assert!(
+                calls.windows(2).all(|pair| pair[0].0 < pair[1].0),
+                "{:?}",
+                calls
+            );
+            assert!(calls.iter().all(|&(_, all)| all == total));
+            assert_eq!(calls.last(), Some(&(total, total)));
+        }
+
+        // in parallel, progress comes a batch at a time
+        let mut calls = Vec::new();
+        let mut progress = |hashed, _| calls.push(hashed);
+        let options = CreateOptions {
+            parallel: true,
+            progress: Some(&mut progress),
+            ..CreateOptions::default()
+        };
+        let mut hasher = PieceHasher::new(16384, total, options);
+        hasher.batch_len = 4 * 16384;
+        hasher.read(&data[..]).unwrap();
+        hasher.finish();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(calls, [65536, 131072, 196608]);
+    }
+
+    /// "Hashes" a piece into its first and last bytes and its length, so tests can see exactly
+    /// where each piece starts and ends.
+    #[derive(Default)]
+    struct Bounds(Vec<u8>);
+
+    impl Hasher for Bounds {
+        fn update(&mut self, data: &[u8]) {
+            self.0.extend_from_slice(data);
+        }
+
+        fn finish(&mut self) -> [u8; 20] {
+            let piece = std::mem::take(&mut self.0);
+            let mut hash = [0; 20];
+            hash[0] = piece[0];
+            hash[1] = piece[piece.len() - 1];
+            hash[2..10].copy_from_slice(&(piece.len() as u64).to_be_bytes());
+            hash
+        }
+    }
+
+    #[test]
+    fn piece_boundaries() {
+        let data: Vec<u8> = (0..=99).collect();
+        for parallel in [false, true] {
+            let options = CreateOptions {
+                parallel,
+                ..CreateOptions::default()
+            };
+            let mut hasher = PieceHasher::<Bounds>::with_hasher(30, 100, options);
+            hasher.batch_len = 60;
+            // reads that don't line up with pieces
+            for part in data.chunks(17) {
+                hasher.update(part);
+            }
+            let pieces: Vec<_> = hasher
+                .finish()
+                .chunks(20)
+                .map(|hash| {
+                    let len = u64::from_be_bytes(hash[2..10].try_into().unwrap());
+                    (hash[0], hash[1], len)
+                })
+                .collect();
+            assert_eq!(
+                pieces,
+                [(0, 29, 30), (30, 59, 30), (60, 89, 30), (90, 99, 10)],
+                "parallel: {}",
+                parallel
+            );
+        }
+    }
+
+    #[test]
+    fn parallel_pieces() {
+        let data = content(100_000);
+        for piece_length in [16384, 20_000, 100_000] {
+            let hash = |parallel| {
+                let options = CreateOptions {
+                    parallel,
+                    ..CreateOptions::default()
+                };
+                let mut hasher = PieceHasher::new(piece_length, data.len() as u64, options);
+                // several batches, rather than all of it in the last one
+                hasher.batch_len = 2 * piece_length;
+                // split unevenly, like files in a torrent
+                for part in data.chunks(7_001) {
+                    hasher.update(part);
+                }
+                hasher.finish()
+            };
+            assert_eq!(hash(true), hash(false), "piece length {}", piece_length);
+        }
+
+        // pieces that run from one file into the next come out the same either way
+        let dir = temp_dir("parallel");
+        let root = dir.join("dataset");
+        fs::create_dir_all(&root).unwrap();
+        for (i, part) in data.chunks(30_000).enumerate() {
+            fs::write(root.join(format!("{}.bin", i)), part).unwrap();
+        }
+        let create = |parallel| {
+            let options = CreateOptions {
+                parallel,
+                ..CreateOptions::default()
+            };
+            OwnedMetaInfo::from_dir(&root, "http://t/", 16384, options).unwrap()
+        };
+        let (serial, parallel) = (create(false), create(true));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(parallel.info.pieces, serial.info.pieces);
+        assert_eq!(parallel.info_hash(), serial.info_hash());
+    }
+
+    #[test]
+    fn file_order() {
+        let dir = temp_dir("file-order");
+        let paths = ["b", "a-b", "a/z", "a/Z", "B", "\u{e9}", "z", "a/y/x", "ab"];
+        let data = content(paths.len() * 1000);
+        // two copies of the same content, with their files created in opposite orders
+        let create = |copy: &str, order: &mut dyn Iterator<Item = (usize, &&str)>| {
+            // both named the same, since the name is part of the info dictionary
+            let root = dir.join(copy).join("data");
+            for (i, path) in order {
+                let file = root.join(path);
+                fs::create_dir_all(file.parent().unwrap()).unwrap();
+                fs::write(file, &data[i * 1000..(i + 1) * 1000]).unwrap();
+            }
+            OwnedMetaInfo::from_dir(&root, "http://t/", 16384, CreateOptions::default()).unwrap()
+        };
+        let forwards = create("forwards", &mut paths.iter().enumerate());
+        let backwards = create("backwards", &mut paths.iter().enumerate().rev());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let files = forwards.info.files.as_ref().unwrap();
+        let sorted: Vec<_> = files.iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(
+            sorted,
+            ["B", "a/Z", "a/y/x", "a/z", "a-b", "ab", "b", "z", "\u{e9}"]
+        );
+        assert_eq!(backwards.info.files, forwards.info.files);
+        assert_eq!(backwards.info_hash(), forwards.info_hash());
+    }
+
+    #[test]
+    fn exclude() {
+        let dir = temp_dir("exclude");
+        let root = dir.join("dataset");
+        fs::create_dir_all(root.join("sub/.git")).unwrap();
+        fs::create_dir_all(root.join("tmp")).unwrap();
+        let data = content(50_000);
+        fs::write(root.join("a.bin"), &data[..30_000]).unwrap();
+        fs::write(root.join("sub/b.bin"), &data[30_000..]).unwrap();
+        for junk in [
+            ".DS_Store",
+            "sub/.DS_Store",
+            "sub/.git/HEAD",
+            "tmp/b.bin",
+            "sub/c.tmp",
+        ] {
+            fs::write(root.join(junk), b"junk").unwrap();
+        }
+
+        let exclude = [".DS_Store", ".git", "tmp", "sub/*.tmp"]
+            .iter()
+            .map(|pattern| Pattern::new(pattern).unwrap())
+            .collect();
+        let options = CreateOptions {
+            exclude,
+            ..CreateOptions::default()
+        };
+        let metainfo = OwnedMetaInfo::from_dir(&root, "http://t/", 16384, options).unwrap();
+        let plain =
+            OwnedMetaInfo::from_dir(&root, "http://t/", 16384, CreateOptions::default()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let files = metainfo.info.files.as_ref().unwrap();
+        let paths: Vec<_> = files.iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(paths, ["a.bin", "sub/b.bin"]);
+        // and the excluded files aren't hashed either
+        let expected: Vec<u8> = data.chunks(16384).flat_map(Sha1::digest).collect();
+        assert_eq!(metainfo.info.pieces, expected);
+        assert_eq!(plain.info.files.unwrap().len(), 7);
+
+        // a path pattern's `*` doesn't reach into subdirectories
+        let components = |path: &str| path.split('/').map(str::to_string).collect::<Vec<_>>();
+        let pattern = [Pattern::new("sub/*.tmp").unwrap()];
+        assert!(is_excluded(&pattern, &components("sub/c.tmp")));
+        assert!(!is_excluded(&pattern, &components("sub/deeper/c.tmp")));
+    }
+
+    #[test]
+    fn verify_data() {
+        let dir = temp_dir("verify");
+        let path = dir.join("data.bin");
+        let mut data = content(200_000);
+        fs::write(&path, &data).unwrap();
+        let single =
+            OwnedMetaInfo::from_file(&path, "http://t/", 16384, CreateOptions::default()).unwrap();
+
+        let report = single.verify_data(&dir).unwrap();
+        assert_eq!(report.pieces, 13);
+        assert!(report.is_complete(), "{:?}", report);
+
+        data[3 * 16384 + 5] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        assert_eq!(single.verify_data(&dir).unwrap().bad_pieces, [3]);
+        // a short file spoils everything after where it ends
+        fs::write(&path, &data[..11 * 16384 + 1]).unwrap();
+        assert_eq!(single.verify_data(&dir).unwrap().bad_pieces, [3, 11, 12]);
+
+        // a missing file only spoils the pieces it's in, including the ones shared with the
+        // files around it
+        let root = dir.join("dataset");
+        fs::create_dir_all(&root).unwrap();
+        for (i, part) in data.chunks(50_000).enumerate() {
+            fs::write(root.join(format!("{}.bin", i)), part).unwrap();
+        }
+        let multi =
+            OwnedMetaInfo::from_dir(&root, "http://t/", 16384, CreateOptions::default()).unwrap();
+        assert!(multi.verify_data(&dir).unwrap().is_complete());
+        fs::remove_file(root.join("1.bin")).unwrap();
+        // 1.bin is bytes 50000 to 100000, pieces 3 to 6
+        assert_eq!(multi.verify_data(&dir).unwrap().bad_pieces, [3, 4, 5, 6]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut escape = multi.clone();
+        escape.info.files.as_mut().unwrap()[0].path = vec!["..".to_string(), "x".to_string()];
+        assert!(matches!(
+            escape.verify_data(&dir),
+            Err(MetaInfoError::Io(err)) if err.kind() == io::ErrorKind::InvalidData
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        let dir = temp_dir("errors");
+        let create_dir = |path: &Path| {
+            OwnedMetaInfo::from_dir(path, "http://t/", 16384, CreateOptions::default())
+        };
+
+        let err = create_dir(&dir.join("missing")).unwrap_err();
+        match &err {
+            MetaInfoError::Io(err) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            err => panic!("expected an io error, got {:?}", err),
+        }
+        assert!(err.source().is_some());
+
+        // a directory of nothing but empty files and directories is just as empty as no files
+        fs::create_dir_all(dir.join("empty/sub")).unwrap();
+        fs::write(dir.join("empty/sub/zero.bin"), b"").unwrap();
+        assert!(matches!(
+            create_dir(&dir.join("empty")),
+            Err(MetaInfoError::Empty)
+        ));
+        let zero = dir.join("empty/sub/zero.bin");
+        assert!(matches!(
+            OwnedMetaInfo::from_file(&zero, "http://t/", 16384, CreateOptions::default()),
+            Err(MetaInfoError::Empty)
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+
+        // the builders decode the torrents they encode, which would fail like this if they ever
+        // wrote out a broken one
+        let err = OwnedMetaInfo::from_bytes(b"d8:announce").unwrap_err();
+        let err = MetaInfoError::from(err);
+        assert!(matches!(err, MetaInfoError::Bencode(_)), "{:?}", err);
+        assert!(
+            err.to_string().starts_with("torrent isn't valid"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn piece_count_guard() {
+        let dir = temp_dir("piece-count");
+        let path = dir.join("data.bin");
+        fs::write(&path, content(1 << 20)).unwrap();
+        let create = |piece_length| {
+            OwnedMetaInfo::from_file(&path, "http://t/", piece_length, CreateOptions::default())
+        };
+
+        // a million pieces
+        let err = create(1).unwrap_err();
+        assert!(
+            matches!(err, MetaInfoError::InvalidPieceLength(_)),
+            "{:?}",
+            err
+        );
+        assert!(err.to_string().contains("1048576 pieces"), "{}", err);
+        // a single piece
+        let err = create(1 << 20).unwrap_err();
+        assert!(
+            matches!(err, MetaInfoError::InvalidPieceLength(_)),
+            "{:?}",
+            err
+        );
+        assert!(err.to_string().contains("only 1 pieces"), "{}", err);
+        assert!(matches!(
+            create(0),
+            Err(MetaInfoError::InvalidPieceLength(_))
+        ));
+        assert_eq!(create(1 << 16).unwrap().info.pieces.len(), 16 * 20);
+
+        // content too small for MIN_PIECES can still be one piece
+        fs::write(&path, content(1000)).unwrap();
+        assert_eq!(create(1 << 20).unwrap().info.pieces.len(), 20);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn source() {
+        let metainfo = || MetaInfo {
+            announce: "http://tracker.example.org:6969/announce",
+            info: InfoInner::SingleFile {
+                name: "a.txt",
+                piece_length: 262144,
+                pieces: "aaaaaaaaaaaaaaaaaaaa",
+                length: 1024,
+                md5sum: None,
+                source: None,
+            },
+        };
+        let plain = metainfo();
+        let sourced = metainfo().with_source("TRACKERNAME");
+
+        let bencoded = sourced.bencode().unwrap();
+        assert!(bencoded.contains("6:source11:TRACKERNAME"), "{}", bencoded);
+        assert_sorted_keys(bencoded.as_bytes());
+        assert!(!plain.bencode().unwrap().contains("6:source"));
+        assert_ne!(plain.info_hash().unwrap(), sourced.info_hash().unwrap());
+
+        // the hash covers the info dictionary exactly as it's written out, source included
+        let parsed = OwnedMetaInfo::from_bytes(bencoded.as_bytes()).unwrap();
+        assert_eq!(parsed.info.source.as_deref(), Some("TRACKERNAME"));
+        assert_eq!(parsed.info_hash(), sourced.info_hash().unwrap());
+
+        let multi = MetaInfo {
+            announce: "http://tracker.example.org:6969/announce",
+            info: InfoInner::MultipleFile {
+                name: "dir",
+                piece_length: 262144,
+                pieces: "aaaaaaaaaaaaaaaaaaaa",
+                files: vec![],
+                source: None,
+            },
+        };
+        assert!(multi
+            .with_source("TRACKERNAME")
+            .bencode()
+            .unwrap()
+            .contains("6:source11:TRACKERNAME"));
+    }
 
     #[test]
     fn basic_test() {
@@ -75,6 +1421,7 @@ mod test {
                 pieces: "abc",
                 length: 100,
                 md5sum: None,
+                source: None,
             },
         };
         // get this error when try to compare without unwrap():
@@ -0,0 +1,142 @@
+//! On-disk snapshots of tracker state, so that swarms survive a restart.
+//!
+//! A snapshot is a small binary header followed by the bencoded state:
+//!
+//! ```text
+//! b"BTSS" | format version (u32, big endian) | bencoded body
+//! ```
+//!
+//! The version has to be bumped whenever the layout of the body changes, so that loading a
+//! snapshot written by a different version of the tracker fails with a clear error instead of
+//! deserializing into the wrong shape.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"BTSS";
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+const HEADER_LEN: usize = 8;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    // The file doesn't start with our magic bytes, so it probably isn't a snapshot at all.
+    BadMagic,
+    // The snapshot was written in a format version we can't read.
+    UnsupportedVersion(u32),
+    // The header was fine but the body couldn't be decoded.
+    Corrupt(serde_bencode::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "couldn't access snapshot: {}", err),
+            Self::BadMagic => write!(f, "not a tracker snapshot"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "snapshot format version {} is not supported (expected {})",
+                version, SNAPSHOT_VERSION
+            ),
+            Self::Corrupt(err) => write!(f, "snapshot is corrupt: {}", err),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+pub fn encode<T: Serialize>(state: &T) -> Result<Vec<u8>, SnapshotError> {
+    let body = serde_bencode::to_bytes(state).map_err(SnapshotError::Corrupt)?;
+    let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+    bytes.extend_from_slice(SNAPSHOT_MAGIC);
+    bytes.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, SnapshotError> {
+    if bytes.len() < HEADER_LEN || &bytes[..4] != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+
+    let version = u32::from_be_bytes(bytes[4..HEADER_LEN].try_into().unwrap());
+    if version != SNAPSHOT_VERSION {
+        // there's only been one version so far, so there's nothing to migrate from
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    serde_bencode::from_bytes(&bytes[HEADER_LEN..]).map_err(SnapshotError::Corrupt)
+}
+
+/// Writes a snapshot next to `path` and then moves it into place, so a crash halfway through
+/// never leaves a truncated snapshot behind.
+pub fn write<T: Serialize>(path: &Path, state: &T) -> Result<(), SnapshotError> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, encode(state)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub fn read<T: DeserializeOwned>(path: &Path) -> Result<T, SnapshotError> {
+    decode(&fs::read(path)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct State {
+        counter: u32,
+        names: Vec<String>,
+    }
+
+    fn state() -> State {
+        State {
+            counter: 3,
+            names: vec!["a".to_string(), "b".to_string()],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = encode(&state()).unwrap();
+        assert_eq!(&bytes[..4], SNAPSHOT_MAGIC);
+        assert_eq!(decode::<State>(&bytes).unwrap(), state());
+    }
+
+    #[test]
+    fn unknown_version() {
+        let mut bytes = encode(&state()).unwrap();
+        bytes[4..8].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_be_bytes());
+
+        match decode::<State>(&bytes) {
+            Err(SnapshotError::UnsupportedVersion(version)) => {
+                assert_eq!(version, SNAPSHOT_VERSION + 1)
+            }
+            other => panic!("expected a version error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn not_a_snapshot() {
+        assert!(matches!(
+            decode::<State>(b"d7:counteri3ee"),
+            Err(SnapshotError::BadMagic)
+        ));
+    }
+}
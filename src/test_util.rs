@@ -0,0 +1,91 @@
+//! Helpers shared by the tests of several modules.
+
+/// Checks that every dictionary in the bencoded `bytes`, however deeply nested, has its keys in
+/// strictly increasing byte order, as BEP 3 requires. Clients are free to reject dictionaries that
+/// aren't, and some do.
+pub fn assert_sorted_keys(bytes: &[u8]) {
+    let end = check_value(bytes, 0, "");
+    assert_eq!(end, bytes.len(), "trailing data after the bencoded value");
+}
+
+/// Checks the value starting at `pos`, found at `path`, and returns where it ends.
+fn check_value(bytes: &[u8], pos: usize, path: &str) -> usize {
+    match bytes.get(pos) {
+        Some(b'i') => pos + find(bytes, pos, b'e') + 1,
+        Some(b'l') => {
+            let mut pos = pos + 1;
+            let mut i = 0;
+            while bytes.get(pos) != Some(&b'e') {
+                pos = check_value(bytes, pos, &format!("{}[{}]", path, i));
+                i += 1;
+            }
+            pos + 1
+        }
+        Some(b'd') => {
+            let mut pos = pos + 1;
+            let mut previous: Option<&[u8]> = None;
+            while bytes.get(pos) != Some(&b'e') {
+                let (key, value) = read_string(bytes, pos);
+                let key_path = format!("{}/{}", path, String::from_utf8_lossy(key));
+                if let Some(previous) = previous {
+                    assert!(
+                        previous < key,
+                        "key {} comes after {:?}",
+                        key_path,
+                        String::from_utf8_lossy(previous)
+                    );
+                }
+                previous = Some(key);
+                pos = check_value(bytes, value, &key_path);
+            }
+            pos + 1
+        }
+        Some(b'0'..=b'9') => {
+            let (string, _) = read_string(bytes, pos);
+            pos + find(bytes, pos, b':') + 1 + string.len()
+        }
+        other => panic!("unexpected {:?} at byte {} ({})", other, pos, path),
+    }
+}
+
+/// Reads the byte string starting at `pos`, returning it and where the value after it starts.
+fn read_string(bytes: &[u8], pos: usize) -> (&[u8], usize) {
+    let colon = pos + find(bytes, pos, b':');
+    let len: usize = std::str::from_utf8(&bytes[pos..colon])
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .unwrap_or_else(|| panic!("invalid string length at byte {}", pos));
+    let start = colon + 1;
+    (&bytes[start..start + len], start + len)
+}
+
+/// Returns how far past `pos` the next `byte` is.
+fn find(bytes: &[u8], pos: usize, byte: u8) -> usize {
+    bytes[pos..]
+        .iter()
+        .position(|&b| b == byte)
+        .unwrap_or_else(|| panic!("unterminated value at byte {}", pos))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sorted_keys() {
+        assert_sorted_keys(b"d1:ai1e1:bl1:xd1:c0:1:d0:ee1:ci-3ee");
+        assert_sorted_keys(b"d2:ab0:2:b\x001:xe");
+    }
+
+    #[test]
+    #[should_panic(expected = "key /b/a comes after \"b\"")]
+    fn unsorted_nested_keys() {
+        assert_sorted_keys(b"d1:ad1:bi1ee1:bd1:bi1e1:ai2eee");
+    }
+
+    #[test]
+    #[should_panic(expected = "key /a comes after \"a\"")]
+    fn duplicate_keys() {
+        assert_sorted_keys(b"d1:ai1e1:ai2ee");
+    }
+}
@@ -1,18 +1,40 @@
+mod client_id;
+mod format;
+pub mod store;
+
+use crate::auth::{self, AUTH_HEADER};
+use crate::connectivity::{ConnectCache, Connector, TcpConnector};
+use crate::geo::{GeoLookup, NoLookup, Region};
+use crate::metainfo::OwnedMetaInfo;
+use crate::snapshot::{self, SnapshotError};
 use crate::Opt;
+use format::Format;
+pub use format::HexBinary;
+use sha1::{Digest, Sha1};
+use store::{InMemoryStore, PeerStore, Selection, SwarmInfo};
 
-use hyper::{Body, Method, Request};
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, CONTENT_LENGTH, CONTENT_TYPE,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use rand::seq::SliceRandom;
 use serde::{de, ser, Deserialize, Serialize};
-use rand::seq::IteratorRandom;
-use serde_urlencoded;
+use tokio::runtime::Handle;
 
-use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet};
-use std::convert::{TryFrom, TryInto};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::fmt;
-use std::net::IpAddr;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
 use std::str;
-use std::sync::Mutex;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub type TrackerResult = Result<TrackerResponse, TrackerError>;
 
@@ -21,24 +43,386 @@ pub struct TrackerResponse {
     // Interval in seconds that the client should wait between sending regular requests to the
     // tracker.
     interval: u32,
-    peers: Vec<Peer>,
+    // Clients must not re-announce more often than this many seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "min interval")]
+    min_interval: Option<u32>,
+    // Number of peers with the entire file (seeders).
+    complete: usize,
+    // Number of peers still downloading (leechers).
+    incomplete: usize,
+    // Total number of times the tracker has registered a completion for this torrent. Left out
+    // for torrents the tracker doesn't know about.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    downloaded: Option<u32>,
+    // BEP 24: the address the announce came from, as 4 or 16 bytes. Only sent with
+    // `external_ip`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "external ip")]
+    external_ip: Option<ExternalIp>,
+    peers: PeerList,
+    // IPv6 peers, only sent to clients that asked for a compact response (BEP 7). In the
+    // dictionary model IPv6 peers are part of `peers`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peers6: Option<CompactPeers>,
+    // A message the client should show its user, even though the announce went through.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum PeerList {
+    // The original model: a list of dictionaries with 'peer id', 'ip' and 'port' keys.
+    Dict(Vec<Peer>),
+    // BEP 23: the IPv4 peers packed into a single byte string.
+    Compact(CompactPeers),
+    // The dictionary model with a non-standard 'client' key added, see `debug_peer_clients`.
+    Labeled(Vec<LabeledPeer>),
+}
+
+impl PeerList {
+    /// Packs peers into compact IPv4 and IPv6 lists.
+    fn compact(peers: &[Peer]) -> (Self, CompactPeers) {
+        let mut peers4 = CompactPeers::default();
+        let mut peers6 = CompactPeers::default();
+        for peer in peers {
+            match peer.ip {
+                IpAddr::V4(_) => peers4.push(peer),
+                IpAddr::V6(_) => peers6.push(peer),
+            }
+        }
+        (PeerList::Compact(peers4), peers6)
+    }
+}
+
+/// A dictionary model peer along with the client software its peer_id says it's running.
+#[derive(Debug, Serialize)]
+pub struct LabeledPeer {
+    #[serde(rename = "peer id")]
+    peer_id: PeerId,
+    ip: IpAddr,
+    port: u16,
+    // Left out when we don't recognize the peer_id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<String>,
+}
+
+impl From<&Peer> for LabeledPeer {
+    fn from(peer: &Peer) -> Self {
+        Self {
+            peer_id: peer.peer_id,
+            ip: peer.ip,
+            port: peer.port,
+            client: client_id::identify(&peer.peer_id).map(|info| info.to_string()),
+        }
+    }
+}
+
+/// Peers packed back to back as their address followed by their port, both in network byte order.
+/// That's 6 bytes per IPv4 peer, and 18 per IPv6 peer.
+#[derive(Debug, Default, PartialEq)]
+pub struct CompactPeers(Vec<u8>);
+
+impl CompactPeers {
+    fn push(&mut self, peer: &Peer) {
+        match peer.ip {
+            IpAddr::V4(ip) => self.0.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => self.0.extend_from_slice(&ip.octets()),
+        }
+        self.0.extend_from_slice(&peer.port.to_be_bytes());
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Serialize for CompactPeers {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+/// A client's address as 4 bytes for IPv4 or 16 bytes for IPv6, without a port.
+#[derive(Debug, PartialEq)]
+pub struct ExternalIp(IpAddr);
+
+impl Serialize for ExternalIp {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            IpAddr::V4(ip) => serializer.serialize_bytes(&ip.octets()),
+            IpAddr::V6(ip) => serializer.serialize_bytes(&ip.octets()),
+        }
+    }
+}
+
+/// The length of a compact peer list wasn't a multiple of the size of one peer.
+#[derive(Debug, PartialEq)]
+pub struct CompactLengthError {
+    len: usize,
+    peer_len: usize,
+}
+
+impl fmt::Display for CompactLengthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "compact peer list of {} bytes isn't made of {} byte peers",
+            self.len, self.peer_len
+        )
+    }
+}
+
+impl std::error::Error for CompactLengthError {}
+
+/// Decodes the compact `peers` string of an announce response into IPv4 addresses and ports. The
+/// tracker itself never needs this, it's the other half of `CompactPeers` for clients.
+pub fn decode_compact_peers(bytes: &[u8]) -> Result<Vec<(Ipv4Addr, u16)>, CompactLengthError> {
+    decode_compact(bytes, 4, |ip| Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+}
+
+/// Decodes the compact `peers6` string of an announce response (BEP 7) into IPv6 addresses and
+/// ports.
+pub fn decode_compact_peers6(bytes: &[u8]) -> Result<Vec<(Ipv6Addr, u16)>, CompactLengthError> {
+    decode_compact(bytes, 16, |ip| {
+        Ipv6Addr::from(<[u8; 16]>::try_from(ip).expect("split off exactly 16 bytes"))
+    })
+}
+
+fn decode_compact<Ip>(
+    bytes: &[u8],
+    ip_len: usize,
+    to_ip: impl Fn(&[u8]) -> Ip,
+) -> Result<Vec<(Ip, u16)>, CompactLengthError> {
+    let peer_len = ip_len + 2;
+    if !bytes.len().is_multiple_of(peer_len) {
+        return Err(CompactLengthError {
+            len: bytes.len(),
+            peer_len,
+        });
+    }
+
+    let peers = bytes
+        .chunks(peer_len)
+        .map(|peer| {
+            let (ip, port) = peer.split_at(ip_len);
+            (to_ip(ip), u16::from_be_bytes([port[0], port[1]]))
+        })
+        .collect();
+    Ok(peers)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ScrapeResponse {
+    // Maps each requested info_hash to the state of its swarm.
+    files: HashMap<InfoHash, ScrapeFile>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ScrapeFile {
+    // Number of peers with the entire file (seeders).
+    complete: usize,
+    // Total number of times the tracker has registered a completion for this torrent.
+    downloaded: u32,
+    // Number of peers still downloading (leechers).
+    incomplete: usize,
+    // Non-standard: the torrent's name, for extended scrapes of torrents we have the metainfo of.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    // Non-standard: unix time of the last completion, for extended scrapes of torrents that had
+    // one since the tracker started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "last completed")]
+    last_completed: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct TrackerError {
-    failure: String,
+    // Human readable reason the request failed, as sent to the client.
+    #[serde(rename = "failure reason", serialize_with = "serialize_display")]
+    kind: TrackerErrorKind,
+}
+
+impl TrackerError {
+    /// The HTTP status code to respond with for this error.
+    pub fn status(&self) -> StatusCode {
+        match self.kind {
+            TrackerErrorKind::ParseError(_) | TrackerErrorKind::ValidationError(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            TrackerErrorKind::NotFound => StatusCode::NOT_FOUND,
+            TrackerErrorKind::UriTooLong => StatusCode::URI_TOO_LONG,
+            TrackerErrorKind::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            TrackerErrorKind::NotAllowed(_) => StatusCode::FORBIDDEN,
+            TrackerErrorKind::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            TrackerErrorKind::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            TrackerErrorKind::Capacity => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
 }
 
 impl TrackerError {
-    fn new(msg: String) -> Self {
-        Self { failure: msg }
+    /// Whether the request could succeed if the client tried again later.
+    fn is_transient(&self) -> bool {
+        matches!(
+            self.kind,
+            TrackerErrorKind::RateLimited | TrackerErrorKind::Capacity
+        )
+    }
+}
+
+impl From<TrackerErrorKind> for TrackerError {
+    fn from(kind: TrackerErrorKind) -> Self {
+        Self { kind }
     }
 }
 
+/// Everything that can go wrong while serving a request. The `Display` impl is the `failure`
+/// string we send back to the client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackerErrorKind {
+    // The query string couldn't be parsed into a request.
+    ParseError(String),
+    // The request parsed, but its contents don't make sense.
+    ValidationError(String),
+    // The request was for a path that we don't serve.
+    NotFound,
+    // The request URI is longer than we're willing to parse.
+    UriTooLong,
+    // The request used an HTTP method other than GET.
+    MethodNotAllowed,
+    // The client isn't allowed to do what it asked for.
+    NotAllowed(String),
+    // An admin request didn't carry a valid signature.
+    Unauthorized(String),
+    // The client is sending requests too quickly, see `max_ip_announce_rate`.
+    RateLimited,
+    // The tracker can't take on any more work right now, see `max_peers`.
+    Capacity,
+}
+
+impl fmt::Display for TrackerErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ParseError(msg) => write!(f, "Invalid request: {}", msg),
+            Self::ValidationError(msg) => write!(f, "{}", msg),
+            // no hint at the announce path, which may be a secret (see `announce_path`)
+            Self::NotFound => write!(f, "Unrecognized path."),
+            Self::UriTooLong => write!(f, "Invalid request: request URI is too long."),
+            Self::MethodNotAllowed => {
+                write!(
+                    f,
+                    "Invalid request type: client request was not an HTTP GET."
+                )
+            }
+            Self::NotAllowed(msg) => write!(f, "Not allowed: {}", msg),
+            Self::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            Self::RateLimited => write!(f, "Rate limited: slow down and try again later."),
+            Self::Capacity => write!(f, "Tracker is at capacity, try again later."),
+        }
+    }
+}
+
+fn serialize_display<T: fmt::Display, S: ser::Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrackerStats {
+    // Name of the tracker node that served this response, if one was configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "tracker name")]
+    tracker_name: Option<String>,
+    // Number of torrents that have at least one registered peer.
+    torrents: usize,
+    // Number of peers registered across all torrents.
+    peers: usize,
+    // Number of 'completed' events received across all torrents.
+    completed: u32,
+    // Number of torrents by swarm size, see SWARM_SIZE_BUCKETS.
+    histogram: Vec<SwarmSizeBucket>,
+    swarms: Vec<SwarmStats>,
+}
+
+// Smallest swarm size counted by each histogram bucket; every bucket runs up to the start of the
+// next one, and the last bucket has no upper bound.
+const SWARM_SIZE_BUCKETS: &[usize] = &[1, 2, 11, 101, 1001];
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SwarmSizeBucket {
+    min: usize,
+    // Largest swarm size counted by this bucket, omitted for the last bucket.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<usize>,
+    torrents: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SwarmStats {
+    #[serde(rename = "info hash")]
+    info_hash: InfoHash,
+    peers: usize,
+    // Seconds since the first peer registered for this torrent.
+    created: u64,
+    // Seconds since the last announce for this torrent.
+    #[serde(rename = "last activity")]
+    last_activity: u64,
+    // Number of peers that joined or left this torrent within the last CHURN_WINDOW. A high churn
+    // compared to the number of peers means the swarm is unstable.
+    churn: usize,
+    // Unix time of the last 'completed' event for this torrent, if there was one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "last completed")]
+    last_completed: Option<u64>,
+}
+
+// Most scrape responses kept in the scrape cache at once, however many different sets of
+// info_hashes are asked for within `scrape_cache_ttl`.
+const MAX_SCRAPE_CACHE: usize = 1024;
+
+// Most peers listed on one page of `GET /admin/torrents/<hex info_hash>`, which is also how many
+// are listed when the request doesn't give a limit.
+const MAX_PEER_PAGE: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+struct PageRequest {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// One page of a torrent's peers, for the admin endpoints.
+#[derive(Debug, Serialize)]
+pub struct PeerPage {
+    // Number of peers in the swarm, whichever page this is.
+    total: usize,
+    offset: usize,
+    peers: Vec<AdminPeer>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminPeer {
+    #[serde(rename = "peer id")]
+    peer_id: PeerId,
+    ip: IpAddr,
+    port: u16,
+    seeder: bool,
+    // Bytes the peer had left to download when it last announced, if it said.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    left: Option<u64>,
+    // Seconds since the peer last announced.
+    #[serde(rename = "last seen")]
+    last_seen: u64,
+}
+
 // Hash is used to avoid duplicates
 // Consider ignoring peer_id so that changing peer_id doesn't cause us to store duplicate ip/port
 // combinations in the hashset of a torrent.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Peer {
     // peer's self selected ID
     #[serde(rename = "peer id")]
@@ -48,12 +432,26 @@ pub struct Peer {
     port: u16,
 }
 
+impl Peer {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+}
+
 // TODO: newtype can borrow from the deserializer as long as the deserializer is alive
 // TODO: consider serde_bytes?
 macro_rules! newtype_bytearray {
     ($newtype:ident, $len:expr) => {
-        #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
-        struct $newtype([u8; $len]);
+        #[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+        pub struct $newtype(pub [u8; $len]);
+
+        impl TryFrom<&[u8]> for $newtype {
+            type Error = std::array::TryFromSliceError;
+
+            fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+                <[u8; $len]>::try_from(bytes).map($newtype)
+            }
+        }
 
         // by default serde_bencode will serialize/deserialize byte arrays as bencoded lists of
         // integers instead of bencoded byte arrays, so we need to implement these traits ourselves
@@ -107,44 +505,316 @@ newtype_bytearray!(PeerId, 20);
 #[derive(Debug, Deserialize)]
 struct TrackerRequest {
     // 20-byte SHA1 hash of the value of the info key from the Metainfo file. Note that th value
-    // will be a bencoded dictionary.
+    // will be a bencoded dictionary. Binary, so it's decoded by `from_query_string` rather than
+    // serde_urlencoded.
+    #[serde(skip_deserializing)]
     info_hash: InfoHash,
     // 20-byte string used as a unique ID for the client, generated by the client at startup. This
     // is allowed to be any value, and may be binary data.
+    #[serde(skip_deserializing)]
     peer_id: PeerId,
+    // Set when the client left out its peer_id and `anonymous_announces` let it, until one is
+    // made up for it.
+    #[serde(skip_deserializing)]
+    anonymous: bool,
+    // Optional: a value the client picked to be told apart by, whatever its address.
+    key: Option<String>,
     // The true address where the client is listening; if missing infer the ip address from the
     // address where the http request came from.
     ip: Option<IpAddr>,
+    // BEP 7: extra addresses a dual-stack client can also be reached at.
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
     // Port number where the client is listening.
     port: u16,
     // Total number of bytes uploaded since the client sent the 'started' event to the tracker.
-    uploaded: u32,
+    // Some minimal clients (and stopped events) leave out the transfer stats, so these three are
+    // optional.
+    uploaded: Option<u64>,
     // Total number of bytes downloaded since the client sent the 'started' event to the tracker.
-    downloaded: u32,
+    downloaded: Option<u64>,
     // The number of bytes the client still has left to download to get all included files.
-    left: u32,
+    left: Option<u64>,
     event: Option<ClientEvent>,
     // The number of peers that the client would like to receive from the tracker.
     numwant: Option<u32>,
+    // Non-standard: when non-zero, the client only wants to see what we would respond with, and
+    // the announce must not change any of the tracker's state.
+    dryrun: Option<u8>,
+    // BEP 23: whether the client would like the peer list in the compact format. Clients send
+    // this as 1/0, but some send true/false instead. Leaving it out means the dictionary model.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    compact: bool,
+    // Non-standard: only hand out peers in this address family, for clients that can't reach the
+    // other one.
+    family: Option<AddressFamily>,
 }
 
 impl TrackerRequest {
+    /// Parses an announce, which may leave out its peer_id if `anonymous`.
+    fn from_query_string(qs: &str, anonymous: bool) -> Result<Self, TrackerError> {
+        // serde_urlencoded only hands out utf-8 strings, which would mangle binary info_hashes and
+        // peer_ids, so those two are picked out and decoded into raw bytes here, and serde only
+        // gets the rest
+        let mut info_hashes = Vec::new();
+        let mut peer_ids = Vec::new();
+        let mut rest = Vec::new();
+        let mut keys = Vec::new();
+        for (pair, key, value) in raw_query_pairs(qs) {
+            match key.as_str() {
+                "info_hash" => info_hashes.push(value),
+                "peer_id" => peer_ids.push(value),
+                _ => rest.push(pair),
+            }
+            keys.push(key);
+        }
+        // tell clients plainly which parameter they left out, rather than with serde's wording
+        if let Some(name) = REQUIRED_ANNOUNCE_PARAMS
+            .iter()
+            .filter(|name| !(anonymous && **name == "peer_id"))
+            .find(|name| !keys.iter().any(|key| key == *name))
+        {
+            return Err(missing_param(name));
+        }
+        // unlike scrape, an announce is about exactly one torrent. Catch repeated info_hashes here
+        // so the client gets a clearer message than a generic duplicate field error.
+        if info_hashes.len() > 1 {
+            let msg = "multiple info_hash not allowed on announce".to_string();
+            return Err(TrackerErrorKind::ValidationError(msg).into());
+        }
+
+        let mut req: Self = serde_urlencoded::from_str(&rest.join("&"))
+            .map_err(|err| TrackerErrorKind::ParseError(err.to_string()))?;
+        req.info_hash = binary_param("info_hash", info_hashes)?;
+        if anonymous && peer_ids.is_empty() {
+            req.anonymous = true;
+        } else {
+            req.peer_id = binary_param("peer_id", peer_ids)?;
+        }
+        Ok(req)
+    }
+
+    /// Checks that the parameters make sense together; each one on its own was already checked
+    /// while parsing, including the info_hash every event (`stopped` too) has to carry. Requests
+    /// we can't act on are rejected, and contradictory ones we can still make sense of are
+    /// returned as a warning for the client, or rejected when `strict`.
+    fn validate_request(&self, strict: bool) -> Result<Option<String>, TrackerError> {
+        let warning = match (&self.event, self.left) {
+            (Some(ClientEvent::Completed), Some(left)) if left > 0 => Some(format!(
+                "contradictory announce: 'completed' event with {} bytes left",
+                left
+            )),
+            _ => None,
+        };
+        match warning {
+            Some(msg) if strict => Err(TrackerErrorKind::ValidationError(msg).into()),
+            warning => Ok(warning),
+        }
+    }
+
+    fn normalize_request(&mut self, default_numwant: u32) {
+        self.numwant = self.numwant.or(Some(default_numwant));
+        self.uploaded = self.uploaded.or(Some(0));
+        self.downloaded = self.downloaded.or(Some(0));
+    }
+
+    fn wants_compact(&self) -> bool {
+        self.compact
+    }
+
+    fn is_dry_run(&self) -> bool {
+        self.dryrun.is_some_and(|dryrun| dryrun != 0)
+    }
+
+    /// A client is a seeder once it has nothing left to download. Clients that don't tell us how
+    /// much they have left are assumed to still be downloading.
+    fn is_seeder(&self) -> bool {
+        self.left == Some(0)
+    }
+}
+
+#[derive(Debug)]
+struct ScrapeRequest {
+    // The torrents the client wants to know about. Empty means every torrent we track.
+    info_hashes: Vec<InfoHash>,
+    // Non-standard: whether to include the names of the torrents we know.
+    extended: bool,
+}
+
+impl ScrapeRequest {
     fn from_query_string(qs: &str) -> Result<Self, TrackerError> {
-        serde_urlencoded::from_str(qs).map_err(|err| TrackerError::new(err.to_string()))
+        // serde_urlencoded can't collect a repeated key into a Vec (nor keep binary values), so
+        // gather all the pairs and pick out the info_hashes ourselves
+        let params: Vec<(String, Vec<u8>)> = raw_query_pairs(qs)
+            .map(|(_, key, value)| (key, value))
+            .collect();
+
+        let mut info_hashes = params
+            .iter()
+            .filter(|(key, _)| key == "info_hash")
+            .map(|(_, value)| InfoHash::try_from(&value[..]))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| {
+                let msg = "Invalid infohash: infohash is not 20 bytes long.".to_string();
+                TrackerErrorKind::ValidationError(msg)
+            })?;
+
+        // the order and repetition of info_hashes doesn't matter, so normalize them to make
+        // identical requests look identical
+        info_hashes.sort();
+        info_hashes.dedup();
+        let extended = params
+            .iter()
+            .any(|(key, value)| key == "extended" && (value == b"1" || value == b"true"));
+        Ok(Self {
+            info_hashes,
+            extended,
+        })
     }
 
-    fn validate_request(&self) -> Result<(), TrackerError> {
-        // let ret = match (self.info_hash.len(), self.peer_id.len()) {
-        //     (20, 20) => Ok(()),
-        //     (20, _) => Err("Invalid peerid: peerid is not 20 bytes long."),
-        //     (_, _) => Err("Invalid infohash: infohash is not 20 bytes long."),
-        // };
-        // ret.map_err(|s: &str| TrackerError::new(s.to_string()))
-        Ok(())
+    /// Parses the info_hash of a `/scrape/<info_hash>` request, which is given as 40 hex digits.
+    fn from_hex(hex: &str) -> Result<Self, TrackerError> {
+        let info_hash = info_hash_from_hex(hex).ok_or_else(|| {
+            let msg = format!("Invalid infohash: '{}' is not 40 hex digits.", hex);
+            TrackerErrorKind::ValidationError(msg)
+        })?;
+        Ok(Self {
+            info_hashes: vec![info_hash],
+            extended: false,
+        })
+    }
+}
+
+/// A custom announce interval for one torrent, written as `<hex info_hash>=<seconds>`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IntervalOverride {
+    info_hash: InfoHash,
+    interval: u32,
+}
+
+impl str::FromStr for IntervalOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{}' isn't of the form <hex info_hash>=<seconds>", s);
+        let mut parts = s.splitn(2, '=');
+        let info_hash = parts
+            .next()
+            .and_then(info_hash_from_hex)
+            .ok_or_else(invalid)?;
+        let interval = parts
+            .next()
+            .and_then(|secs| secs.parse().ok())
+            .ok_or_else(invalid)?;
+        Ok(Self {
+            info_hash,
+            interval,
+        })
+    }
+}
+
+impl TryFrom<String> for IntervalOverride {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
     }
+}
+
+impl From<IntervalOverride> for String {
+    fn from(o: IntervalOverride) -> Self {
+        let hex: String = o.info_hash.0.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}={}", hex, o.interval)
+    }
+}
+
+/// Parses an info_hash written out as 40 hex digits.
+fn info_hash_from_hex(hex: &str) -> Option<InfoHash> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0u8; 20];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        // the length and ascii checks above make these two characters valid utf-8
+        let digits = str::from_utf8(digits).unwrap();
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(InfoHash(bytes))
+}
+
+/// Splits a query string into its pairs, each along with its decoded key and its value
+/// percent-decoded into raw bytes. Unlike serde_urlencoded, values aren't required to be utf-8.
+fn raw_query_pairs(qs: &str) -> impl Iterator<Item = (&str, String, Vec<u8>)> {
+    // `+` stands for a space in query strings, and a real `+` is always escaped
+    let decode = |s: &str| -> Vec<u8> { percent_decode_str(&s.replace('+', " ")).collect() };
+    qs.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(move |pair| {
+            let mut parts = pair.splitn(2, '=');
+            let key = decode(parts.next().unwrap_or_default());
+            let value = decode(parts.next().unwrap_or_default());
+            (pair, String::from_utf8_lossy(&key).into_owned(), value)
+        })
+}
+
+/// The parameters every announce has to carry.
+const REQUIRED_ANNOUNCE_PARAMS: [&str; 3] = ["info_hash", "peer_id", "port"];
+
+/// The error for a request that left out the parameter `name`.
+fn missing_param(name: &str) -> TrackerError {
+    let msg = format!("missing required parameter: {}", name);
+    TrackerErrorKind::ParseError(msg).into()
+}
+
+/// Turns the values given for a binary query parameter into its type, requiring exactly one value
+/// of the right length. Other errors are worded like serde's, as for every other parameter.
+fn binary_param<T>(name: &str, mut values: Vec<Vec<u8>>) -> Result<T, TrackerError>
+where
+    T: for<'a> TryFrom<&'a [u8]>,
+{
+    let value = match (values.pop(), values.is_empty()) {
+        (Some(value), true) => value,
+        (Some(_), false) => {
+            let msg = format!("duplicate field `{}`", name);
+            return Err(TrackerErrorKind::ParseError(msg).into());
+        }
+        (None, _) => return Err(missing_param(name)),
+    };
+    T::try_from(&value).map_err(|_| {
+        let msg = format!(
+            "invalid length {}, expected a byte array of length 20",
+            value.len()
+        );
+        TrackerErrorKind::ParseError(msg).into()
+    })
+}
+
+/// Parses and validates the query string of an announce, without touching any tracker state, and
+/// returns the request along with a warning if it contradicts itself (see `validate_request`).
+/// This is the entry point for everything a client controls in an announce, so it must return an
+/// error rather than panic on any input, which makes it a good target for fuzzing.
+fn parse_announce(
+    query: &str,
+    strict: bool,
+    anonymous: bool,
+) -> Result<(TrackerRequest, Option<String>), TrackerError> {
+    let req = TrackerRequest::from_query_string(query, anonymous)?;
+    let warning = req.validate_request(strict)?;
+    Ok((req, warning))
+}
 
-    fn normalize_request(&mut self) {
-        self.numwant = self.numwant.or(Some(50));
+/// Parses a boolean query parameter given as `1`/`0` or `true`/`false`.
+fn deserialize_flag<'de, D: de::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    match value.as_str() {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        other => Err(de::Error::invalid_value(
+            de::Unexpected::Str(other),
+            &"1, 0, true or false",
+        )),
     }
 }
 
@@ -157,171 +827,4808 @@ enum ClientEvent {
     Stopped,
     // This event must be sent to the tracker when the download completes.
     Completed,
+    // Any event we don't recognize (including BEP 3's 'empty') is treated as a regular announce,
+    // so that new events don't break older trackers.
+    #[serde(other)]
+    Unknown,
 }
 
-pub struct Tracker {
-    opt: Opt,
-    // TODO: replace with a concurrent hashmap for finer grained locking?
-    torrents: Mutex<HashMap<InfoHash, HashSet<Peer>>>,
-    complete_count: AtomicU32,
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum AddressFamily {
+    Ipv4,
+    Ipv6,
 }
 
-impl Tracker {
-    pub fn new(opt: Opt) -> Self {
-        Self {
-            opt,
-            torrents: Mutex::new(HashMap::new()),
-            complete_count: AtomicU32::new(0),
+impl AddressFamily {
+    fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Self::Ipv4,
+            IpAddr::V6(_) => Self::Ipv6,
         }
     }
+}
 
-    /// Registers a new peer as interested in a torrent if we don't already know about this peer.
-    fn maybe_register_new_peer(&self, req: &TrackerRequest) {
-        let mut torrents = self.torrents.lock().unwrap();
-        let peer = Peer {
-            peer_id: req.peer_id.clone(), // could probably have this be a borrow?
-            ip: req.ip.unwrap(), // TODO: we might need to infer the client's IP
-            port: req.port,
-        };
-
-        torrents
-            .entry(req.info_hash.clone()) // we identify a torrent by its info_hash
-            .or_insert(HashSet::new()) // create a mapping for new torrents
-            .insert(peer); // track all the peers participating in this torrent
-    }
-
-    /// Pick `numwant` number of random peers, excluding the client making this request, from the
-    /// torrent that the client is interested in.
-    // TODO: exclude the requester from the peer list
-    fn get_peers(&self, req: &TrackerRequest) -> Vec<Peer> {
-        let torrents = self.torrents.lock().unwrap();
-        let mut rng = rand::thread_rng();
-        let peers = torrents
-            .get(&req.info_hash)
-            .map_or(vec![], |peers: &HashSet<Peer>| {
-                // we can copy these out or return the MutexGuard
-                // since these borrow from the `torrents` MutexGuard we are not allowed to return
-                // references without also holding the lock.
-                peers.iter().choose_multiple(&mut rng, req.numwant.unwrap() as usize)
-            });
+/// What we know about a peer in a swarm. Stores keep it as they got it from `PeerStore::register`.
+#[derive(Clone, Debug)]
+pub struct PeerState {
+    // whether this peer reported that it has the entire file
+    seeder: bool,
+    // whether this peer only announces to show up in the swarm (see `numwant_zero_observers`), and
+    // so shouldn't be counted as downloading
+    observer: bool,
+    // when we last heard from this peer
+    last_seen: Instant,
+    // where this peer is, if `geo_aware` is on and we could tell
+    region: Option<Region>,
+    // bytes this peer had left to download when it last announced, if it told us
+    left: Option<u64>,
+    // bytes this peer has reported transferring over all of its announces
+    uploaded: Transfer,
+    downloaded: Transfer,
+}
 
-        peers.into_iter().copied().collect()
+impl PeerState {
+    pub fn is_seeder(&self) -> bool {
+        self.seeder
     }
 
-    pub fn handle_session(&self, req: Request<Body>) -> TrackerResult {
-        let uri = req.uri();
-        let ret = match (req.method(), uri.path(), uri.query()) {
-            (&Method::GET, "/announce", Some(query)) => {
-                let mut qs = TrackerRequest::from_query_string(query)?;
-                qs.validate_request()?;
-                qs.normalize_request();
-                self.maybe_register_new_peer(&qs);
-                match qs.event {
-                    Some(ClientEvent::Started) => unimplemented!(),
-                    Some(ClientEvent::Stopped) => unimplemented!(),
-                    Some(ClientEvent::Completed) => {
-                        self.complete_count.fetch_add(1, Ordering::Relaxed);
-                    },
-                    None => {}
-                }
-                Ok(TrackerResponse {
-                    interval: 1,
-                    peers: self.get_peers(&qs),
-                })
-            }
-            (&Method::GET, "/announce", None) => Err("Invalid request: no query string."),
-            (&Method::GET, _, _) => Err("Unrecognized path, try '/announce'."),
-            _ => Err("Invalid request type: client request was not an HTTP GET."),
-        };
+    /// When we last heard from this peer, which `PeerStore::reap` goes by.
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
 
-        ret.map_err(|s: &str| TrackerError::new(s.to_string()))
+    /// Where this peer is, which `PeerStore::sample` picks nearby peers by.
+    pub fn region(&self) -> Option<&Region> {
+        self.region.as_ref()
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use serde_bencode;
-    use std::net::Ipv4Addr;
-    use std::path::PathBuf;
+/// Adds up the running byte counts a peer reports in each announce.
+#[derive(Copy, Clone, Debug, Default)]
+struct Transfer {
+    // bytes transferred over every session we've seen
+    total: u64,
+    // the count in the peer's last announce
+    last_reported: u64,
+}
 
-    #[test]
-    fn peer_id_ser_test() {
-        let hash: [u8; 20] = ['a' as u8; 20];
-        let peer_id = PeerId(hash);
+impl Transfer {
+    fn record(&mut self, reported: u64) {
+        // the counts are since the client sent 'started', so a count that went down means the
+        // client restarted and this is all new
+        let delta = reported.checked_sub(self.last_reported).unwrap_or(reported);
+        self.total += delta;
+        self.last_reported = reported;
+    }
+}
 
-        assert_eq!(
-            format!("20:{}", str::from_utf8(&hash).unwrap()),
-            serde_bencode::to_string(&peer_id).unwrap(),
-        );
+/// All the peers participating in a single torrent.
+#[derive(Clone, Debug)]
+pub struct Swarm {
+    pub peers: HashMap<Peer, PeerState>,
+    // peers that sent 'stopped' within `stop_grace_period`, with when they're to be dropped. They
+    // aren't handed out or counted, but get their state back if they announce again in time.
+    pub leaving: HashMap<Peer, (PeerState, Instant)>,
+    // when the first peer registered for this torrent
+    pub created: Instant,
+    // when we last received an announce for this torrent
+    pub last_activity: Instant,
+    // number of 'completed' events received for this torrent
+    pub completed: u32,
+    // when the last 'completed' event was received
+    pub last_completed: Option<SystemTime>,
+    // peers joining and leaving
+    churn: Churn,
+    // how often peers announce to this torrent
+    announce_rate: AnnounceRate,
+}
+
+impl Swarm {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            peers: HashMap::new(),
+            leaving: HashMap::new(),
+            created: now,
+            last_activity: now,
+            completed: 0,
+            last_completed: None,
+            churn: Churn::default(),
+            announce_rate: AnnounceRate::default(),
+        }
     }
 
-    #[test]
-    fn peer_id_de_test() {
-        #[derive(Debug, Deserialize, PartialEq)]
-        struct TestData {
-            peer_id: PeerId,
+    /// Folds another node's view of this torrent into ours. Peers are matched up by address, and
+    /// whichever of the two announces is more recent wins.
+    pub fn merge(&mut self, other: &Swarm) {
+        let mut by_addr: HashMap<SocketAddr, Peer> =
+            self.peers.keys().map(|peer| (peer.addr(), *peer)).collect();
+        for (peer, state) in &other.peers {
+            if let Some(ours) = by_addr.get(&peer.addr()) {
+                if self.peers[ours].last_seen > state.last_seen {
+                    continue;
+                }
+                self.peers.remove(ours);
+            }
+            by_addr.insert(peer.addr(), *peer);
+            self.peers.insert(*peer, state.clone());
         }
+        self.created = self.created.min(other.created);
+        self.last_activity = self.last_activity.max(other.last_activity);
+        self.completed += other.completed;
+        self.last_completed = self.last_completed.max(other.last_completed);
+        self.churn.merge(&other.churn);
+        self.announce_rate.merge(&other.announce_rate);
+    }
 
-        let hash: [u8; 20] = ['a' as u8; 20];
-        let test_data = TestData { peer_id: PeerId(hash) };
+    /// Counts a peer joining or leaving at `now`, for the swarm's churn.
+    pub fn record_churn(&mut self, now: Instant) {
+        self.churn.record(now);
+    }
 
-        // this is throwing an error because serde can't deserialize a byte array into `an array of
-        // length 20`
-        assert_eq!(
-            PeerId(hash),
-            serde_bencode::from_str::<PeerId>("20:aaaaaaaaaaaaaaaaaaaa").unwrap(),
-        );
+    /// Counts an announce at `now`, for the swarm's announce rate.
+    pub fn record_announce(&mut self, now: Instant) {
+        self.announce_rate.record(now);
+    }
 
-        assert_eq!(
-            test_data,
-            serde_urlencoded::from_str::<TestData>("peer_id=aaaaaaaaaaaaaaaaaaaa").unwrap(),
-        );
+    pub fn info(&self, now: Instant) -> SwarmInfo {
+        SwarmInfo {
+            peers: self.peers.len(),
+            seeders: self.seeders(),
+            leechers: self.leechers(),
+            completed: self.completed,
+            last_completed: self.last_completed,
+            created: self.created,
+            last_activity: self.last_activity,
+            churn: self.churn.count(now),
+            announce_rate: self.announce_rate.at(now),
+        }
     }
 
-    #[test]
-    fn peer_id_wrong_length() {
-        assert!(serde_bencode::from_str::<PeerId>("3:abc").is_err());
+    fn seeders(&self) -> usize {
+        self.peers.values().filter(|state| state.seeder).count()
     }
 
-    #[test]
-    fn basic_ok_test() {
-        let peer = Peer {
-            peer_id: PeerId("abcdefghijklmnopqrst".as_bytes().try_into().unwrap()),
-            ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            port: 6981,
-        };
-        let response = TrackerResponse {
-            interval: 10,
-            peers: vec![peer],
-        };
+    fn leechers(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|state| !state.seeder && !state.observer)
+            .count()
+    }
+}
 
-        assert_eq!(
-            serde_bencode::to_string(&response).unwrap(),
-            "d8:intervali10e5:peersld2:ip9:127.0.0.17:peer id20:abcdefghijklmnopqrst4:porti6981eeee"
-        );
+/// The most `busy_announce_rate` will stretch the interval of a torrent by.
+const BUSY_INTERVAL_SCALE: f64 = 4.0;
+
+/// How far back the churn of a swarm is counted.
+const CHURN_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// When peers joined (with a 'started' event) or left (with a 'stopped' event, or by timing out) a
+/// swarm, as far back as CHURN_WINDOW.
+#[derive(Clone, Debug, Default)]
+struct Churn {
+    // oldest first
+    events: VecDeque<Instant>,
+}
+
+impl Churn {
+    fn record(&mut self, now: Instant) {
+        self.events.push_back(now);
+        self.expire(now);
     }
 
-    #[test]
-    fn basic_err_test() {
-        let err = TrackerError {
-            failure: "oops".to_string(),
-        };
+    /// Returns the number of joins and leaves within CHURN_WINDOW of `now`.
+    fn count(&self, now: Instant) -> usize {
+        self.events
+            .iter()
+            .filter(|&&event| now.saturating_duration_since(event) < CHURN_WINDOW)
+            .count()
+    }
 
-        assert_eq!(serde_bencode::to_string(&err).unwrap(), "d7:failure4:oopse");
+    fn expire(&mut self, now: Instant) {
+        while let Some(&oldest) = self.events.front() {
+            if now.saturating_duration_since(oldest) < CHURN_WINDOW {
+                break;
+            }
+            self.events.pop_front();
+        }
     }
 
-    #[test]
-    fn basic_handle_session() {
+    fn merge(&mut self, other: &Churn) {
+        let mut events: Vec<_> = self
+            .events
+            .drain(..)
+            .chain(other.events.iter().copied())
+            .collect();
+        events.sort();
+        self.events = events.into();
+    }
+}
+
+/// How quickly the announce rate of a swarm forgets older announces. Roughly the last minute of
+/// announces counts towards the rate.
+const ANNOUNCE_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// An exponentially weighted moving average of how many announces a swarm gets per second. Every
+/// announce adds to the rate, which then decays by a factor of e every ANNOUNCE_RATE_WINDOW.
+#[derive(Clone, Debug, Default)]
+struct AnnounceRate {
+    // announces per second, as of `updated`
+    rate: f64,
+    updated: Option<Instant>,
+}
+
+impl AnnounceRate {
+    fn record(&mut self, now: Instant) {
+        self.rate = self.at(now) + 1.0 / ANNOUNCE_RATE_WINDOW.as_secs_f64();
+        self.updated = Some(now);
+    }
+
+    /// Returns the rate as of `now`, decayed for however long it's been since the last announce.
+    fn at(&self, now: Instant) -> f64 {
+        let elapsed = self.updated.map_or(Duration::ZERO, |updated| {
+            now.saturating_duration_since(updated)
+        });
+        self.rate * (-elapsed.as_secs_f64() / ANNOUNCE_RATE_WINDOW.as_secs_f64()).exp()
+    }
+
+    /// Adds in the announces another node has seen, since clients announce to both.
+    fn merge(&mut self, other: &AnnounceRate) {
+        let now = match (self.updated, other.updated) {
+            (Some(ours), Some(theirs)) => ours.max(theirs),
+            (ours, theirs) => match ours.or(theirs) {
+                Some(updated) => updated,
+                None => return,
+            },
+        };
+        self.rate = self.at(now) + other.at(now);
+        self.updated = Some(now);
+    }
+}
+
+/// Everything about the tracker worth keeping across a restart. Timestamps aren't saved: restored
+/// peers count as freshly seen, and expire as usual if they don't announce again.
+#[derive(Debug, Serialize, Deserialize)]
+struct TrackerSnapshot {
+    completed: u32,
+    torrents: Vec<TorrentSnapshot>,
+}
+
+// bencode doesn't have booleans, so seeders and leechers are kept in separate lists
+#[derive(Debug, Serialize, Deserialize)]
+struct TorrentSnapshot {
+    #[serde(rename = "info hash")]
+    info_hash: InfoHash,
+    completed: u32,
+    seeders: Vec<Peer>,
+    leechers: Vec<Peer>,
+}
+
+/// A peer as `export_json` writes it out, for tools that don't speak bencode.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedPeer {
+    ip: IpAddr,
+    port: u16,
+    peer_id_hex: String,
+    seeder: bool,
+    // seconds since the peer last announced
+    last_seen: u64,
+}
+
+/// The tracker's shared state.
+///
+/// All of the locks here are plain `std::sync::Mutex`es, which is fine as long as they are only
+/// held for quick, synchronous bookkeeping: a lock must never be held across an `.await`, or while
+/// doing blocking I/O. Async work such as connectability checks is spawned onto its own task after
+/// the lock is released, and snapshots copy the state out before touching the disk.
+pub struct Tracker {
+    opt: Arc<Opt>,
+    // the peers of every torrent
+    store: Arc<dyn PeerStore>,
+    complete_count: AtomicU32,
+    // recently computed scrape responses, keyed on the (sorted) info_hashes that were requested
+    scrape_cache: Mutex<HashMap<Vec<InfoHash>, (Instant, ScrapeResponse)>>,
+    // used to check that peers accept connections when `check_connectable` is on
+    connector: Arc<dyn Connector>,
+    connect_checks: Arc<ConnectCache>,
+    // where connection checks run, since requests may be handled outside of any runtime
+    runtime: Option<Handle>,
+    // announce intervals for torrents that don't use the global `interval`
+    interval_overrides: HashMap<InfoHash, u32>,
+    // used to prefer peers from the client's own region when `geo_aware` is on
+    geo: Arc<dyn GeoLookup>,
+    // whether the saved state has been loaded, if there is any, and we can serve requests
+    ready: AtomicBool,
+    // names of the torrents we have the metainfo of, for extended scrapes
+    torrent_names: HashMap<InfoHash, String>,
+    // how often each address announces, when `max_ip_announce_rate` is set
+    ip_announce_rates: Mutex<HashMap<IpAddr, AnnounceRate>>,
+}
+
+impl Tracker {
+    /// Creates a tracker configured by `opt`, which may be shared with the rest of the program.
+    pub fn new(opt: impl Into<Arc<Opt>>) -> Self {
+        let opt = opt.into();
+        let connect_checks = ConnectCache::new(
+            opt.connect_checks_per_sec,
+            Duration::from_secs(opt.connect_retry_interval),
+        );
+        let interval_overrides = opt
+            .interval_override
+            .iter()
+            .map(|o| (o.info_hash, o.interval))
+            .collect();
+        // with a snapshot to load, we're not ready until it's been loaded
+        let ready = AtomicBool::new(opt.snapshot.is_none());
+        Self {
+            opt,
+            store: Arc::new(InMemoryStore::default()),
+            complete_count: AtomicU32::new(0),
+            scrape_cache: Mutex::new(HashMap::new()),
+            connector: Arc::new(TcpConnector::new(CONNECT_TIMEOUT)),
+            connect_checks: Arc::new(connect_checks),
+            runtime: Handle::try_current().ok(),
+            interval_overrides,
+            geo: Arc::new(NoLookup),
+            ready,
+            torrent_names: HashMap::new(),
+            ip_announce_rates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reads the metainfo of the torrent at `root`, or of every `.torrent` file directly inside it
+    /// if it's a directory, so extended scrapes can name them. Files that aren't valid metainfo are
+    /// skipped with a warning. Returns the number of torrents loaded.
+    pub fn load_torrents(&mut self, root: &Path) -> io::Result<usize> {
+        let mut paths = if root.is_dir() {
+            let mut paths = Vec::new();
+            for entry in fs::read_dir(root)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "torrent") {
+                    paths.push(path);
+                }
+            }
+            paths
+        } else {
+            vec![root.to_path_buf()]
+        };
+        paths.sort();
+
+        let mut loaded = 0;
+        for path in paths {
+            let metainfo = match OwnedMetaInfo::from_bytes(&fs::read(&path)?) {
+                Ok(metainfo) => metainfo,
+                Err(err) => {
+                    tracing::warn!(path = %path.display(), %err, "skipping invalid metainfo");
+                    continue;
+                }
+            };
+            let info_hash = InfoHash(metainfo.info_hash());
+            self.torrent_names.insert(info_hash, metainfo.info.name);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// Uses `geo` to find out which region peers are in when `geo_aware` is on.
+    pub fn with_geo(mut self, geo: Arc<dyn GeoLookup>) -> Self {
+        self.geo = geo;
+        self
+    }
+
+    /// Runs connection checks (see `check_connectable`) on `runtime`. By default they run on the
+    /// runtime the tracker was created on, and aren't run at all if there wasn't one.
+    pub fn with_runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Keeps swarms in `store` rather than in memory.
+    pub fn with_store(mut self, store: Arc<dyn PeerStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Whether serving a request may block on the store, see `PeerStore::may_block`.
+    pub fn store_may_block(&self) -> bool {
+        self.store.may_block()
+    }
+
+    fn region(&self, ip: IpAddr) -> Option<Region> {
+        if self.opt.geo_aware {
+            self.geo.region(ip)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(test)]
+    fn with_connector(mut self, connector: Arc<dyn Connector>) -> Self {
+        self.connector = connector;
+        self
+    }
+
+    /// Registers a new peer as interested in a torrent if we don't already know about this peer,
+    /// and records whether it's seeding. Returns the peer, and whether it's new to the swarm.
+    fn maybe_register_new_peer(&self, req: &TrackerRequest, ip: IpAddr) -> (Peer, bool) {
+        let region = self.region(ip);
+        let now = Instant::now();
+        let new = Cell::new(false);
+        let peer = Peer {
+            peer_id: req.peer_id, // could probably have this be a borrow?
+            ip,
+            port: req.port,
+        };
+
+        self.store.register(req.info_hash, peer, &|previous| {
+            new.set(previous.is_none());
+            // keep adding to the transfer counts of peers we already know
+            let mut uploaded = previous.map_or_else(Transfer::default, |state| state.uploaded);
+            let mut downloaded = previous.map_or_else(Transfer::default, |state| state.downloaded);
+            if let Some(reported) = req.uploaded {
+                uploaded.record(reported);
+            }
+            if let Some(reported) = req.downloaded {
+                downloaded.record(reported);
+            }
+
+            PeerState {
+                seeder: req.is_seeder(),
+                observer: self.opt.numwant_zero_observers && req.numwant == Some(0),
+                last_seen: now,
+                region: region.clone(),
+                left: req.left,
+                uploaded,
+                downloaded,
+            }
+        });
+        (peer, new.get())
+    }
+
+    /// Starts checking in the background whether other clients will be able to connect to this
+    /// peer, unless we already know or have hit the rate limit for checks. Until a check succeeds
+    /// the peer isn't handed out to anyone.
+    fn schedule_connect_check(&self, peer: &Peer) {
+        let runtime = match &self.runtime {
+            Some(runtime) => runtime,
+            None => {
+                tracing::debug!("no runtime to check whether peers are connectable on");
+                return;
+            }
+        };
+        let addr = peer.addr();
+        if !self.connect_checks.begin_check(addr, Instant::now()) {
+            return;
+        }
+
+        let connector = self.connector.clone();
+        let connect_checks = self.connect_checks.clone();
+        runtime.spawn(async move {
+            let reachable = connector.connect(addr).await;
+            connect_checks.record(addr, reachable, Instant::now());
+        });
+    }
+
+    /// Saves the current swarms to `path`, see the `snapshot` module for the format.
+    pub fn save_snapshot(&self, path: &Path) -> Result<(), SnapshotError> {
+        let snapshot = {
+            let torrents = self
+                .store
+                .swarms()
+                .into_iter()
+                .map(|(info_hash, swarm)| {
+                    let (seeders, leechers) = swarm
+                        .peers
+                        .iter()
+                        .partition::<Vec<_>, _>(|(_, state)| state.seeder);
+                    TorrentSnapshot {
+                        info_hash,
+                        completed: swarm.completed,
+                        seeders: seeders.into_iter().map(|(peer, _)| *peer).collect(),
+                        leechers: leechers.into_iter().map(|(peer, _)| *peer).collect(),
+                    }
+                })
+                .collect();
+
+            TrackerSnapshot {
+                completed: self.complete_count.load(Ordering::Relaxed),
+                torrents,
+            }
+        };
+
+        snapshot::write(path, &snapshot)
+    }
+
+    /// Writes out every swarm as a JSON object mapping hex info_hashes to their peers, for
+    /// external tools. Each peer is `{ip, port, peer_id_hex, seeder, last_seen}`, where
+    /// `last_seen` is how many seconds ago the peer last announced.
+    pub fn export_json(&self) -> String {
+        let now = Instant::now();
+        let torrents: BTreeMap<String, Vec<ExportedPeer>> = self
+            .store
+            .swarms()
+            .into_iter()
+            .map(|(info_hash, swarm)| {
+                let mut peers: Vec<_> = swarm.peers.iter().collect();
+                peers.sort_by_key(|(peer, _)| (peer.addr(), peer.peer_id));
+                let peers = peers
+                    .into_iter()
+                    .map(|(peer, state)| ExportedPeer {
+                        ip: peer.ip,
+                        port: peer.port,
+                        peer_id_hex: hex::encode(peer.peer_id.0),
+                        seeder: state.seeder,
+                        last_seen: now.saturating_duration_since(state.last_seen).as_secs(),
+                    })
+                    .collect();
+                (hex::encode(info_hash.0), peers)
+            })
+            .collect();
+        serde_json::to_string(&torrents).expect("exported swarms are always serializable")
+    }
+
+    /// Adds the swarms written out by `export_json` to this tracker. A peer that's already
+    /// registered at the same ip and port is only replaced if the imported one was seen more
+    /// recently. Nothing is imported unless the whole document is valid. Returns the number of
+    /// peers imported.
+    pub fn import_json(&self, json: &str) -> io::Result<usize> {
+        let torrents: BTreeMap<String, Vec<ExportedPeer>> = serde_json::from_str(json)?;
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let now = Instant::now();
+        let mut imports = Vec::with_capacity(torrents.len());
+        for (hex_hash, peers) in torrents {
+            let info_hash = info_hash_from_hex(&hex_hash)
+                .ok_or_else(|| invalid(format!("invalid info_hash {:?}", hex_hash)))?;
+            let mut imported = Vec::with_capacity(peers.len());
+            for exported in peers {
+                let peer_id = hex::decode(&exported.peer_id_hex)
+                    .ok()
+                    .and_then(|bytes| PeerId::try_from(&bytes[..]).ok())
+                    .ok_or_else(|| {
+                        invalid(format!("invalid peer_id {:?}", exported.peer_id_hex))
+                    })?;
+                let peer = Peer {
+                    peer_id,
+                    ip: exported.ip,
+                    port: exported.port,
+                };
+                let last_seen = now
+                    .checked_sub(Duration::from_secs(exported.last_seen))
+                    .unwrap_or(now);
+                imported.push((peer, exported.seeder, last_seen));
+            }
+            imports.push((info_hash, imported));
+        }
+
+        let mut count = 0;
+        for (info_hash, peers) in imports {
+            // the peers already registered at each address, which may have another peer_id
+            let mut existing: HashMap<SocketAddr, (Peer, Instant)> = self
+                .store
+                .swarm(&info_hash)
+                .map(|swarm| {
+                    swarm
+                        .peers
+                        .iter()
+                        .map(|(peer, state)| (peer.addr(), (*peer, state.last_seen)))
+                        .collect()
+                })
+                .unwrap_or_default();
+            for (peer, seeder, last_seen) in peers {
+                let replaced = match existing.get(&peer.addr()) {
+                    Some((_, seen)) if *seen >= last_seen => continue,
+                    Some((old, _)) if *old != peer => Some(*old),
+                    _ => None,
+                };
+                let region = self.region(peer.ip);
+                self.store.register(info_hash, peer, &|_| PeerState {
+                    seeder,
+                    observer: false,
+                    last_seen,
+                    region: region.clone(),
+                    left: None,
+                    uploaded: Transfer::default(),
+                    downloaded: Transfer::default(),
+                });
+                // only once the new peer is in, so the swarm is never left empty and dropped
+                if let Some(old) = replaced {
+                    self.store.remove(&info_hash, &old, now);
+                }
+                existing.insert(peer.addr(), (peer, last_seen));
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Replaces the current swarms with the ones saved in the snapshot at `path`.
+    pub fn load_snapshot(&self, path: &Path) -> Result<(), SnapshotError> {
+        let snapshot: TrackerSnapshot = snapshot::read(path)?;
+        let now = Instant::now();
+
+        let mut torrents = Vec::with_capacity(snapshot.torrents.len());
+        for saved in snapshot.torrents {
+            let mut swarm = Swarm::new(now);
+            swarm.completed = saved.completed;
+            let seeders = saved.seeders.into_iter().map(|peer| (peer, true));
+            let leechers = saved.leechers.into_iter().map(|peer| (peer, false));
+            for (peer, seeder) in seeders.chain(leechers) {
+                let state = PeerState {
+                    seeder,
+                    observer: false,
+                    last_seen: now,
+                    region: self.region(peer.ip),
+                    left: None,
+                    uploaded: Transfer::default(),
+                    downloaded: Transfer::default(),
+                };
+                swarm.peers.insert(peer, state);
+            }
+            torrents.push((saved.info_hash, swarm));
+        }
+        self.store.restore(torrents);
+        self.complete_count
+            .store(snapshot.completed, Ordering::Relaxed);
+        self.mark_ready();
+        Ok(())
+    }
+
+    /// Reports the tracker as ready to serve requests on `/readyz`. Without a `--snapshot` it's
+    /// ready from the start, otherwise this happens once the snapshot has been loaded, or it turned
+    /// out there was none to load.
+    pub fn mark_ready(&self) {
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    /// Adds the peers listed in a seed file to their swarms, as if they had just announced. Each
+    /// line of the file holds a hex info_hash, an ip and a port separated by whitespace; blank
+    /// lines and lines starting with `#` are skipped. Seeded peers are reaped like any other peer
+    /// if they never announce themselves. Returns the number of peers added.
+    pub fn seed_from_file(&self, path: &Path) -> io::Result<usize> {
+        let contents = fs::read_to_string(path)?;
+        let mut seeds = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let seed = parse_seed(line).ok_or_else(|| {
+                let msg = format!("line {}: expected '<info_hash> <ip> <port>'", i + 1);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?;
+            seeds.push(seed);
+        }
+
+        let now = Instant::now();
+        for (info_hash, peer) in &seeds {
+            let region = self.region(peer.ip);
+            self.store.register(*info_hash, *peer, &|_| PeerState {
+                seeder: false,
+                observer: false,
+                last_seen: now,
+                region: region.clone(),
+                left: None,
+                uploaded: Transfer::default(),
+                downloaded: Transfer::default(),
+            });
+        }
+        Ok(seeds.len())
+    }
+
+    /// Merges the swarms known to another tracker node into this one, and adds up their completion
+    /// counts.
+    pub fn merge(&self, other: &Tracker) {
+        if std::ptr::eq(self, other) {
+            return;
+        }
+
+        // copy the other node's state out first, so the two trackers are never locked at once and
+        // concurrent merges in opposite directions can't deadlock
+        self.store.merge(other.store.swarms());
+        self.complete_count.fetch_add(
+            other.complete_count.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Forgets peers that haven't announced within the peer timeout, along with any torrent left
+    /// without peers so the map doesn't fill up with dead swarms.
+    pub fn reap_expired(&self, now: Instant) {
+        let timeout = Duration::from_secs(self.opt.peer_timeout);
+        self.store.reap(now, timeout);
+        self.connect_checks.reap(now);
+        // addresses that have gone quiet would never be limited again
+        let mut rates = lock(&self.ip_announce_rates);
+        rates.retain(|_, rate| rate.at(now) * ANNOUNCE_RATE_WINDOW.as_secs_f64() >= 0.01);
+    }
+
+    /// With `max_ip_announce_rate` set, counts an announce from `ip`, unless it's already been
+    /// announcing faster than that, in which case it's turned away.
+    fn check_announce_rate(&self, ip: IpAddr, now: Instant) -> Result<(), TrackerError> {
+        let max_rate = match self.opt.max_ip_announce_rate {
+            Some(max_rate) => max_rate,
+            None => return Ok(()),
+        };
+        let mut rates = lock(&self.ip_announce_rates);
+        let rate = rates.entry(ip).or_default();
+        if rate.at(now) >= max_rate {
+            return Err(TrackerErrorKind::RateLimited.into());
+        }
+        rate.record(now);
+        Ok(())
+    }
+
+    /// With `max_peers` set, turns away a peer we don't know yet once that many are registered.
+    fn check_capacity(&self, qs: &TrackerRequest, ip: IpAddr) -> Result<(), TrackerError> {
+        let max_peers = match self.opt.max_peers {
+            Some(max_peers) => max_peers,
+            None => return Ok(()),
+        };
+        if self.store.peer_count() < max_peers {
+            return Ok(());
+        }
+        let peer = Peer {
+            peer_id: qs.peer_id,
+            ip,
+            port: qs.port,
+        };
+        match self.store.peer(&qs.info_hash, &peer) {
+            Some(_) => Ok(()),
+            None => Err(TrackerErrorKind::Capacity.into()),
+        }
+    }
+
+    /// Pick `numwant` number of random peers from the torrent that the client is interested in,
+    /// leaving the client itself out with `exclude_requester`. With `geo_aware` on, peers in the
+    /// same region as the client at `ip` are picked first, and the rest of the list is filled from
+    /// elsewhere. Only peers in `family` are picked, if it's given. With `seeder_share` set, that
+    /// share of the list is filled with seeders and the rest with leechers, topped up from the
+    /// other side when either runs short. With `collapse_dual_stack`, a host registered at both an
+    /// IPv4 and an IPv6 address is only handed out at the one in the client's family.
+    ///
+    /// No more than the `swarm_len` peers the swarm has are ever asked for, so a client asking for
+    /// a huge `numwant` gets the whole swarm, and nothing is sized after its numwant.
+    fn get_peers(
+        &self,
+        req: &TrackerRequest,
+        numwant: u32,
+        swarm_len: usize,
+        ip: IpAddr,
+        family: Option<AddressFamily>,
+    ) -> Vec<Peer> {
+        let region = self.region(ip);
+        let now = Instant::now();
+        let reachable = |peer: &Peer| {
+            !(self.opt.exclude_requester && peer.peer_id == req.peer_id)
+                && family.is_none_or(|family| AddressFamily::of(peer.ip) == family)
+                && (!self.opt.check_connectable
+                    || self.connect_checks.is_reachable(&peer.addr(), now))
+        };
+        let selection = if self.opt.stable_peers {
+            Selection::Recent
+        } else {
+            Selection::Random
+        };
+        let sample = |count: usize, eligible: &dyn Fn(&Peer, &PeerState) -> bool| {
+            self.store
+                .sample(&req.info_hash, count, selection, region.as_ref(), eligible)
+        };
+
+        let numwant = usize::try_from(numwant)
+            .unwrap_or(usize::MAX)
+            .min(swarm_len);
+        let mut peers = match self.opt.seeder_share {
+            None => sample(numwant, &|peer, _| reachable(peer)),
+            Some(share) => {
+                let wanted_seeders = (numwant as f64 * share.clamp(0.0, 1.0)).round() as usize;
+                let mut peers = sample(wanted_seeders, &|peer, state| {
+                    state.seeder && reachable(peer)
+                });
+                let seeders = peers.len();
+                peers.extend(sample(numwant - seeders, &|peer, state| {
+                    !state.seeder && reachable(peer)
+                }));
+                // not enough leechers, so give the rest of the slots back to seeders
+                if peers.len() < numwant && seeders == wanted_seeders {
+                    let chosen = peers.clone();
+                    peers.extend(sample(numwant - peers.len(), &|peer, state| {
+                        state.seeder && reachable(peer) && !chosen.contains(peer)
+                    }));
+                }
+                // mix the seeders in with the leechers, unless the nearby peers have to come first
+                if selection == Selection::Random && region.is_none() {
+                    peers.shuffle(&mut rand::thread_rng());
+                }
+                peers
+            }
+        };
+        if self.opt.collapse_dual_stack {
+            collapse_dual_stack(&mut peers, family.unwrap_or_else(|| AddressFamily::of(ip)));
+        }
+        peers
+    }
+
+    /// Handles an announce whose request came from `remote_ip`.
+    pub fn announce(&self, query: &str, remote_ip: IpAddr) -> TrackerResult {
+        self.handle_announce(query, remote_ip, false)
+    }
+
+    // `head` announces are only validated and answered, like a dry run, so that checking on the
+    // tracker with HEAD doesn't add peers to the swarm.
+    fn handle_announce(&self, query: &str, remote_ip: IpAddr, head: bool) -> TrackerResult {
+        self.check_announce_rate(remote_ip, Instant::now())?;
+        let (mut qs, contradiction) = parse_announce(
+            query,
+            self.opt.reject_contradictory,
+            self.opt.anonymous_announces,
+        )?;
+        qs.normalize_request(self.opt.peers);
+        let numwant = qs.numwant.unwrap_or(self.opt.peers);
+        let ips = self.peer_ips(&qs, remote_ip);
+        if qs.anonymous {
+            qs.peer_id = anonymous_peer_id(ips[0], qs.port, qs.key.as_deref());
+        }
+        tracing::info!(
+            ip = %self.logged_ip(ips[0]),
+            port = qs.port,
+            dry_run = qs.is_dry_run(),
+            head,
+            "announce"
+        );
+        if !head && !qs.is_dry_run() && !matches!(qs.event, Some(ClientEvent::Stopped)) {
+            self.check_capacity(&qs, ips[0])?;
+        }
+
+        // a dry run gets the response it would have gotten, but isn't registered and isn't counted
+        let ignored = if qs.is_dry_run() {
+            if !self.opt.allow_dry_run {
+                let msg = "dry-run announces are disabled".to_string();
+                return Err(TrackerErrorKind::NotAllowed(msg).into());
+            }
+            None
+        } else if head {
+            None
+        } else {
+            self.register_announce(&qs, &ips)
+        };
+
+        let info = self.store.stats(&qs.info_hash, Instant::now());
+        let (complete, incomplete) = info.map_or((0, 0), |info| (info.seeders, info.leechers));
+        // asking for no more than the share we'd hand out keeps the interval from being shortened
+        // for a list we cut short on purpose
+        let numwant = match self.opt.max_swarm_share {
+            Some(share) => {
+                let allowed = ((complete + incomplete) as f64 * share.clamp(0.0, 1.0)) as u32;
+                numwant.min(allowed)
+            }
+            None => numwant,
+        };
+        let ratio_warning = self.ratio_warning(&qs, ips[0]);
+        // ask for one peer past the cap, to tell whether the list really was cut short
+        let cap = self.opt.max_response_peers.unwrap_or(u32::MAX);
+        let mut peers = if ratio_warning.is_some() {
+            Vec::new()
+        } else {
+            let wanted = numwant.min(cap.saturating_add(1));
+            let swarm_len = info.map_or(0, |info| info.peers);
+            let family = self.wanted_family(&qs, &ips);
+            self.get_peers(&qs, wanted, swarm_len, ips[0], family)
+        };
+        let truncated = if peers.len() > cap as usize {
+            peers.truncate(cap as usize);
+            Some(format!(
+                "responses are limited to {} peers, fewer than the {} asked for",
+                cap, numwant
+            ))
+        } else {
+            None
+        };
+        let numwant = numwant.min(cap);
+        let mut warning_message = ratio_warning.or(contradiction).or(ignored).or(truncated);
+        if warning_message.is_none() && peers.len() < numwant as usize {
+            warning_message = self.sparse_swarm_warning(complete + incomplete);
+        }
+        let rate = info.map_or(0.0, |info| info.announce_rate);
+        let min_interval = self.min_interval();
+        let interval = self.announce_interval(
+            &qs.info_hash,
+            qs.is_seeder(),
+            rate,
+            peers.len(),
+            numwant,
+            min_interval,
+        );
+        let (peers, peers6) = if qs.wants_compact() {
+            let (peers, peers6) = PeerList::compact(&peers);
+            (peers, Some(peers6).filter(|peers6| !peers6.is_empty()))
+        } else {
+            let peers = if self.opt.debug_peer_clients {
+                PeerList::Labeled(peers.iter().map(LabeledPeer::from).collect())
+            } else {
+                PeerList::Dict(peers)
+            };
+            (peers, None)
+        };
+
+        Ok(TrackerResponse {
+            interval,
+            min_interval: Some(min_interval),
+            complete,
+            incomplete,
+            downloaded: info.map(|info| info.completed),
+            external_ip: Some(ExternalIp(remote_ip)).filter(|_| self.opt.external_ip),
+            peers,
+            peers6,
+            warning_message,
+        })
+    }
+
+    /// Picks the address family a client at `ips` should get peers in: the one it asked for, or
+    /// with `match_address_family`, the only one it can be reached over.
+    fn wanted_family(&self, qs: &TrackerRequest, ips: &[IpAddr]) -> Option<AddressFamily> {
+        if qs.family.is_some() || !self.opt.match_address_family {
+            return qs.family;
+        }
+        // a client's BEP 7 addresses say which families it can use even when we don't trust them
+        // enough to hand them out
+        let family = AddressFamily::of(ips[0]);
+        let mut reachable_at = ips
+            .iter()
+            .copied()
+            .chain(qs.ipv4.map(IpAddr::V4))
+            .chain(qs.ipv6.map(IpAddr::V6));
+        Some(family).filter(|_| reachable_at.all(|ip| AddressFamily::of(ip) == family))
+    }
+
+    /// Returns a warning for a leecher whose share ratio is below `--min-ratio`, which is then
+    /// refused peers. Peers that haven't downloaded anything yet get the benefit of the doubt.
+    fn ratio_warning(&self, qs: &TrackerRequest, ip: IpAddr) -> Option<String> {
+        let min_ratio = self.opt.min_ratio?;
+        if qs.is_seeder() {
+            return None;
+        }
+
+        let peer = Peer {
+            peer_id: qs.peer_id,
+            ip,
+            port: qs.port,
+        };
+        let (uploaded, downloaded) = self.peer_stats(&qs.info_hash, &peer);
+        let ratio = uploaded as f64 / downloaded as f64;
+        if downloaded == 0 || ratio >= min_ratio {
+            return None;
+        }
+        Some(format!(
+            "your share ratio of {:.2} is below the minimum of {:.2}, upload more to get peers",
+            ratio, min_ratio
+        ))
+    }
+
+    /// Returns a warning for a client that got fewer peers than it wanted from a swarm of `size`,
+    /// if that's smaller than `--sparse-swarm-size`, so it knows the list is short because the
+    /// swarm is and not because of us.
+    fn sparse_swarm_warning(&self, size: usize) -> Option<String> {
+        let sparse_size = self.opt.sparse_swarm_size?;
+        if size >= sparse_size {
+            return None;
+        }
+        Some(format!(
+            "this torrent only has {} peer{}, try again later for more",
+            size,
+            if size == 1 { "" } else { "s" }
+        ))
+    }
+
+    /// With `soft_fail_interval` set, turns an announce that failed because we're too busy into a
+    /// response without peers that tells the client to come back after that interval. Clients
+    /// tend to retry failed announces right away, while they always honor the interval.
+    fn soft_fail(&self, result: TrackerResult) -> TrackerResult {
+        let interval = match (self.opt.soft_fail_interval, &result) {
+            (Some(interval), Err(err)) if err.is_transient() => interval,
+            _ => return result,
+        };
+        let warning = result.unwrap_err().kind.to_string();
+        Ok(TrackerResponse {
+            interval,
+            min_interval: Some(interval),
+            complete: 0,
+            incomplete: 0,
+            downloaded: None,
+            external_ip: None,
+            peers: PeerList::Dict(Vec::new()),
+            peers6: None,
+            warning_message: Some(warning),
+        })
+    }
+
+    /// Picks the interval to send a client of `info_hash` that got `returned` peers after asking
+    /// for `numwant`. Clients that got fewer peers than they wanted are asked to come back sooner,
+    /// in proportion to how short their list was, so they pick up new peers as the swarm grows.
+    /// With `seeder_interval` set, seeders get that instead, whatever they got.
+    ///
+    /// With `busy_announce_rate` set, torrents getting more announces per second than that have
+    /// their interval stretched in proportion, up to BUSY_INTERVAL_SCALE times. No interval is
+    /// shorter than `min_interval`.
+    fn announce_interval(
+        &self,
+        info_hash: &InfoHash,
+        seeder: bool,
+        rate: f64,
+        returned: usize,
+        numwant: u32,
+        min_interval: u32,
+    ) -> u32 {
+        let seeder_interval = self.opt.seeder_interval.filter(|_| seeder);
+        let mut interval = match self.interval_overrides.get(info_hash) {
+            Some(interval) => *interval,
+            None => seeder_interval.unwrap_or(self.opt.interval),
+        };
+        if let Some(busy_rate) = self.opt.busy_announce_rate {
+            let scale = (rate / busy_rate).clamp(1.0, BUSY_INTERVAL_SCALE);
+            interval = (f64::from(interval) * scale).round() as u32;
+        }
+        let interval = interval.max(min_interval);
+        if returned >= numwant as usize || seeder_interval.is_some() {
+            return interval;
+        }
+
+        let shortened = u64::from(interval) * returned as u64 / u64::from(numwant);
+        (shortened as u32).max(min_interval).min(interval)
+    }
+
+    /// The shortest interval clients may be given: `min_interval`, raised with
+    /// `interval_floor_torrents` while more torrents than that are tracked.
+    fn min_interval(&self) -> u32 {
+        let floor = self.opt.min_interval;
+        let threshold = match self.opt.interval_floor_torrents {
+            Some(threshold) => threshold.max(1),
+            None => return floor,
+        };
+        let torrents = self.store.torrent_count();
+        if torrents <= threshold {
+            return floor;
+        }
+        let raised = u64::from(floor) * torrents as u64 / threshold as u64;
+        raised.min(u64::from(self.opt.interval.max(floor))) as u32
+    }
+
+    /// Returns the form of `ip` that may be written to logs.
+    fn logged_ip(&self, ip: IpAddr) -> IpAddr {
+        if self.opt.anonymize_ips {
+            anonymize_ip(ip)
+        } else {
+            ip
+        }
+    }
+
+    /// Lists the addresses to register a client under. The first is the address it announced
+    /// from (or asked for with `ip`, see `resolve_peer_ip`), followed by any other addresses it
+    /// gave with the BEP 7 `ipv4` and `ipv6` parameters. Like `ip`, those are only honored with
+    /// `--trust-ip-param`.
+    fn peer_ips(&self, qs: &TrackerRequest, remote_ip: IpAddr) -> Vec<IpAddr> {
+        let trusted = |ip: Option<IpAddr>| ip.filter(|_| self.opt.trust_ip_param);
+        let mut ips = vec![resolve_peer_ip(qs.ip, None, remote_ip, &self.opt)];
+        let extra = [qs.ipv4.map(IpAddr::V4), qs.ipv6.map(IpAddr::V6)];
+        for ip in extra.iter().filter_map(|ip| trusted(*ip)) {
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+        ips
+    }
+
+    /// Updates the tracker's state to reflect an announce from a peer reachable at `ips`. Returns a
+    /// warning for the client if its announce was ignored.
+    fn register_announce(&self, qs: &TrackerRequest, ips: &[IpAddr]) -> Option<String> {
+        let peers = ips.iter().map(|ip| Peer {
+            peer_id: qs.peer_id,
+            ip: *ip,
+            port: qs.port,
+        });
+        // a stopping client is leaving the swarm, so there's nothing to register
+        if let Some(ClientEvent::Stopped) = qs.event {
+            let now = Instant::now();
+            for peer in peers {
+                match self.opt.stop_grace_period {
+                    Some(grace) => {
+                        let until = now + Duration::from_secs(grace);
+                        self.store.leave(&qs.info_hash, &peer, until);
+                    }
+                    None => {
+                        self.store.remove(&qs.info_hash, &peer, now);
+                    }
+                }
+            }
+            return None;
+        }
+        let completed = matches!(qs.event, Some(ClientEvent::Completed));
+        if completed && self.opt.ignore_unknown_completions {
+            let mut peers = peers;
+            if !peers.any(|peer| self.store.peer(&qs.info_hash, &peer).is_some()) {
+                return Some(
+                    "'completed' event ignored, since the tracker has no record of this \
+                     download; it will be tracked from the next announce"
+                        .to_string(),
+                );
+            }
+        }
+
+        let mut joined = false;
+        for ip in ips {
+            let (peer, new) = self.maybe_register_new_peer(qs, *ip);
+            joined |= new;
+            if self.opt.check_connectable {
+                self.schedule_connect_check(&peer);
+            }
+        }
+        self.store.announced(&qs.info_hash, Instant::now());
+        match qs.event {
+            // a client whose response got lost retries its 'started' announce, which only
+            // refreshes the peer we already have
+            Some(ClientEvent::Started) if joined => self.store.join(&qs.info_hash, Instant::now()),
+            Some(ClientEvent::Completed) => {
+                self.complete_count.fetch_add(1, Ordering::Relaxed);
+                self.record_completion(&qs.info_hash);
+            },
+            Some(ClientEvent::Started)
+            | Some(ClientEvent::Stopped)
+            | Some(ClientEvent::Unknown)
+            | None => {}
+        }
+        None
+    }
+
+    fn record_completion(&self, info_hash: &InfoHash) {
+        self.store.complete(info_hash, SystemTime::now());
+    }
+
+    /// Reports the state of the requested swarms, or of every swarm if none were requested.
+    /// Responses are cached for `scrape_cache_ttl` seconds so that clients hammering us with the
+    /// same scrape don't cost a walk over the torrents each time.
+    fn scrape(&self, query: &str, now: Instant) -> Result<ScrapeResponse, TrackerError> {
+        let req = ScrapeRequest::from_query_string(query)?;
+        if req.info_hashes.is_empty() && self.opt.disable_full_scrape {
+            let msg = "full scrape disabled".to_string();
+            return Err(TrackerErrorKind::NotAllowed(msg).into());
+        }
+        // every info_hash costs a swarm lookup and a bit of the response
+        if req.info_hashes.len() > self.opt.max_scrape_hashes {
+            let msg = format!(
+                "Invalid request: can't scrape more than {} torrents at once.",
+                self.opt.max_scrape_hashes
+            );
+            return Err(TrackerErrorKind::ValidationError(msg).into());
+        }
+        Ok(self.scrape_info_hashes(req, now))
+    }
+
+    /// Scrapes the single torrent named in the path of a `/scrape/<info_hash>` request.
+    fn scrape_path(&self, hex: &str, now: Instant) -> Result<ScrapeResponse, TrackerError> {
+        let req = ScrapeRequest::from_hex(hex)?;
+        Ok(self.scrape_info_hashes(req, now))
+    }
+
+    fn scrape_info_hashes(&self, req: ScrapeRequest, now: Instant) -> ScrapeResponse {
+        let mut response = self.scrape_counts(req.info_hashes, now);
+        for (info_hash, file) in response.files.iter_mut() {
+            if req.extended {
+                file.name = self.torrent_names.get(info_hash).cloned();
+            } else {
+                file.last_completed = None;
+            }
+        }
+        response
+    }
+
+    /// Scrapes `info_hashes`, or every torrent if it's empty, going through the scrape cache.
+    fn scrape_counts(&self, info_hashes: Vec<InfoHash>, now: Instant) -> ScrapeResponse {
+        let ttl = Duration::from_secs(self.opt.scrape_cache_ttl);
+        let fresh = |created: Instant| now.duration_since(created) < ttl;
+
+        if let Some((created, response)) = lock(&self.scrape_cache).get(&info_hashes) {
+            if fresh(*created) {
+                return response.clone();
+            }
+        }
+
+        // computed without holding the cache's lock, so that hits don't wait behind a slow scrape
+        let files = if info_hashes.is_empty() {
+            self.store
+                .all_stats(now)
+                .into_iter()
+                .map(|(info_hash, info)| (info_hash, info.scrape()))
+                .collect()
+        } else {
+            info_hashes
+                .iter()
+                .filter_map(|info_hash| {
+                    Some((*info_hash, self.store.stats(info_hash, now)?.scrape()))
+                })
+                .collect()
+        };
+        let response = ScrapeResponse { files };
+
+        if ttl.as_secs() > 0 {
+            let mut cache = lock(&self.scrape_cache);
+            cache.retain(|_, (created, _)| fresh(*created));
+            if cache.len() >= MAX_SCRAPE_CACHE {
+                // make room by dropping the response that would have expired first
+                let oldest = cache
+                    .iter()
+                    .min_by_key(|(_, (created, _))| *created)
+                    .map(|(info_hashes, _)| info_hashes.clone());
+                if let Some(oldest) = oldest {
+                    cache.remove(&oldest);
+                }
+            }
+            cache.insert(info_hashes, (now, response.clone()));
+        }
+        response
+    }
+
+    /// Returns the number of seeders and leechers participating in a torrent.
+    #[cfg(test)]
+    fn swarm_counts(&self, info_hash: &InfoHash) -> (usize, usize) {
+        self.store
+            .stats(info_hash, Instant::now())
+            .map_or((0, 0), |info| (info.seeders, info.leechers))
+    }
+
+    /// Returns the total bytes a peer has reported uploading and downloading for a torrent, over
+    /// all of its announces.
+    fn peer_stats(&self, info_hash: &InfoHash, peer: &Peer) -> (u64, u64) {
+        self.store.peer(info_hash, peer).map_or((0, 0), |state| {
+            (state.uploaded.total, state.downloaded.total)
+        })
+    }
+
+    /// Returns how far along the leechers of a torrent that is `total_size` bytes long are on
+    /// average, from 0 (nothing downloaded) to 1 (done), based on how much they reported they had
+    /// `left`. Returns None if no leecher has told us. The tracker doesn't know how big torrents
+    /// are, so this is for callers that do.
+    pub fn swarm_progress(&self, info_hash: &InfoHash, total_size: u64) -> Option<f32> {
+        let swarm = self.store.swarm(info_hash)?;
+        let progress: Vec<f64> = swarm
+            .peers
+            .values()
+            .filter(|state| !state.seeder && !state.observer)
+            .filter_map(|state| state.left)
+            .map(|left| {
+                let done = 1.0 - left as f64 / total_size.max(1) as f64;
+                done.max(0.0)
+            })
+            .collect();
+
+        if progress.is_empty() {
+            return None;
+        }
+        Some((progress.iter().sum::<f64>() / progress.len() as f64) as f32)
+    }
+
+    fn stats(&self) -> TrackerStats {
+        let now = Instant::now();
+        let torrents = self.store.all_stats(now);
+        let mut histogram: Vec<_> = SWARM_SIZE_BUCKETS
+            .iter()
+            .enumerate()
+            .map(|(i, &min)| SwarmSizeBucket {
+                min,
+                max: SWARM_SIZE_BUCKETS.get(i + 1).map(|next| next - 1),
+                torrents: 0,
+            })
+            .collect();
+        let mut peers = 0;
+        let mut swarms = Vec::with_capacity(torrents.len());
+
+        for (info_hash, info) in &torrents {
+            let size = info.peers;
+            peers += size;
+            // buckets are sorted, so the last one starting at or below this size is the one
+            if let Some(bucket) = histogram.iter_mut().rev().find(|bucket| bucket.min <= size) {
+                bucket.torrents += 1;
+            }
+            swarms.push(SwarmStats {
+                info_hash: *info_hash,
+                peers: size,
+                created: now.duration_since(info.created).as_secs(),
+                last_activity: now.duration_since(info.last_activity).as_secs(),
+                churn: info.churn,
+                last_completed: info.last_completed.map(unix_time),
+            });
+        }
+
+        TrackerStats {
+            tracker_name: self.opt.tracker_name.clone(),
+            torrents: torrents.len(),
+            peers,
+            completed: self.complete_count.load(Ordering::Relaxed),
+            histogram,
+            swarms,
+        }
+    }
+
+    /// Serves the admin endpoints, which are only there when `admin_secret` is set and only answer
+    /// requests signed with it (see the `auth` module). They respond with the tracker's stats in
+    /// `format` after making their change.
+    ///
+    /// * `POST /admin/reap` forgets expired peers right away instead of waiting for the reaper.
+    /// * `DELETE /admin/torrents/<hex info_hash>` forgets a torrent and all of its peers.
+    /// * `GET /admin/torrents/<hex info_hash>?offset=&limit=` lists a page of a torrent's peers
+    ///   instead, see `peer_page`.
+    fn admin(&self, req: &Request<Body>, format: Format) -> Result<Vec<u8>, TrackerError> {
+        let secret = match &self.opt.admin_secret {
+            Some(secret) => secret,
+            None => return Err(TrackerErrorKind::NotFound.into()),
+        };
+        let path = req.uri().path();
+        let query = req.uri().query().unwrap_or("");
+        let header = req
+            .headers()
+            .get(AUTH_HEADER)
+            .and_then(|value| value.to_str().ok());
+        let window = Duration::from_secs(self.opt.admin_auth_window);
+        let method = req.method().as_str();
+        auth::verify(
+            secret.as_bytes(),
+            method,
+            path,
+            query,
+            header,
+            SystemTime::now(),
+            window,
+        )
+        .map_err(|err| TrackerErrorKind::Unauthorized(err.to_string()))?;
+
+        match (req.method(), path) {
+            (&Method::POST, "/admin/reap") => self.reap_expired(Instant::now()),
+            (method, path) if path.starts_with("/admin/torrents/") => {
+                let hex = &path["/admin/torrents/".len()..];
+                let info_hash = info_hash_from_hex(hex).ok_or_else(|| {
+                    let msg = format!("Invalid infohash: '{}' is not 40 hex digits.", hex);
+                    TrackerErrorKind::ValidationError(msg)
+                })?;
+                let unknown = || {
+                    let msg = format!("Unknown torrent: {}.", hex);
+                    TrackerError::from(TrackerErrorKind::ValidationError(msg))
+                };
+                match *method {
+                    Method::GET => {
+                        let query = req.uri().query().unwrap_or_default();
+                        let page = self.peer_page(&info_hash, query, Instant::now())?;
+                        return Ok(format.render(&page.ok_or_else(unknown)?));
+                    }
+                    Method::DELETE if !self.store.remove_swarm(&info_hash) => {
+                        return Err(unknown());
+                    }
+                    Method::DELETE => {}
+                    _ => return Err(TrackerErrorKind::MethodNotAllowed.into()),
+                }
+            }
+            (_, "/admin/reap") => return Err(TrackerErrorKind::MethodNotAllowed.into()),
+            _ => return Err(TrackerErrorKind::NotFound.into()),
+        }
+        Ok(format.render(&self.stats()))
+    }
+
+    /// Lists the page of a torrent's peers that `query` asks for with `offset` and `limit`, with
+    /// at most MAX_PEER_PAGE peers on it. Returns None if we don't track the torrent.
+    fn peer_page(
+        &self,
+        info_hash: &InfoHash,
+        query: &str,
+        now: Instant,
+    ) -> Result<Option<PeerPage>, TrackerError> {
+        let req: PageRequest = serde_urlencoded::from_str(query)
+            .map_err(|err| TrackerErrorKind::ParseError(err.to_string()))?;
+        let offset = req.offset.unwrap_or(0);
+        let limit = req.limit.unwrap_or(MAX_PEER_PAGE).min(MAX_PEER_PAGE);
+        // the store lets go of its lock before the page is turned into a response
+        let (total, peers) = match self.store.peer_page(info_hash, offset, limit) {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        let peers = peers
+            .into_iter()
+            .map(|(peer, state)| AdminPeer {
+                peer_id: peer.peer_id,
+                ip: peer.ip,
+                port: peer.port,
+                seeder: state.seeder,
+                left: state.left,
+                last_seen: now.saturating_duration_since(state.last_seen).as_secs(),
+            })
+            .collect();
+        Ok(Some(PeerPage {
+            total,
+            offset,
+            peers,
+        }))
+    }
+
+    /// Serves a request that came from `remote_addr`.
+    pub fn handle_session(&self, req: Request<Body>, remote_addr: SocketAddr) -> Response<Body> {
+        let uri = req.uri();
+        let uri_len = uri.path_and_query().map_or(0, |pq| pq.as_str().len());
+        // only the read-only endpoints a dashboard would poll are exposed to browsers
+        let cors_origin = match uri.path() {
+            "/stats" | "/scrape" => self.cors_origin(),
+            path if path.starts_with("/scrape/") => self.cors_origin(),
+            _ => None,
+        };
+        if let (Some(origin), &Method::OPTIONS) = (&cors_origin, req.method()) {
+            return cors_preflight(origin.clone());
+        }
+
+        // health checks are for orchestrators rather than bittorrent clients, so they're answered
+        // in plain text. So are the paths browsers and crawlers look for on any server, which
+        // would otherwise fill the logs with unrecognized paths.
+        match (req.method(), uri.path()) {
+            (&Method::GET | &Method::HEAD, "/healthz") => return plain_text(StatusCode::OK, "ok"),
+            (&Method::GET | &Method::HEAD, "/readyz") if self.ready.load(Ordering::Relaxed) => {
+                return plain_text(StatusCode::OK, "ready");
+            }
+            (&Method::GET | &Method::HEAD, "/readyz") => {
+                return plain_text(StatusCode::SERVICE_UNAVAILABLE, "loading snapshot");
+            }
+            (&Method::GET | &Method::HEAD, "/favicon.ico") => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::NO_CONTENT;
+                return response;
+            }
+            (&Method::GET | &Method::HEAD, "/robots.txt") => {
+                return plain_text(StatusCode::OK, "User-agent: *\nDisallow: /\n");
+            }
+            _ => {}
+        }
+
+        // clients only understand bencode, while the stats and admin endpoints are mostly read by
+        // dashboards and scripts
+        let format = match uri.path() {
+            path if path == "/stats" || path.starts_with("/admin/") => {
+                Format::negotiate(&req, Format::Json)
+            }
+            _ => Format::negotiate(&req, Format::Bencode),
+        };
+
+        // every X-Forwarded-For header counts, in order, as if they were one list
+        let forwarded_for = req
+            .headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .map(|value| value.to_str().ok())
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(","))
+            .filter(|forwarded_for| !forwarded_for.is_empty());
+        let client_ip =
+            resolve_peer_ip(None, forwarded_for.as_deref(), remote_addr.ip(), &self.opt);
+
+        // HEAD requests to announce/scrape are served like a GET, minus the body, and announces
+        // aren't registered. This lets monitoring tools check that we're alive.
+        let head = req.method() == Method::HEAD;
+        let ret = match (req.method(), uri.path(), uri.query()) {
+            // refuse to even look at the query string of huge requests, which are most likely
+            // someone trying to make us waste time and memory parsing them
+            _ if uri_len > self.opt.max_uri_length => Err(TrackerErrorKind::UriTooLong.into()),
+            (&Method::GET | &Method::HEAD, path, Some(query)) if path == self.opt.announce_path => {
+                self.soft_fail(self.handle_announce(query, client_ip, head))
+                    .map(|r| format.render(&r))
+            }
+            // some old torrents announce to the bare host
+            (&Method::GET | &Method::HEAD, "/", Some(query)) if self.opt.announce_on_root => self
+                .soft_fail(self.handle_announce(query, client_ip, head))
+                .map(|r| format.render(&r)),
+            (&Method::GET | &Method::HEAD, path, None) if path == self.opt.announce_path => {
+                let msg = "Invalid request: no query string.".to_string();
+                Err(TrackerErrorKind::ValidationError(msg).into())
+            }
+            (&Method::GET | &Method::HEAD, "/scrape", query) => self
+                .scrape(query.unwrap_or(""), Instant::now())
+                .map(|r| format.render(&r)),
+            (&Method::GET | &Method::HEAD, path, _) if path.starts_with("/scrape/") => self
+                .scrape_path(&path["/scrape/".len()..], Instant::now())
+                .map(|r| format.render(&r)),
+            (&Method::GET, "/stats", _) => Ok(format.render(&self.stats())),
+            (_, path, _) if path.starts_with("/admin/") => self.admin(&req, format),
+            (&Method::GET, _, _) => Err(TrackerErrorKind::NotFound.into()),
+            _ => Err(TrackerErrorKind::MethodNotAllowed.into()),
+        };
+
+        let (status, body) = match ret {
+            Ok(body) => (StatusCode::OK, body),
+            Err(err) => (err.status(), format.render(&err)),
+        };
+        let mut response = if head {
+            let mut response = Response::new(Body::empty());
+            response
+                .headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+            response
+        } else {
+            Response::new(Body::from(body))
+        };
+        *response.status_mut() = status;
+        if let Some(content_type) = format.content_type() {
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        }
+        if let Some(origin) = cors_origin {
+            response
+                .headers_mut()
+                .insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+        response
+    }
+
+    /// The `Access-Control-Allow-Origin` value to send, if CORS is enabled.
+    fn cors_origin(&self) -> Option<HeaderValue> {
+        // an origin that isn't a valid header value couldn't match any browser's origin anyway
+        let origin = self.opt.cors_origin.as_ref()?;
+        HeaderValue::from_str(origin).ok()
+    }
+}
+
+/// Answers a CORS preflight request, allowing the methods our read-only endpoints support.
+fn cors_preflight(origin: HeaderValue) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+    let headers = response.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("GET, HEAD, OPTIONS"),
+    );
+    headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("*"));
+    headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(86400));
+    response
+}
+
+/// A response with a short plain text body.
+fn plain_text(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    response
+}
+
+/// Makes up a peer_id for a client that didn't send one, from where it's listening and the `key`
+/// it sent, if any. The same client gets the same peer_id every time.
+fn anonymous_peer_id(ip: IpAddr, port: u16, key: Option<&str>) -> PeerId {
+    let identity = format!("{} {} {}", ip, port, key.unwrap_or_default());
+    PeerId(Sha1::digest(identity.as_bytes()).into())
+}
+
+// Seeded peers never told us their peer_id.
+const SEED_PEER_ID: PeerId = PeerId([0; 20]);
+
+/// Parses a `<info_hash> <ip> <port>` line of a seed file.
+fn parse_seed(line: &str) -> Option<(InfoHash, Peer)> {
+    let mut fields = line.split_whitespace();
+    let info_hash = info_hash_from_hex(fields.next()?)?;
+    let ip = fields.next()?.parse().ok()?;
+    let port = fields.next()?.parse().ok()?;
+    if fields.next().is_some() {
+        return None;
+    }
+
+    let peer = Peer {
+        peer_id: SEED_PEER_ID,
+        ip,
+        port,
+    };
+    Some((info_hash, peer))
+}
+
+/// Picks the address other peers should use to reach a client. Of the places it can come from,
+/// the first one that applies wins:
+///
+/// 1. the `ip` parameter of the announce (`param`), with `trust_ip_param`
+/// 2. the `X-Forwarded-For` header, if the request came from one of the `trusted_proxy`
+///    addresses. Every proxy appends the address it got the request from, so the header is read
+///    from the end, and the first address that isn't another trusted proxy is the client's. A
+///    header with anything but addresses in it is ignored.
+/// 3. the address the request came from (`connection`)
+///
+/// IPv4 clients talking to a dual-stack socket show up as IPv4-mapped IPv6 addresses
+/// (`::ffff:a.b.c.d`), which are turned back into plain IPv4 addresses so that a host isn't
+/// mistaken for an IPv6 peer.
+fn resolve_peer_ip(
+    param: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    connection: IpAddr,
+    opt: &Opt,
+) -> IpAddr {
+    let proxies = &opt.trusted_proxy;
+    let forwarded = || {
+        if !proxies.contains(&unmap_ipv4(connection)) {
+            return None;
+        }
+        let hops = forwarded_for?
+            .rsplit(',')
+            .map(|hop| hop.trim().parse().map(unmap_ipv4))
+            .collect::<Result<Vec<IpAddr>, _>>()
+            .ok()?;
+        // if every hop is one of our proxies, the first one is as close to the client as we get
+        hops.iter()
+            .find(|ip| !proxies.contains(ip))
+            .or_else(|| hops.last())
+            .copied()
+    };
+    let ip = param
+        .filter(|_| opt.trust_ip_param)
+        .or_else(forwarded)
+        .unwrap_or(connection);
+    unmap_ipv4(ip)
+}
+
+/// Turns an IPv4-mapped IPv6 address back into the IPv4 address it stands for.
+fn unmap_ipv4(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Zeroes the host part of an address: the last octet of an IPv4 address, or everything past the
+/// first 48 bits of an IPv6 address.
+fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(v6) => {
+            let mask = !0u128 << 80;
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+// How long to wait for a peer to accept our connection when checking that it's reachable.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Drops the addresses of dual-stack hosts outside of `preferred` from `peers`, when the host's
+/// address in `preferred` is in there too. A host's addresses are told apart from other peers by
+/// its peer_id and port, which it registers every one of them with.
+fn collapse_dual_stack(peers: &mut Vec<Peer>, preferred: AddressFamily) {
+    let hosts: HashSet<(PeerId, u16)> = peers
+        .iter()
+        .filter(|peer| AddressFamily::of(peer.ip) == preferred)
+        .map(|peer| (peer.peer_id, peer.port))
+        .collect();
+    peers.retain(|peer| {
+        AddressFamily::of(peer.ip) == preferred || !hosts.contains(&(peer.peer_id, peer.port))
+    });
+}
+
+/// Locks a mutex even if another thread panicked while holding it. Every critical section leaves
+/// the tracker's maps in a usable state at each step, so a panic in one request is better off
+/// losing that request than taking every later one down with it.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Seconds since the unix epoch at `time`, for the timestamps we show to dashboards.
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Bencodes one of our response types. These only contain types that serde_bencode knows how to
+/// serialize, so this can't fail.
+fn bencode<T: Serialize>(value: &T) -> Vec<u8> {
+    serde_bencode::to_bytes(value).expect("response types are always serializable")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::assert_sorted_keys;
+    use std::convert::TryInto;
+    use structopt::StructOpt;
+
+    // where test requests come from, unless they say otherwise
+    const REMOTE: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    const REMOTE_ADDR: SocketAddr = SocketAddr::new(REMOTE, 6881);
+
+    fn dict_peers(response: &TrackerResponse) -> &[Peer] {
+        match &response.peers {
+            PeerList::Dict(peers) => peers,
+            _ => panic!("expected a dictionary model response"),
+        }
+    }
+
+    /// Copies out the swarms of a tracker, keyed on their info_hash.
+    fn swarms(tracker: &Tracker) -> HashMap<InfoHash, Swarm> {
+        tracker.store.swarms().into_iter().collect()
+    }
+
+    fn test_opt(args: &[&str]) -> Opt {
+        let base = ["tracker", "--root", "."];
+        Opt::from_iter(base.iter().chain(args))
+    }
+
+    #[test]
+    fn peer_id_ser_test() {
+        let hash: [u8; 20] = [b'a'; 20];
+        let peer_id = PeerId(hash);
+
+        assert_eq!(
+            format!("20:{}", str::from_utf8(&hash).unwrap()),
+            serde_bencode::to_string(&peer_id).unwrap(),
+        );
+    }
+
+    #[test]
+    fn peer_id_de_test() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct TestData {
+            peer_id: PeerId,
+        }
+
+        let hash: [u8; 20] = [b'a'; 20];
+        let test_data = TestData { peer_id: PeerId(hash) };
+
+        // this is throwing an error because serde can't deserialize a byte array into `an array of
+        // length 20`
+        assert_eq!(
+            PeerId(hash),
+            serde_bencode::from_str::<PeerId>("20:aaaaaaaaaaaaaaaaaaaa").unwrap(),
+        );
+
+        assert_eq!(
+            test_data,
+            serde_urlencoded::from_str::<TestData>("peer_id=aaaaaaaaaaaaaaaaaaaa").unwrap(),
+        );
+    }
+
+    #[test]
+    fn peer_id_wrong_length() {
+        assert!(serde_bencode::from_str::<PeerId>("3:abc").is_err());
+    }
+
+    #[test]
+    fn binary_query_params() {
+        let query = "info_hash=%12%34%56%78%9a%bc%de%f1%23%45%67%89%ab%cd%ef%12%34%56%78%9a\
+            &peer_id=-XX0001-%00%00%FF%FE%80abcdef%ff&port=1000";
+        let (req, _) = parse_announce(query, false, false).unwrap();
+        assert_eq!(
+            req.info_hash.0,
+            [
+                0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf1, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+                0xef, 0x12, 0x34, 0x56, 0x78, 0x9a
+            ]
+        );
+        assert_eq!(req.peer_id, PeerId(*b"-XX0001-\0\0\xff\xfe\x80abcdef\xff"));
+        assert_eq!(req.port, 1000);
+
+        // `+` is a space, and everything else can be escaped too
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=%61bcdefghijklmnopq+st&port=1000";
+        assert_eq!(
+            parse_announce(query, false, false).unwrap().0.peer_id,
+            PeerId(*b"abcdefghijklmnopq st")
+        );
+
+        for (query, msg) in [
+            (
+                "info_hash=abcdefghijklmnopqrst&peer_id=%ff&port=1000",
+                "invalid length 1, expected a byte array of length 20",
+            ),
+            (
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst\
+                &peer_id=bbcdefghijklmnopqrst&port=1000",
+                "duplicate field `peer_id`",
+            ),
+        ] {
+            let err = parse_announce(query, false, false).unwrap_err();
+            assert_eq!(err.kind, TrackerErrorKind::ParseError(msg.to_string()));
+        }
+    }
+
+    #[test]
+    fn missing_required_params() {
+        for (query, name) in [
+            (
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst",
+                "port",
+            ),
+            ("peer_id=abcdefghijklmnopqrst&port=1000", "info_hash"),
+            ("info_hash=abcdefghijklmnopqrst&port=1000", "peer_id"),
+            ("left=0", "info_hash"),
+        ] {
+            let err = parse_announce(query, false, false).unwrap_err();
+            let msg = format!("missing required parameter: {}", name);
+            assert_eq!(err.kind, TrackerErrorKind::ParseError(msg), "{}", query);
+        }
+        // a parameter that's there but empty is invalid, not missing
+        let err = parse_announce(
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=",
+            false,
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.kind.to_string(),
+            "Invalid request: cannot parse integer from empty string"
+        );
+    }
+
+    #[test]
+    fn anonymous_announces() {
+        let query = "info_hash=abcdefghijklmnopqrst&port=1000&key=abc";
+        let err = Tracker::new(test_opt(&[]))
+            .announce(query, REMOTE)
+            .unwrap_err();
+        let msg = "missing required parameter: peer_id".to_string();
+        assert_eq!(err.kind, TrackerErrorKind::ParseError(msg));
+
+        let tracker = Tracker::new(test_opt(&["--anonymous-announces"]));
+        tracker.announce(query, REMOTE).unwrap();
+        // announcing again is the same peer, while another key or port is another one
+        tracker.announce(query, REMOTE).unwrap();
+        let response = tracker
+            .announce("info_hash=abcdefghijklmnopqrst&port=1000&key=abd", REMOTE)
+            .unwrap();
+        assert_eq!(response.incomplete, 2);
+        let response = tracker
+            .announce("info_hash=abcdefghijklmnopqrst&port=2000", REMOTE)
+            .unwrap();
+        assert_eq!(response.incomplete, 3);
+        let peers = dict_peers(&response);
+        let anonymous = anonymous_peer_id(REMOTE, 1000, Some("abc"));
+        assert!(peers.iter().any(|peer| peer.peer_id == anonymous));
+
+        // clients that do send a peer_id keep it
+        let response = tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=3000",
+                REMOTE,
+            )
+            .unwrap();
+        assert_eq!(response.incomplete, 4);
+        let swarm = &swarms(&tracker)[&InfoHash(*b"abcdefghijklmnopqrst")];
+        assert!(swarm
+            .peers
+            .keys()
+            .any(|peer| peer.peer_id == PeerId(*b"abcdefghijklmnopqrst")));
+    }
+
+    #[test]
+    fn basic_ok_test() {
+        let peer = Peer {
+            peer_id: PeerId("abcdefghijklmnopqrst".as_bytes().try_into().unwrap()),
+            ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            port: 6981,
+        };
+        let response = TrackerResponse {
+            interval: 10,
+            min_interval: None,
+            complete: 1,
+            incomplete: 0,
+            downloaded: None,
+            external_ip: None,
+            peers: PeerList::Dict(vec![peer]),
+            peers6: None,
+            warning_message: None,
+        };
+
+        assert_eq!(
+            serde_bencode::to_string(&response).unwrap(),
+            "d8:completei1e10:incompletei0e8:intervali10e5:peersld2:ip9:127.0.0.17:peer id20:abcdefghijklmnopqrst4:porti6981eeee"
+        );
+    }
+
+    #[test]
+    fn binary_peer_id_response() {
+        // a peer_id that isn't valid utf-8, with embedded nulls
+        let id = *b"-XX0001-\0\0\xff\xfe\x80abcdef\xff";
+        let peer = Peer {
+            peer_id: PeerId(id),
+            ip: "10.0.0.1".parse().unwrap(),
+            port: 6881,
+        };
+        let tracker = Tracker::new(test_opt(&[]));
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        tracker.store.register(info_hash, peer, &|_| PeerState {
+            seeder: true,
+            observer: false,
+            last_seen: Instant::now(),
+            region: None,
+            left: Some(0),
+            uploaded: Transfer::default(),
+            downloaded: Transfer::default(),
+        });
+
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&port=1000";
+        let response = tracker.announce(query, REMOTE).unwrap();
+        let encoded = bencode(&response);
+        let mut expected = b"7:peer id20:".to_vec();
+        expected.extend_from_slice(&id);
+        assert!(encoded.windows(expected.len()).any(|w| w == &expected[..]));
+
+        // and it decodes back to the same bytes
+        #[derive(Deserialize)]
+        struct Response {
+            peers: Vec<Peer>,
+        }
+        let decoded: Response = serde_bencode::from_bytes(&encoded).unwrap();
+        assert!(decoded.peers.contains(&peer));
+    }
+
+    // The examples below are written out by hand from BEP 3 (peer lists and failures), BEP 23
+    // (compact peer lists) and BEP 48 (scrapes), to catch key names, key order and types that
+    // drift from the spec.
+
+    #[test]
+    fn bep3_peer_list() {
+        let peers = vec![
+            Peer {
+                peer_id: PeerId(*b"-TR2940-abcdefghijkl"),
+                ip: "10.0.0.1".parse().unwrap(),
+                port: 51413,
+            },
+            Peer {
+                peer_id: PeerId(*b"-qB4250-mnopqrstuvwx"),
+                ip: "2001:db8::2".parse().unwrap(),
+                port: 6881,
+            },
+        ];
+        let response = TrackerResponse {
+            interval: 1800,
+            min_interval: None,
+            complete: 0,
+            incomplete: 2,
+            downloaded: None,
+            external_ip: None,
+            peers: PeerList::Dict(peers),
+            peers6: None,
+            warning_message: None,
+        };
+
+        assert_eq!(
+            bencode(&response),
+            &b"d8:completei0e10:incompletei2e8:intervali1800e5:peersl\
+               d2:ip8:10.0.0.17:peer id20:-TR2940-abcdefghijkl4:porti51413ee\
+               d2:ip11:2001:db8::27:peer id20:-qB4250-mnopqrstuvwx4:porti6881ee\
+               ee"[..]
+        );
+    }
+
+    #[test]
+    fn bep3_failure_reason() {
+        let err = TrackerError::from(TrackerErrorKind::NotFound);
+        assert_eq!(
+            bencode(&err),
+            &b"d14:failure reason18:Unrecognized path.e"[..]
+        );
+    }
+
+    #[test]
+    fn bep23_compact_peers() {
+        let peers = [
+            Peer {
+                peer_id: PeerId(*b"abcdefghijklmnopqrst"),
+                ip: "10.0.0.1".parse().unwrap(),
+                port: 6881,
+            },
+            Peer {
+                peer_id: PeerId(*b"bbcdefghijklmnopqrst"),
+                ip: "192.168.1.2".parse().unwrap(),
+                port: 51413,
+            },
+        ];
+        let (peers, _) = PeerList::compact(&peers);
+        let response = TrackerResponse {
+            interval: 1800,
+            min_interval: Some(900),
+            complete: 1,
+            incomplete: 1,
+            downloaded: None,
+            external_ip: None,
+            peers,
+            peers6: None,
+            warning_message: None,
+        };
+
+        assert_eq!(
+            bencode(&response),
+            &b"d8:completei1e10:incompletei1e8:intervali1800e12:min intervali900e\
+               5:peers12:\x0a\x00\x00\x01\x1a\xe1\xc0\xa8\x01\x02\xc8\xd5e"[..]
+        );
+    }
+
+    #[test]
+    fn bep48_scrape() {
+        let mut files = HashMap::new();
+        let file = ScrapeFile {
+            complete: 5,
+            downloaded: 50,
+            incomplete: 10,
+            name: None,
+            last_completed: None,
+        };
+        files.insert(InfoHash(*b"abcdefghijklmnopqrst"), file);
+
+        assert_eq!(
+            bencode(&ScrapeResponse { files }),
+            &b"d5:filesd20:abcdefghijklmnopqrst\
+               d8:completei5e10:downloadedi50e10:incompletei10eeee"[..]
+        );
+    }
+
+    #[test]
+    fn sorted_response_keys() {
+        let tracker = Tracker::new(test_opt(&[
+            "--trust-ip-param",
+            "--debug-peer-clients",
+            "--external-ip",
+            "--sparse-swarm-size",
+            "10",
+        ]));
+        let announce = |query: &str| {
+            let query = format!("info_hash=abcdefghijklmnopqrst&port=1000&{}", query);
+            bencode(&tracker.announce(&query, REMOTE).unwrap())
+        };
+        announce("peer_id=-TR2940-abcdefghijkl&ipv6=2001:db8::1&left=0");
+        tracker
+            .announce(
+                "info_hash=bbcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000",
+                REMOTE,
+            )
+            .unwrap();
+
+        for query in [
+            "peer_id=bbcdefghijklmnopqrst",
+            "peer_id=bbcdefghijklmnopqrst&compact=1",
+            "peer_id=bbcdefghijklmnopqrst&event=completed&numwant=50",
+        ] {
+            assert_sorted_keys(&announce(query));
+        }
+        assert_sorted_keys(&bencode(&tracker.scrape("", Instant::now()).unwrap()));
+        assert_sorted_keys(&bencode(&tracker.stats()));
+        let err = tracker.announce("info_hash=abc", REMOTE).unwrap_err();
+        assert_sorted_keys(&bencode(&err));
+    }
+
+    #[test]
+    fn external_ip() {
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000";
+        let tracker = Tracker::new(test_opt(&["--external-ip"]));
+
+        let response = tracker
+            .announce(query, "203.0.113.7".parse().unwrap())
+            .unwrap();
+        let encoded = bencode(&response);
+        let key = b"11:external ip4:\xcb\x00\x71\x07";
+        assert!(encoded.windows(key.len()).any(|window| window == key));
+
+        let ip: Ipv6Addr = "2001:db8::7".parse().unwrap();
+        let response = tracker.announce(query, ip.into()).unwrap();
+        let key = [&b"11:external ip16:"[..], &ip.octets()].concat();
+        let encoded = bencode(&response);
+        assert!(encoded.windows(key.len()).any(|window| window == key));
+
+        // off by default
+        let response = Tracker::new(test_opt(&[])).announce(query, REMOTE).unwrap();
+        assert_eq!(response.external_ip, None);
+    }
+
+    #[test]
+    fn basic_err_test() {
+        let err = TrackerError::from(TrackerErrorKind::ValidationError("oops".to_string()));
+
+        assert_eq!(
+            serde_bencode::to_string(&err).unwrap(),
+            "d14:failure reason4:oopse"
+        );
+    }
+
+    #[test]
+    fn basic_handle_session() {
         // TODO: flesh this out
-        let mut req = Request::builder()
+        let req = Request::builder()
             .uri("http://localhost:6981?info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&ip=192.168.0.1&port=1000&uploaded=42&downloaded=10&left=20");
-        let opt = Opt {
-            root: PathBuf::new(),
-            peers: 10,
+
+        let tracker = Tracker::new(test_opt(&["--peers", "10"]));
+        tracker.handle_session(req.body(Body::empty()).unwrap(), REMOTE_ADDR);
+    }
+
+    #[test]
+    fn stats_tracker_name() {
+        let tracker = Tracker::new(test_opt(&["--tracker-name", "node-1"]));
+
+        assert_eq!(
+            serde_bencode::to_string(&tracker.stats()).unwrap(),
+            "d9:completedi0e9:histogramld3:maxi1e3:mini1e8:torrentsi0eed3:maxi10e3:mini2e8:torrentsi0eed3:maxi100e3:mini11e8:torrentsi0eed3:maxi1000e3:mini101e8:torrentsi0eed3:mini1001e8:torrentsi0eee5:peersi0e6:swarmsle8:torrentsi0e12:tracker name6:node-1e"
+        );
+    }
+
+    #[test]
+    fn stats_histogram() {
+        let tracker = Tracker::new(test_opt(&[]));
+        for (info_hash, size) in [("a", 1), ("b", 1), ("c", 3), ("d", 12)].iter() {
+            for n in 0..*size {
+                let query = format!(
+                    "info_hash={:a<20}&peer_id={:020}&port={}",
+                    info_hash,
+                    n,
+                    1000 + n
+                );
+                tracker.announce(&query, REMOTE).unwrap();
+            }
+        }
+
+        let counts: Vec<_> = tracker
+            .stats()
+            .histogram
+            .iter()
+            .map(|bucket| bucket.torrents)
+            .collect();
+        assert_eq!(counts, vec![2, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn announce_downloaded() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000";
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert_eq!(response.downloaded, Some(0));
+
+        for peer_id in &["bbcdefghijklmnopqrst", "cbcdefghijklmnopqrst"] {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}&port=1000&left=0&event=completed",
+                peer_id
+            );
+            tracker.announce(&query, REMOTE).unwrap();
+        }
+
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert_eq!(response.downloaded, Some(2));
+        assert!(bencode(&response)
+            .windows(b"10:downloadedi2e".len())
+            .any(|w| w == b"10:downloadedi2e"));
+    }
+
+    #[test]
+    fn numwant_zero_observers() {
+        let observer = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000\
+            &numwant=0&left=100";
+        let leecher = "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&port=1000\
+            &left=100";
+
+        let tracker = Tracker::new(test_opt(&["--numwant-zero-observers"]));
+        let response = tracker.announce(observer, REMOTE).unwrap();
+        assert_eq!((response.complete, response.incomplete), (0, 0));
+        let response = tracker.announce(leecher, REMOTE).unwrap();
+        assert_eq!((response.complete, response.incomplete), (0, 1));
+        // the observer is still part of the swarm
+        assert_eq!(dict_peers(&response).len(), 2);
+
+        // without the option they're regular leechers
+        let tracker = Tracker::new(test_opt(&[]));
+        tracker.announce(observer, REMOTE).unwrap();
+        let response = tracker.announce(leecher, REMOTE).unwrap();
+        assert_eq!((response.complete, response.incomplete), (0, 2));
+    }
+
+    #[test]
+    fn underfilled_interval() {
+        let tracker = Tracker::new(test_opt(&["--interval", "1000", "--min-interval", "100"]));
+        let announce = |peer_id: &str, numwant: u32| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}&port=1000&numwant={}",
+                peer_id, numwant
+            );
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+
+        // the swarm is too small to fill anyone's list, so everyone is told to come back sooner
+        let response = announce("abcdefghijklmnopqrst", 4);
+        assert_eq!(dict_peers(&response).len(), 1);
+        assert_eq!(response.interval, 250);
+        assert_eq!(response.min_interval, Some(100));
+
+        let response = announce("bbcdefghijklmnopqrst", 4);
+        assert_eq!(response.interval, 500);
+
+        // but never sooner than the min interval
+        let response = announce("cbcdefghijklmnopqrst", 50);
+        assert_eq!(response.interval, 100);
+
+        // a full list gets the regular interval
+        let response = announce("dbcdefghijklmnopqrst", 2);
+        assert_eq!(dict_peers(&response).len(), 2);
+        assert_eq!(response.interval, 1000);
+    }
+
+    #[test]
+    fn interval_floor_torrents() {
+        let tracker = Tracker::new(test_opt(&[
+            "--interval",
+            "1000",
+            "--min-interval",
+            "100",
+            "--interval-floor-torrents",
+            "2",
+        ]));
+        let announce = |torrent: usize| {
+            let query = format!(
+                "info_hash=abcdefghijklmnop{:04}&peer_id=abcdefghijklmnopqrst&port=1000&numwant=50",
+                torrent
+            );
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+
+        // up to the threshold, a lone peer is told to come back after the usual minimum
+        for torrent in 0..2 {
+            let response = announce(torrent);
+            assert_eq!(response.interval, 100);
+            assert_eq!(response.min_interval, Some(100));
+        }
+        // past it, the minimum grows with the number of torrents
+        announce(2);
+        let response = announce(3);
+        assert_eq!(response.interval, 200);
+        assert_eq!(response.min_interval, Some(200));
+        // but never past the regular interval
+        for torrent in 4..30 {
+            announce(torrent);
+        }
+        let response = announce(30);
+        assert_eq!(response.interval, 1000);
+        assert_eq!(response.min_interval, Some(1000));
+    }
+
+    #[test]
+    fn max_swarm_share() {
+        let tracker = Tracker::new(test_opt(&["--max-swarm-share", "0.5"]));
+        let announce = |peer: usize| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnop{:04}&port=1000",
+                peer
+            );
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+
+        // half of a swarm of one rounds down to nobody
+        assert!(dict_peers(&announce(0)).is_empty());
+        assert_eq!(dict_peers(&announce(1)).len(), 1);
+        assert_eq!(dict_peers(&announce(2)).len(), 1);
+        for peer in 3..6 {
+            announce(peer);
+        }
+        let response = announce(6);
+        assert_eq!(dict_peers(&response).len(), 3);
+        // the list isn't short of what the client could have got, so the interval isn't shortened
+        assert_eq!(response.interval, 900);
+        assert_eq!(response.warning_message, None);
+    }
+
+    #[test]
+    fn seeder_interval() {
+        let tracker = Tracker::new(test_opt(&[
+            "--interval",
+            "1000",
+            "--seeder-interval",
+            "3000",
+            "--interval-override",
+            "6262636465666768696a6b6c6d6e6f7071727374=500",
+        ]));
+        let announce = |info_hash: &str, peer_id: &str, left: u32| {
+            let query = format!(
+                "info_hash={}&peer_id={}&port=1000&left={}&numwant=1",
+                info_hash, peer_id, left
+            );
+            tracker.announce(&query, REMOTE).unwrap().interval
+        };
+
+        // a seeder alone in its swarm still gets the long interval, while a leecher's is shortened
+        assert_eq!(
+            announce("abcdefghijklmnopqrst", "abcdefghijklmnopqrst", 0),
+            3000
+        );
+        assert_eq!(
+            announce("abcdefghijklmnopqrst", "bbcdefghijklmnopqrst", 100),
+            1000
+        );
+        assert_eq!(
+            announce("abcdefghijklmnopqrst", "abcdefghijklmnopqrst", 0),
+            3000
+        );
+        // a torrent's own interval is for everyone
+        assert_eq!(
+            announce("bbcdefghijklmnopqrst", "abcdefghijklmnopqrst", 0),
+            500
+        );
+    }
+
+    #[test]
+    fn malformed_announces() {
+        let cases = [
+            "",
+            "&&&=",
+            "info_hash",
+            "info_hash=abcdefghijklmnopqrst",
+            "info_hash=abc&peer_id=abcdefghijklmnopqrst&port=1000",
+            "info_hash=%ff%fe%fd&peer_id=abcdefghijklmnopqrst&port=1000",
+            "info_hash=abcdefghijklmnopqrst&peer_id=%zz&port=1000",
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=70000",
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=-1",
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000&numwant=-5",
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000&ip=999.1.1.1",
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000&left=1e9",
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000&compact=256",
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000&compact=yes",
+            "info_hash=\u{1f600}\u{1f600}\u{1f600}\u{1f600}&peer_id=abcdefghijklmnopqrst\
+             &port=1000",
+        ];
+
+        let tracker = Tracker::new(test_opt(&[]));
+        for query in cases.iter() {
+            assert!(parse_announce(query, false, false).is_err(), "{}", query);
+            assert!(tracker.announce(query, REMOTE).is_err(), "{}", query);
+        }
+        assert!(swarms(&tracker).is_empty());
+    }
+
+    #[test]
+    fn extreme_numwant() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000\
+            &numwant=4294967295";
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert_eq!(dict_peers(&response).len(), 1);
+
+        // asking for far more peers than there are gets exactly the whole swarm, however the list
+        // is filled
+        for args in [&[][..], &["--seeder-share", "0.5"], &["--stable-peers"]] {
+            let tracker = Tracker::new(test_opt(args));
+            for port in 1000..1005 {
+                let query = format!(
+                    "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port={}&left={}",
+                    port,
+                    port % 2
+                );
+                tracker.announce(&query, REMOTE).unwrap();
+            }
+            let response = tracker.announce(query, REMOTE).unwrap();
+            let peers = dict_peers(&response);
+            assert_eq!(peers.len(), 5, "{:?}", args);
+            let ports: HashSet<u16> = peers.iter().map(|peer| peer.port).collect();
+            assert_eq!(ports, (1000..1005).collect(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn interval_override() {
+        let tracker = Tracker::new(test_opt(&[
+            "--interval",
+            "900",
+            "--interval-override",
+            "6162636465666768696a6b6c6d6e6f7071727374=120",
+        ]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000\
+            &numwant=1";
+        assert_eq!(tracker.announce(query, REMOTE).unwrap().interval, 120);
+
+        let query = "info_hash=bbcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000\
+            &numwant=1";
+        assert_eq!(tracker.announce(query, REMOTE).unwrap().interval, 900);
+
+        assert!("6162=120".parse::<IntervalOverride>().is_err());
+        assert!("6162636465666768696a6b6c6d6e6f7071727374"
+            .parse::<IntervalOverride>()
+            .is_err());
+    }
+
+    #[test]
+    fn compact_param() {
+        let base = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000";
+        let cases = [
+            ("", false),
+            ("&compact=0", false),
+            ("&compact=1", true),
+            ("&compact=false", false),
+            ("&compact=true", true),
+        ];
+        for (param, compact) in cases.iter() {
+            let query = format!("{}{}", base, param);
+            assert_eq!(
+                parse_announce(&query, false, false)
+                    .unwrap()
+                    .0
+                    .wants_compact(),
+                *compact,
+                "{}",
+                query
+            );
+        }
+
+        let err = parse_announce(&format!("{}&compact=2", base), false, false).unwrap_err();
+        assert_eq!(
+            err.kind.to_string(),
+            "Invalid request: invalid value: string \"2\", expected 1, 0, true or false"
+        );
+    }
+
+    #[test]
+    fn debug_peer_clients() {
+        let tracker = Tracker::new(test_opt(&["--debug-peer-clients"]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=-qB4500-abcdefghijkl&port=1000";
+        let response = tracker.announce(query, REMOTE).unwrap();
+        let encoded = bencode(&response.peers);
+        let encoded = String::from_utf8_lossy(&encoded);
+        assert!(
+            encoded.contains("6:client19:qBittorrent 4.5.0.0"),
+            "{}",
+            encoded
+        );
+
+        // the key is left out unless asked for
+        let tracker = Tracker::new(test_opt(&[]));
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert_eq!(dict_peers(&response).len(), 1);
+    }
+
+    #[test]
+    fn decode_compact() {
+        let peers = decode_compact_peers(&[127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x03, 0xe8]);
+        assert_eq!(
+            peers.unwrap(),
+            vec![
+                (Ipv4Addr::LOCALHOST, 6881),
+                (Ipv4Addr::new(10, 0, 0, 2), 1000)
+            ]
+        );
+        assert_eq!(decode_compact_peers(&[]).unwrap(), vec![]);
+
+        let mut peer6 = Ipv6Addr::LOCALHOST.octets().to_vec();
+        peer6.extend_from_slice(&[0x1a, 0xe1]);
+        assert_eq!(
+            decode_compact_peers6(&peer6).unwrap(),
+            vec![(Ipv6Addr::LOCALHOST, 6881)]
+        );
+
+        // whatever the tracker packs, a client can unpack
+        let peers = [
+            Peer {
+                peer_id: PeerId(*b"abcdefghijklmnopqrst"),
+                ip: "192.168.0.1".parse().unwrap(),
+                port: 1000,
+            },
+            Peer {
+                peer_id: PeerId(*b"bbcdefghijklmnopqrst"),
+                ip: "2001:db8::1".parse().unwrap(),
+                port: 2000,
+            },
+        ];
+        let (packed, packed6) = PeerList::compact(&peers);
+        match packed {
+            PeerList::Compact(packed) => assert_eq!(
+                decode_compact_peers(&packed.0).unwrap(),
+                vec![(Ipv4Addr::new(192, 168, 0, 1), 1000)]
+            ),
+            _ => panic!("expected a compact response"),
+        }
+        assert_eq!(
+            decode_compact_peers6(&packed6.0).unwrap(),
+            vec![("2001:db8::1".parse().unwrap(), 2000)]
+        );
+    }
+
+    #[test]
+    fn decode_compact_bad_length() {
+        let err = decode_compact_peers(&[127, 0, 0, 1, 0x1a]).unwrap_err();
+        assert_eq!(
+            err,
+            CompactLengthError {
+                len: 5,
+                peer_len: 6
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "compact peer list of 5 bytes isn't made of 6 byte peers"
+        );
+        assert!(decode_compact_peers6(&[0; 12]).is_err());
+    }
+
+    #[test]
+    fn swarm_progress() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        assert_eq!(tracker.swarm_progress(&info_hash, 1000), None);
+
+        // a seeder, and a leecher that didn't say how much it has left, don't count
+        for (n, left) in ["&left=0", "", "&left=1000", "&left=500", "&left=100"]
+            .iter()
+            .enumerate()
+        {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={:020}&port=1000{}",
+                n, left
+            );
+            tracker.announce(&query, REMOTE).unwrap();
+        }
+
+        // (0 + 0.5 + 0.9) / 3
+        let progress = tracker.swarm_progress(&info_hash, 1000).unwrap();
+        assert!((progress - 0.4666667).abs() < 1e-6, "{}", progress);
+
+        // claiming to have more left than the torrent's size is treated as no progress
+        let progress = tracker.swarm_progress(&info_hash, 800).unwrap();
+        assert!(
+            (progress - (0.0 + 0.375 + 0.875) / 3.0).abs() < 1e-6,
+            "{}",
+            progress
+        );
+    }
+
+    #[test]
+    fn peer_transfer_accounting() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let announce = |uploaded: u32, downloaded: u32| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000\
+                 &uploaded={}&downloaded={}",
+                uploaded, downloaded
+            );
+            tracker.announce(&query, REMOTE).unwrap();
+        };
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let peer = Peer {
+            peer_id: PeerId(*b"abcdefghijklmnopqrst"),
+            ip: REMOTE,
+            port: 1000,
+        };
+
+        announce(100, 1000);
+        announce(300, 5000);
+        assert_eq!(tracker.peer_stats(&info_hash, &peer), (300, 5000));
+
+        // the client restarted, so its counts start over
+        announce(50, 200);
+        assert_eq!(tracker.peer_stats(&info_hash, &peer), (350, 5200));
+        announce(80, 200);
+        assert_eq!(tracker.peer_stats(&info_hash, &peer), (380, 5200));
+
+        let stranger = Peer { port: 2000, ..peer };
+        assert_eq!(tracker.peer_stats(&info_hash, &stranger), (0, 0));
+    }
+
+    #[test]
+    fn busy_announce_rate() {
+        let tracker = Tracker::new(test_opt(&[
+            "--interval",
+            "100",
+            "--busy-announce-rate",
+            "0.25",
+        ]));
+        let announce = || {
+            let query =
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000&numwant=0";
+            tracker.announce(query, REMOTE).unwrap().interval
+        };
+
+        // one announce a minute is nowhere near busy
+        assert_eq!(announce(), 100);
+        // a burst of 30 in a minute is twice the busy rate
+        for _ in 0..28 {
+            announce();
+        }
+        assert_eq!(announce(), 200);
+        // and however busy a torrent gets, the interval is only stretched so far
+        for _ in 0..500 {
+            announce();
+        }
+        assert_eq!(announce(), 400);
+
+        // the rate decays once the burst is over
+        let start = Instant::now();
+        let mut rate = AnnounceRate::default();
+        for _ in 0..30 {
+            rate.record(start);
+        }
+        assert!((rate.at(start) - 0.5).abs() < 1e-9);
+        let later = rate.at(start + ANNOUNCE_RATE_WINDOW);
+        assert!((later - 0.5 / std::f64::consts::E).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sparse_swarm_warning() {
+        let tracker = Tracker::new(test_opt(&["--sparse-swarm-size", "5"]));
+        let announce = |peer_id: &str, left: u32, numwant: u32| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}&port=1000&left={}&numwant={}",
+                peer_id, left, numwant
+            );
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+
+        announce("abcdefghijklmnopqrst", 0, 0);
+        let response = announce("bbcdefghijklmnopqrst", 100, 50);
+        assert_eq!((response.complete, response.incomplete), (1, 1));
+        assert_eq!(dict_peers(&response).len(), 2);
+        assert_eq!(
+            response.warning_message.as_deref(),
+            Some("this torrent only has 2 peers, try again later for more")
+        );
+
+        // no warning for a client that got everything it asked for
+        let response = announce("bbcdefghijklmnopqrst", 100, 2);
+        assert_eq!(response.warning_message, None);
+
+        // nor once the swarm is big enough
+        for peer_id in ["cbcdefghijklmnopqrst", "dbcdefghijklmnopqrst"] {
+            announce(peer_id, 100, 0);
+        }
+        assert!(announce("ebcdefghijklmnopqrst", 100, 50)
+            .warning_message
+            .is_none());
+    }
+
+    #[test]
+    fn contradictory_completed() {
+        let query = |event: &str, left: u32| {
+            format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000\
+                &event={}&left={}",
+                event, left
+            )
+        };
+        let msg = "contradictory announce: 'completed' event with 100 bytes left";
+
+        let (_, warning) = parse_announce(&query("completed", 100), false, false).unwrap();
+        assert_eq!(warning.as_deref(), Some(msg));
+        let err = parse_announce(&query("completed", 100), true, false).unwrap_err();
+        assert_eq!(err.kind, TrackerErrorKind::ValidationError(msg.to_string()));
+        for (event, left) in [("completed", 0), ("started", 100), ("stopped", 100)] {
+            let (_, warning) = parse_announce(&query(event, left), true, false).unwrap();
+            assert_eq!(warning, None, "{}", query(event, left));
+        }
+
+        // the client is warned, and the download still counts
+        let tracker = Tracker::new(test_opt(&[]));
+        let response = tracker.announce(&query("completed", 100), REMOTE).unwrap();
+        assert_eq!(response.warning_message.as_deref(), Some(msg));
+        assert_eq!(response.downloaded, Some(1));
+
+        let tracker = Tracker::new(test_opt(&["--reject-contradictory"]));
+        let err = tracker
+            .announce(&query("completed", 100), REMOTE)
+            .unwrap_err();
+        assert_eq!(err.kind, TrackerErrorKind::ValidationError(msg.to_string()));
+        assert_eq!(
+            tracker.swarm_counts(&InfoHash(*b"abcdefghijklmnopqrst")),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn max_response_peers() {
+        let tracker = Tracker::new(test_opt(&["--max-response-peers", "3"]));
+        let announce = |port: u16, numwant: u32| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port={}&numwant={}",
+                port, numwant
+            );
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+
+        // no warning while the whole swarm fits under the cap
+        for port in 1000..1003 {
+            announce(port, 0);
+        }
+        let response = announce(1000, 50);
+        assert_eq!(dict_peers(&response).len(), 3);
+        assert_eq!(response.warning_message, None);
+
+        for port in 1003..1010 {
+            announce(port, 0);
+        }
+        let response = announce(1000, 5);
+        assert_eq!(dict_peers(&response).len(), 3);
+        assert_eq!(
+            response.warning_message.as_deref(),
+            Some("responses are limited to 3 peers, fewer than the 5 asked for")
+        );
+        // the shortened list doesn't make the client come back sooner
+        assert_eq!(response.interval, tracker.opt.interval);
+
+        let response = announce(1000, 3);
+        assert_eq!(dict_peers(&response).len(), 3);
+        assert_eq!(response.warning_message, None);
+    }
+
+    #[test]
+    fn soft_fail() {
+        let busy = || Err(TrackerErrorKind::Capacity.into());
+        let rate_limited = || Err(TrackerErrorKind::RateLimited.into());
+
+        // failures are sent as they are by default
+        let tracker = Tracker::new(test_opt(&[]));
+        let err = tracker.soft_fail(busy()).unwrap_err();
+        assert_eq!(err.kind, TrackerErrorKind::Capacity);
+
+        let tracker = Tracker::new(test_opt(&["--soft-fail-interval", "3600"]));
+        for result in [busy(), rate_limited()] {
+            let response = tracker.soft_fail(result).unwrap();
+            assert_eq!(response.interval, 3600);
+            assert_eq!(response.min_interval, Some(3600));
+            assert!(dict_peers(&response).is_empty());
+        }
+        assert_eq!(
+            bencode(&tracker.soft_fail(busy()).unwrap()),
+            b"d8:completei0e10:incompletei0e8:intervali3600e12:min intervali3600e5:peersle\
+              15:warning message40:Tracker is at capacity, try again later.e"
+                .to_vec()
+        );
+
+        // a bad request won't get any better by waiting
+        let query = "info_hash=abcdefghijklmnopqrst&port=1000";
+        let result = tracker.announce(query, REMOTE);
+        assert!(tracker.soft_fail(result).is_err());
+    }
+
+    #[test]
+    fn max_ip_announce_rate() {
+        let tracker = Tracker::new(test_opt(&[
+            "--max-ip-announce-rate",
+            "0.04",
+            "--soft-fail-interval",
+            "3600",
+        ]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000";
+        // every announce adds 1/60 to the rate
+        for _ in 0..3 {
+            tracker.announce(query, REMOTE).unwrap();
+        }
+        let err = tracker.announce(query, REMOTE).unwrap_err();
+        assert_eq!(err.kind, TrackerErrorKind::RateLimited);
+        let response = tracker.soft_fail(tracker.announce(query, REMOTE)).unwrap();
+        assert_eq!(response.interval, 3600);
+
+        // other addresses have their own rate
+        tracker
+            .announce(query, "10.0.0.1".parse().unwrap())
+            .unwrap();
+        // and addresses are forgotten once they've been quiet for long enough
+        tracker.reap_expired(Instant::now() + Duration::from_secs(600));
+        assert!(lock(&tracker.ip_announce_rates).is_empty());
+        tracker.announce(query, REMOTE).unwrap();
+    }
+
+    #[test]
+    fn max_peers() {
+        let tracker = Tracker::new(test_opt(&["--max-peers", "2"]));
+        let announce = |peer_id: &str, event: &str| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}&port=1000{}",
+                peer_id, event
+            );
+            tracker.announce(&query, REMOTE)
+        };
+
+        announce("abcdefghijklmnopqrst", "").unwrap();
+        announce("bbcdefghijklmnopqrst", "").unwrap();
+        let err = announce("cbcdefghijklmnopqrst", "").unwrap_err();
+        assert_eq!(err.kind, TrackerErrorKind::Capacity);
+        // peers we know keep announcing
+        announce("abcdefghijklmnopqrst", "").unwrap();
+        // and once one leaves there's room again
+        announce("abcdefghijklmnopqrst", "&event=stopped").unwrap();
+        announce("cbcdefghijklmnopqrst", "").unwrap();
+        assert_eq!(
+            tracker.swarm_counts(&InfoHash(*b"abcdefghijklmnopqrst")),
+            (0, 2)
+        );
+    }
+
+    #[test]
+    fn min_ratio() {
+        let tracker = Tracker::new(test_opt(&["--min-ratio", "0.5"]));
+        let announce = |peer_id: &str, uploaded: u32, downloaded: u32, left: u32| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}&port=1000\
+                 &uploaded={}&downloaded={}&left={}",
+                peer_id, uploaded, downloaded, left
+            );
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+
+        // a seeder that never uploaded anything is still fine
+        let response = announce("aaaaaaaaaaaaaaaaaaaa", 0, 1000, 0);
+        assert!(response.warning_message.is_none());
+
+        let response = announce("bbbbbbbbbbbbbbbbbbbb", 600, 1000, 100);
+        assert!(response.warning_message.is_none());
+        assert_eq!(dict_peers(&response).len(), 2);
+
+        let response = announce("cccccccccccccccccccc", 100, 1000, 100);
+        assert!(dict_peers(&response).is_empty());
+        let warning = response.warning_message.unwrap();
+        assert!(warning.contains("0.10"), "{}", warning);
+        assert!(warning.contains("0.50"), "{}", warning);
+        // the leecher is still part of the swarm, it just doesn't get peers
+        assert_eq!(response.incomplete, 2);
+
+        // catching up on uploads gets it peers again
+        let response = announce("cccccccccccccccccccc", 500, 1000, 100);
+        assert!(response.warning_message.is_none());
+        assert_eq!(dict_peers(&response).len(), 3);
+    }
+
+    #[test]
+    fn announce_without_left() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let query =
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&ip=192.168.0.1&port=1000";
+
+        let req = TrackerRequest::from_query_string(query, false).unwrap();
+        assert_eq!(req.left, None);
+        assert!(!req.is_seeder());
+
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert_eq!((response.complete, response.incomplete), (0, 1));
+    }
+
+    #[test]
+    fn transfers_past_4gib() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let announce = |downloaded: u64| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000\
+                 &uploaded=0&downloaded={}&left=0",
+                downloaded
+            );
+            let req = TrackerRequest::from_query_string(&query, false).unwrap();
+            assert_eq!(req.downloaded, Some(downloaded));
+            assert!(req.is_seeder());
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+
+        let response = announce(u64::from(u32::MAX) + 1);
+        assert_eq!((response.complete, response.incomplete), (1, 0));
+        announce(10 << 32);
+        let peer = Peer {
+            peer_id: PeerId(*b"abcdefghijklmnopqrst"),
+            ip: REMOTE,
+            port: 1000,
+        };
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        assert_eq!(tracker.peer_stats(&info_hash, &peer), (0, 10 << 32));
+    }
+
+    #[test]
+    fn reap_drops_empty_swarms() {
+        let tracker = Tracker::new(test_opt(&["--peer-timeout", "60"]));
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst\
+                &ip=192.168.0.1&port=1000",
+                REMOTE,
+            )
+            .unwrap();
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst\
+                &ip=192.168.0.2&port=1000",
+                REMOTE,
+            )
+            .unwrap();
+
+        // nobody has timed out yet
+        tracker.reap_expired(Instant::now());
+        assert_eq!(tracker.stats().peers, 2);
+
+        tracker.reap_expired(Instant::now() + Duration::from_secs(61));
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        assert!(!swarms(&tracker).contains_key(&info_hash));
+        assert_eq!(tracker.stats().torrents, 0);
+    }
+
+    #[test]
+    fn scrape_cache_ttl() {
+        let tracker = Tracker::new(test_opt(&["--scrape-cache-ttl", "10"]));
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let scrape = "info_hash=abcdefghijklmnopqrst";
+        let now = Instant::now();
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst\
+                &ip=192.168.0.1&port=1000",
+                REMOTE,
+            )
+            .unwrap();
+
+        let first = tracker.scrape(scrape, now).unwrap();
+        assert_eq!(first.files[&info_hash].incomplete, 1);
+
+        // a new peer shows up, but the cached response hasn't expired yet
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst\
+                &ip=192.168.0.2&port=1000&left=0",
+                REMOTE,
+            )
+            .unwrap();
+        let second = tracker
+            .scrape(scrape, now + Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(second.files[&info_hash], first.files[&info_hash]);
+
+        let third = tracker
+            .scrape(scrape, now + Duration::from_secs(11))
+            .unwrap();
+        assert_eq!(third.files[&info_hash].complete, 1);
+        assert_eq!(third.files[&info_hash].incomplete, 1);
+    }
+
+    #[test]
+    fn scrape_cache_size() {
+        let tracker = Tracker::new(test_opt(&["--scrape-cache-ttl", "10"]));
+        let now = Instant::now();
+        for n in 0..MAX_SCRAPE_CACHE as u32 + 10 {
+            let mut info_hash = [0; 20];
+            info_hash[..4].copy_from_slice(&n.to_be_bytes());
+            tracker.scrape_counts(
+                vec![InfoHash(info_hash)],
+                now + Duration::from_millis(n.into()),
+            );
+        }
+        let cache = lock(&tracker.scrape_cache);
+        assert_eq!(cache.len(), MAX_SCRAPE_CACHE);
+        // the first ones asked for made room for the last ones
+        assert!(!cache.contains_key(&vec![InfoHash([0; 20])]));
+    }
+
+    #[test]
+    fn scrape_hash_limit() {
+        let tracker = Tracker::new(test_opt(&["--max-scrape-hashes", "3"]));
+        let query = |count: u8| {
+            (0..count)
+                .map(|i| format!("info_hash={}bcdefghijklmnopqrs{}", i, i))
+                .collect::<Vec<_>>()
+                .join("&")
+        };
+
+        assert!(tracker.scrape(&query(3), Instant::now()).is_ok());
+        // repeated info_hashes only count once
+        let repeated = format!("{}&{}", query(3), query(3));
+        assert!(tracker.scrape(&repeated, Instant::now()).is_ok());
+
+        let err = tracker.scrape(&query(4), Instant::now()).unwrap_err();
+        assert_eq!(
+            err.kind.to_string(),
+            "Invalid request: can't scrape more than 3 torrents at once."
+        );
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn disable_full_scrape() {
+        let tracker = Tracker::new(test_opt(&["--disable-full-scrape"]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000";
+        tracker.announce(query, REMOTE).unwrap();
+
+        for full in ["", "key=value"] {
+            let err = tracker.scrape(full, Instant::now()).unwrap_err();
+            assert_eq!(err.kind.to_string(), "Not allowed: full scrape disabled");
+            assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        }
+
+        let response = tracker
+            .scrape("info_hash=abcdefghijklmnopqrst", Instant::now())
+            .unwrap();
+        assert_eq!(response.files.len(), 1);
+        assert_eq!(
+            tracker
+                .scrape_path(&hex::encode("abcdefghijklmnopqrst"), Instant::now())
+                .unwrap()
+                .files
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn scrape_path() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000";
+        tracker.announce(query, REMOTE).unwrap();
+
+        let now = Instant::now();
+        let hex = "6162636465666768696a6b6c6d6e6f7071727374";
+        let by_path = tracker.scrape_path(hex, now).unwrap();
+        let by_query = tracker
+            .scrape("info_hash=abcdefghijklmnopqrst", now)
+            .unwrap();
+        assert_eq!(by_path.files.len(), 1);
+        assert_eq!(by_path.files, by_query.files);
+
+        // upper case hex is fine too
+        let req = Request::get(format!("/scrape/{}", hex.to_uppercase()))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            tracker.handle_session(req, REMOTE_ADDR).status(),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn scrape_names() {
+        let mut tracker = Tracker::new(test_opt(&[]));
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        assert_eq!(tracker.load_torrents(&fixtures).unwrap(), 2);
+        let single = fixtures.join("single-file.torrent");
+        let metainfo = OwnedMetaInfo::from_bytes(&fs::read(single).unwrap()).unwrap();
+        let served = InfoHash(metainfo.info_hash());
+        let unknown = InfoHash(*b"abcdefghijklmnopqrst");
+        for info_hash in [served, unknown] {
+            let peer = Peer {
+                peer_id: PeerId(*b"abcdefghijklmnopqrst"),
+                ip: "10.0.0.1".parse().unwrap(),
+                port: 1000,
+            };
+            tracker.store.register(info_hash, peer, &|_| PeerState {
+                seeder: true,
+                observer: false,
+                last_seen: Instant::now(),
+                region: None,
+                left: Some(0),
+                uploaded: Transfer::default(),
+                downloaded: Transfer::default(),
+            });
+        }
+
+        let scrape = tracker.scrape("extended=1", Instant::now()).unwrap();
+        assert_eq!(
+            scrape.files[&served].name.as_deref(),
+            Some("release-notes.txt")
+        );
+        assert_eq!(scrape.files[&served].complete, 1);
+        assert_eq!(scrape.files[&unknown].name, None);
+        assert!(!bencode(&scrape.files[&unknown])
+            .windows(4)
+            .any(|w| w == b"name"));
+
+        // names are only sent to clients that ask for them
+        let scrape = tracker.scrape("", Instant::now()).unwrap();
+        assert_eq!(scrape.files[&served].name, None);
+    }
+
+    #[test]
+    fn last_completed() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let announce = |peer_id: &str, event: &str| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}&port=1000&left=0&event={}",
+                peer_id, event
+            );
+            tracker.announce(&query, REMOTE).unwrap();
+        };
+        let last_completed = || {
+            let stats = tracker.stats();
+            let scrape = tracker.scrape("extended=1", Instant::now()).unwrap();
+            assert_eq!(
+                stats.swarms[0].last_completed,
+                scrape.files[&info_hash].last_completed
+            );
+            stats.swarms[0].last_completed
+        };
+
+        announce("abcdefghijklmnopqrst", "started");
+        assert_eq!(last_completed(), None);
+
+        let before = unix_time(SystemTime::now());
+        announce("bbcdefghijklmnopqrst", "completed");
+        let after = unix_time(SystemTime::now());
+        let completed = last_completed().unwrap();
+        assert!(before <= completed && completed <= after);
+
+        // a later completion moves it forward
+        tracker
+            .store
+            .complete(&info_hash, UNIX_EPOCH + Duration::from_secs(after + 60));
+        assert_eq!(last_completed(), Some(after + 60));
+
+        // like names, it's only in extended scrapes
+        let scrape = tracker.scrape("", Instant::now()).unwrap();
+        assert_eq!(scrape.files[&info_hash].last_completed, None);
+    }
+
+    #[test]
+    fn scrape_path_invalid_hex() {
+        let tracker = Tracker::new(test_opt(&[]));
+        for hex in &[
+            "zz62636465666768696a6b6c6d6e6f7071727374",
+            "6162",
+            "é162636465666768696a6b6c6d6e6f707172737",
+        ] {
+            let err = tracker.scrape_path(hex, Instant::now()).unwrap_err();
+            let msg = format!("Invalid infohash: '{}' is not 40 hex digits.", hex);
+            assert_eq!(err.kind, TrackerErrorKind::ValidationError(msg));
+        }
+
+        let req = Request::get("/scrape/not-hex").body(Body::empty()).unwrap();
+        assert_eq!(
+            tracker.handle_session(req, REMOTE_ADDR).status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn admin_endpoints() {
+        let tracker = Tracker::new(test_opt(&["--admin-secret", "hunter2"]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000";
+        tracker.announce(query, REMOTE).unwrap();
+        let hex = "6162636465666768696a6b6c6d6e6f7071727374";
+        let path = format!("/admin/torrents/{}", hex);
+        let now = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let delete = |auth: Option<String>| {
+            let mut req = Request::delete(path.as_str());
+            if let Some(auth) = auth {
+                req = req.header("X-Auth", auth);
+            }
+            tracker.handle_session(req.body(Body::empty()).unwrap(), REMOTE_ADDR)
+        };
+
+        let response = delete(None);
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let sign = |secret, method, timestamp| auth::sign(secret, method, &path, "", timestamp);
+        let response = delete(Some(sign(b"hunter3", "DELETE", now)));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = delete(Some(sign(b"hunter2", "DELETE", now - 3600)));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        // a signature for listing the torrent's peers can't be used to delete it
+        let response = delete(Some(sign(b"hunter2", "GET", now)));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(swarms(&tracker).len(), 1);
+
+        let response = delete(Some(sign(b"hunter2", "DELETE", now)));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(swarms(&tracker).is_empty());
+        let response = delete(Some(sign(b"hunter2", "DELETE", now)));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let sign = |method| auth::sign(b"hunter2", method, "/admin/reap", "", now);
+        let req = Request::post("/admin/reap")
+            .header("X-Auth", sign("POST"))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            tracker.handle_session(req, REMOTE_ADDR).status(),
+            StatusCode::OK
+        );
+        let req = Request::get("/admin/reap")
+            .header("X-Auth", sign("GET"))
+            .body(Body::empty())
+            .unwrap();
+        let response = tracker.handle_session(req, REMOTE_ADDR);
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        // without a secret there are no admin endpoints at all
+        let tracker = Tracker::new(test_opt(&[]));
+        let req = Request::post("/admin/reap")
+            .header("X-Auth", sign("POST"))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            tracker.handle_session(req, REMOTE_ADDR).status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_peer_pages() {
+        let tracker = Tracker::new(test_opt(&["--admin-secret", "hunter2"]));
+        for port in 1000..1025 {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port={}",
+                port
+            );
+            tracker.announce(&query, REMOTE).unwrap();
+        }
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let now = Instant::now();
+
+        let mut seen = HashSet::new();
+        for offset in (0..30).step_by(10) {
+            let query = format!("offset={}&limit=10", offset);
+            let page = tracker.peer_page(&info_hash, &query, now).unwrap().unwrap();
+            assert_eq!((page.total, page.offset), (25, offset));
+            assert_eq!(page.peers.len(), 10.min(25 - offset));
+            for peer in page.peers {
+                assert!(seen.insert(peer.port), "port {} on two pages", peer.port);
+            }
+        }
+        assert_eq!(seen, (1000..1025).collect());
+
+        // pages are never bigger than MAX_PEER_PAGE, and run out past the end of the swarm
+        let page = tracker.peer_page(&info_hash, "limit=100000", now).unwrap();
+        assert_eq!(page.unwrap().peers.len(), 25);
+        let page = tracker.peer_page(&info_hash, "offset=50", now).unwrap();
+        assert!(page.unwrap().peers.is_empty());
+        assert!(tracker.peer_page(&info_hash, "limit=-1", now).is_err());
+        let unknown = InfoHash(*b"bbcdefghijklmnopqrst");
+        assert!(tracker.peer_page(&unknown, "", now).unwrap().is_none());
+
+        let path = "/admin/torrents/6162636465666768696a6b6c6d6e6f7071727374";
+        let now = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let req = Request::get(format!("{}?offset=20", path))
+            .header(
+                "X-Auth",
+                auth::sign(b"hunter2", "GET", path, "offset=20", now),
+            )
+            .body(Body::empty())
+            .unwrap();
+        let response = tracker.handle_session(req, REMOTE_ADDR);
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["total"], 25);
+        assert_eq!(json["peers"].as_array().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn cors_stats() {
+        let tracker = Tracker::new(test_opt(&["--cors-origin", "https://dash.example"]));
+        let req = Request::get("/stats").body(Body::empty()).unwrap();
+        let response = tracker.handle_session(req, REMOTE_ADDR);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://dash.example"
+        );
+
+        // announces are only made by bittorrent clients, not browsers
+        let req = Request::get(
+            "/announce?info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000",
+        )
+        .body(Body::empty())
+        .unwrap();
+        let response = tracker.handle_session(req, REMOTE_ADDR);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // nothing is sent when CORS isn't configured
+        let tracker = Tracker::new(test_opt(&[]));
+        let req = Request::get("/stats").body(Body::empty()).unwrap();
+        let response = tracker.handle_session(req, REMOTE_ADDR);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[test]
+    fn cors_preflight_request() {
+        let tracker = Tracker::new(test_opt(&["--cors-origin", "*"]));
+        let req = Request::options("/scrape")
+            .header("Origin", "https://dash.example")
+            .header("Access-Control-Request-Method", "GET")
+            .body(Body::empty())
+            .unwrap();
+        let response = tracker.handle_session(req, REMOTE_ADDR);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, HEAD, OPTIONS"
+        );
+
+        let req = Request::options("/announce").body(Body::empty()).unwrap();
+        assert_eq!(
+            tracker.handle_session(req, REMOTE_ADDR).status(),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+
+    #[test]
+    fn swarm_churn() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let announce = |peer_id: char, event: &str| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}bcdefghijklmnopqrst&port=1000&event={}",
+                peer_id, event
+            );
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+
+        for peer_id in ['a', 'b', 'c', 'd'].iter() {
+            announce(*peer_id, "started");
+        }
+        announce('a', "stopped");
+        announce('b', "stopped");
+        // regular announces aren't joins
+        announce('c', "");
+
+        let stats = tracker.stats();
+        assert_eq!(stats.swarms.len(), 1);
+        assert_eq!((stats.swarms[0].peers, stats.swarms[0].churn), (2, 6));
+
+        // churn only counts recent joins and leaves
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let later = Instant::now() + CHURN_WINDOW;
+        assert_eq!(tracker.store.stats(&info_hash, later).unwrap().churn, 0);
+    }
+
+    #[test]
+    fn repeated_started() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let query =
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000&event=started";
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let peer = Peer {
+            peer_id: PeerId(*b"abcdefghijklmnopqrst"),
+            ip: REMOTE,
+            port: 1000,
+        };
+
+        tracker.announce(query, REMOTE).unwrap();
+        let first_seen = tracker.store.peer(&info_hash, &peer).unwrap().last_seen;
+        // the client retries, as if it never got the first response
+        tracker.announce(query, REMOTE).unwrap();
+
+        let info = tracker.store.stats(&info_hash, Instant::now()).unwrap();
+        assert_eq!((info.peers, info.churn), (1, 1));
+        assert!(tracker.store.peer(&info_hash, &peer).unwrap().last_seen >= first_seen);
+
+        // a peer that left and came back does count as joining again. Someone else stays in the
+        // swarm meanwhile, so it isn't forgotten along with its churn.
+        let other = query.replace("peer_id=a", "peer_id=b");
+        tracker.announce(&other, REMOTE).unwrap();
+        tracker
+            .announce(&query.replace("started", "stopped"), REMOTE)
+            .unwrap();
+        tracker.announce(query, REMOTE).unwrap();
+        let info = tracker.store.stats(&info_hash, Instant::now()).unwrap();
+        assert_eq!((info.peers, info.churn), (2, 4));
+    }
+
+    #[test]
+    fn completed_by_unknown_peer() {
+        let query =
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000&event=completed";
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let peer = Peer {
+            peer_id: PeerId(*b"abcdefghijklmnopqrst"),
+            ip: REMOTE,
+            port: 1000,
+        };
+
+        // by default, as if the tracker had seen the whole download
+        let tracker = Tracker::new(test_opt(&[]));
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert_eq!(response.warning_message, None);
+        assert_eq!(response.downloaded, Some(1));
+        assert!(tracker.store.peer(&info_hash, &peer).is_some());
+
+        let tracker = Tracker::new(test_opt(&["--ignore-unknown-completions"]));
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert!(response
+            .warning_message
+            .unwrap()
+            .starts_with("'completed' event ignored"));
+        assert_eq!(response.downloaded, None);
+        assert!(tracker.store.peer(&info_hash, &peer).is_none());
+
+        // once the peer is known, its completion counts
+        let regular = query.replace("&event=completed", "");
+        tracker.announce(&regular, REMOTE).unwrap();
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert_eq!(response.warning_message, None);
+        assert_eq!(response.downloaded, Some(1));
+    }
+
+    #[test]
+    fn stop_grace_period() {
+        let tracker = Tracker::new(test_opt(&["--stop-grace-period", "30"]));
+        let query = |event: &str, uploaded: u32| {
+            format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000\
+                 &uploaded={}&event={}",
+                uploaded, event
+            )
+        };
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let peer = Peer {
+            peer_id: PeerId(*b"abcdefghijklmnopqrst"),
+            ip: REMOTE,
+            port: 1000,
+        };
+
+        tracker.announce(&query("started", 0), REMOTE).unwrap();
+        tracker.announce(&query("", 100), REMOTE).unwrap();
+        tracker.announce(&query("stopped", 100), REMOTE).unwrap();
+        // gone as far as anyone asking can tell
+        let info = tracker.store.stats(&info_hash, Instant::now()).unwrap();
+        assert_eq!((info.peers, info.churn), (0, 1));
+        assert!(tracker.store.peer(&info_hash, &peer).is_none());
+
+        // a quick restart picks up the old record, without leaving and joining again
+        tracker.announce(&query("started", 50), REMOTE).unwrap();
+        let info = tracker.store.stats(&info_hash, Instant::now()).unwrap();
+        assert_eq!((info.peers, info.churn), (1, 1));
+        // so it keeps adding to what it uploaded before
+        assert_eq!(tracker.peer_stats(&info_hash, &peer), (150, 0));
+
+        // a peer that doesn't come back is dropped once the grace period is over
+        tracker.announce(&query("stopped", 60), REMOTE).unwrap();
+        tracker.reap_expired(Instant::now() + Duration::from_secs(29));
+        assert!(tracker.store.stats(&info_hash, Instant::now()).is_some());
+        tracker.reap_expired(Instant::now() + Duration::from_secs(31));
+        assert!(tracker.store.stats(&info_hash, Instant::now()).is_none());
+    }
+
+    #[test]
+    fn shuffled_peers() {
+        let announce = |tracker: &Tracker, kind: char, port: u16| {
+            let left = if kind == 's' { 0 } else { 100 };
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}bcdefghijklmnopqrst&port={}&left={}",
+                kind, port, left
+            );
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+        // the whole swarm fits in every response, so only the order can change
+        for opt in [test_opt(&[]), test_opt(&["--seeder-share", "0.5"])] {
+            let tracker = Tracker::new(opt);
+            for port in 1000..1005 {
+                announce(&tracker, 's', port);
+                announce(&tracker, 'l', port + 1000);
+            }
+            let firsts: HashSet<u16> = (0..100)
+                .map(|_| dict_peers(&announce(&tracker, 'l', 2000))[0].port)
+                .collect();
+            assert!(firsts.len() > 3, "{:?}", firsts);
+        }
+    }
+
+    #[test]
+    fn stable_peers() {
+        let tracker = Tracker::new(test_opt(&["--stable-peers"]));
+        let announce = |port: u16| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port={}&numwant=3",
+                port
+            );
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+        for port in 1000..1010 {
+            announce(port);
+        }
+
+        let first = announce(2000);
+        let ports: Vec<u16> = dict_peers(&first).iter().map(|peer| peer.port).collect();
+        assert_eq!(ports, [2000, 1009, 1008]);
+        for _ in 0..5 {
+            assert_eq!(bencode(&announce(2000)), bencode(&first));
+        }
+    }
+
+    #[test]
+    fn seeder_share() {
+        let tracker = Tracker::new(test_opt(&["--seeder-share", "0.3"]));
+        let announce = |kind: char, port: u16, numwant: u32| {
+            let left = if kind == 's' { 0 } else { 100 };
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}bcdefghijklmnopqrst&port={}&left={}\
+                &numwant={}",
+                kind, port, left, numwant
+            );
+            let response = tracker.announce(&query, REMOTE).unwrap();
+            let seeders = dict_peers(&response)
+                .iter()
+                .filter(|peer| peer.peer_id.0[0] == b's')
+                .count();
+            (seeders, dict_peers(&response).len() - seeders)
+        };
+        for port in 1000..1010 {
+            announce('s', port, 0);
+            announce('l', port, 0);
+        }
+
+        for _ in 0..5 {
+            assert_eq!(announce('l', 1000, 10), (3, 7));
+        }
+        assert_eq!(announce('l', 1000, 5), (2, 3));
+        // with only 10 leechers, seeders make up the rest
+        assert_eq!(announce('l', 1000, 15), (5, 10));
+        assert_eq!(announce('l', 1000, 50), (10, 10));
+    }
+
+    #[test]
+    fn pluggable_store() {
+        // passes everything on to an in-memory store, noting which methods were called
+        #[derive(Default)]
+        struct RecordingStore {
+            inner: InMemoryStore,
+            calls: Mutex<Vec<&'static str>>,
+        }
+
+        impl RecordingStore {
+            fn record(&self, call: &'static str) {
+                self.calls.lock().unwrap().push(call);
+            }
+
+            fn take_calls(&self) -> Vec<&'static str> {
+                std::mem::take(&mut *self.calls.lock().unwrap())
+            }
+        }
+
+        impl PeerStore for RecordingStore {
+            fn register(
+                &self,
+                info_hash: InfoHash,
+                peer: Peer,
+                update: &dyn Fn(Option<&PeerState>) -> PeerState,
+            ) {
+                self.record("register");
+                self.inner.register(info_hash, peer, update)
+            }
+
+            fn remove(&self, info_hash: &InfoHash, peer: &Peer, now: Instant) -> bool {
+                self.record("remove");
+                self.inner.remove(info_hash, peer, now)
+            }
+
+            fn leave(&self, info_hash: &InfoHash, peer: &Peer, until: Instant) -> bool {
+                self.record("leave");
+                self.inner.leave(info_hash, peer, until)
+            }
+
+            fn remove_swarm(&self, info_hash: &InfoHash) -> bool {
+                self.record("remove_swarm");
+                self.inner.remove_swarm(info_hash)
+            }
+
+            fn join(&self, info_hash: &InfoHash, now: Instant) {
+                self.record("join");
+                self.inner.join(info_hash, now)
+            }
+
+            fn complete(&self, info_hash: &InfoHash, now: SystemTime) {
+                self.record("complete");
+                self.inner.complete(info_hash, now)
+            }
+
+            fn announced(&self, info_hash: &InfoHash, now: Instant) {
+                self.record("announced");
+                self.inner.announced(info_hash, now)
+            }
+
+            fn sample(
+                &self,
+                info_hash: &InfoHash,
+                count: usize,
+                selection: Selection,
+                region: Option<&Region>,
+                eligible: &dyn Fn(&Peer, &PeerState) -> bool,
+            ) -> Vec<Peer> {
+                self.record("sample");
+                self.inner
+                    .sample(info_hash, count, selection, region, eligible)
+            }
+
+            fn peer(&self, info_hash: &InfoHash, peer: &Peer) -> Option<PeerState> {
+                self.record("peer");
+                self.inner.peer(info_hash, peer)
+            }
+
+            fn stats(&self, info_hash: &InfoHash, now: Instant) -> Option<SwarmInfo> {
+                self.record("stats");
+                self.inner.stats(info_hash, now)
+            }
+
+            fn all_stats(&self, now: Instant) -> Vec<(InfoHash, SwarmInfo)> {
+                self.record("all_stats");
+                self.inner.all_stats(now)
+            }
+
+            fn torrent_count(&self) -> usize {
+                self.record("torrent_count");
+                self.inner.torrent_count()
+            }
+
+            fn peer_count(&self) -> usize {
+                self.record("peer_count");
+                self.inner.peer_count()
+            }
+
+            fn reap(&self, now: Instant, timeout: Duration) {
+                self.record("reap");
+                self.inner.reap(now, timeout)
+            }
+
+            fn swarm(&self, info_hash: &InfoHash) -> Option<Swarm> {
+                self.record("swarm");
+                self.inner.swarm(info_hash)
+            }
+
+            fn swarms(&self) -> Vec<(InfoHash, Swarm)> {
+                self.record("swarms");
+                self.inner.swarms()
+            }
+
+            fn peer_page(
+                &self,
+                info_hash: &InfoHash,
+                offset: usize,
+                limit: usize,
+            ) -> Option<(usize, Vec<(Peer, PeerState)>)> {
+                self.record("peer_page");
+                self.inner.peer_page(info_hash, offset, limit)
+            }
+
+            fn restore(&self, swarms: Vec<(InfoHash, Swarm)>) {
+                self.record("restore");
+                self.inner.restore(swarms)
+            }
+
+            fn merge(&self, swarms: Vec<(InfoHash, Swarm)>) {
+                self.record("merge");
+                self.inner.merge(swarms)
+            }
+        }
+
+        let store = Arc::new(RecordingStore::default());
+        let tracker = Tracker::new(test_opt(&["--min-ratio", "1"])).with_store(store.clone());
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let announce = |event: &str| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000\
+                 &left=100{}",
+                event
+            );
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+
+        let response = announce("");
+        assert_eq!(response.incomplete, 1);
+        assert_eq!(
+            store.take_calls(),
+            ["register", "announced", "stats", "peer", "sample"]
+        );
+
+        announce("&event=completed");
+        assert_eq!(
+            store.take_calls(),
+            [
+                "register",
+                "announced",
+                "complete",
+                "stats",
+                "peer",
+                "sample"
+            ]
+        );
+        assert_eq!(
+            store
+                .inner
+                .stats(&info_hash, Instant::now())
+                .unwrap()
+                .completed,
+            1
+        );
+
+        tracker.scrape("", Instant::now()).unwrap();
+        assert_eq!(store.take_calls(), ["all_stats"]);
+        tracker.reap_expired(Instant::now());
+        assert_eq!(store.take_calls(), ["reap"]);
+
+        let response = announce("&event=stopped");
+        assert_eq!(response.incomplete, 0);
+        assert_eq!(store.take_calls(), ["remove", "stats", "peer", "sample"]);
+        assert!(store.inner.stats(&info_hash, Instant::now()).is_none());
+    }
+
+    #[test]
+    fn shared_tracker() {
+        let opt = Arc::new(test_opt(&["--tracker-name", "shared"]));
+        let tracker = Arc::new(Tracker::new(opt.clone()));
+
+        // each connection gets its own handle on the same tracker
+        let uri = "/announce?info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000";
+        let first = tracker.clone();
+        let announce = std::thread::spawn(move || {
+            let req = Request::get(uri).body(Body::empty()).unwrap();
+            first.handle_session(req, REMOTE_ADDR).status()
+        });
+        assert_eq!(announce.join().unwrap(), StatusCode::OK);
+
+        let second = tracker.clone();
+        let req = Request::get("/stats").body(Body::empty()).unwrap();
+        assert_eq!(
+            second.handle_session(req, REMOTE_ADDR).status(),
+            StatusCode::OK
+        );
+
+        let stats = tracker.stats();
+        assert_eq!(stats.peers, 1);
+        assert_eq!(stats.tracker_name.as_deref(), Some("shared"));
+        assert!(Arc::ptr_eq(&tracker.opt, &opt));
+    }
+
+    #[test]
+    fn head_announce() {
+        use hyper::body::HttpBody;
+
+        let tracker = Tracker::new(test_opt(&[]));
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst\
+                    &ip=192.168.0.2&port=1000",
+                REMOTE,
+            )
+            .unwrap();
+        let uri = "/announce?info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst\
+            &ip=192.168.0.1&port=1000";
+        let req = Request::head(uri).body(Body::empty()).unwrap();
+
+        let response = tracker.handle_session(req, REMOTE_ADDR);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().size_hint().exact(), Some(0));
+
+        let content_length = &response.headers()[CONTENT_LENGTH];
+        assert!(content_length.to_str().unwrap().parse::<usize>().unwrap() > 0);
+        // the HEAD announce wasn't registered
+        assert_eq!(tracker.stats().peers, 1);
+    }
+
+    #[test]
+    fn error_kinds() {
+        let cases = [
+            (
+                TrackerErrorKind::ParseError("missing field `port`".to_string()),
+                "Invalid request: missing field `port`",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                TrackerErrorKind::ValidationError("bad peer".to_string()),
+                "bad peer",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                TrackerErrorKind::NotFound,
+                "Unrecognized path.",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                TrackerErrorKind::UriTooLong,
+                "Invalid request: request URI is too long.",
+                StatusCode::URI_TOO_LONG,
+            ),
+            (
+                TrackerErrorKind::MethodNotAllowed,
+                "Invalid request type: client request was not an HTTP GET.",
+                StatusCode::METHOD_NOT_ALLOWED,
+            ),
+            (
+                TrackerErrorKind::NotAllowed("unregistered torrent".to_string()),
+                "Not allowed: unregistered torrent",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                TrackerErrorKind::Unauthorized("missing X-Auth header".to_string()),
+                "Unauthorized: missing X-Auth header",
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                TrackerErrorKind::RateLimited,
+                "Rate limited: slow down and try again later.",
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                TrackerErrorKind::Capacity,
+                "Tracker is at capacity, try again later.",
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+        ];
+
+        for (kind, failure, status) in cases.iter().cloned() {
+            let err = TrackerError::from(kind);
+            assert_eq!(err.kind.to_string(), failure);
+            assert_eq!(err.status(), status);
+            assert_eq!(
+                serde_bencode::to_string(&err).unwrap(),
+                format!("d14:failure reason{}:{}e", failure.len(), failure)
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_path_not_found() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let req = Request::get("/nope").body(Body::empty()).unwrap();
+        assert_eq!(
+            tracker.handle_session(req, REMOTE_ADDR).status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn announce_on_root() {
+        let uri = "/?info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000";
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let tracker = Tracker::new(test_opt(&["--announce-on-root"]));
+        let response = tracker.handle_session(request(uri), REMOTE_ADDR);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(tracker.stats().peers, 1);
+        let response = tracker.handle_session(request("/"), REMOTE_ADDR);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let tracker = Tracker::new(test_opt(&[]));
+        let response = tracker.handle_session(request(uri), REMOTE_ADDR);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(tracker.stats().peers, 0);
+    }
+
+    #[tokio::test]
+    async fn announce_path() {
+        let query = "?info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000";
+        let request = |path: &str| {
+            let uri = format!("{}{}", path, query);
+            Request::get(uri).body(Body::empty()).unwrap()
+        };
+        let tracker = Tracker::new(test_opt(&["--announce-path", "/s3cr3t/announce"]));
+
+        let response = tracker.handle_session(request("/s3cr3t/announce"), REMOTE_ADDR);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(tracker.stats().peers, 1);
+        for path in ["/announce", "/s3cr3t", "/other/announce"] {
+            let response = tracker.handle_session(request(path), REMOTE_ADDR);
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+            // the error doesn't give the real path away
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(body, &b"d14:failure reason18:Unrecognized path.e"[..]);
+        }
+        let response = tracker.handle_session(
+            Request::get("/s3cr3t/announce")
+                .body(Body::empty())
+                .unwrap(),
+            REMOTE_ADDR,
+        );
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(tracker.stats().peers, 1);
+    }
+
+    #[test]
+    fn announce_multiple_info_hash() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let query = "info_hash=abcdefghijklmnopqrst&info_hash=bbcdefghijklmnopqrst\
+            &peer_id=abcdefghijklmnopqrst&ip=192.168.0.1&port=1000";
+
+        let err = tracker.announce(query, REMOTE).unwrap_err();
+        assert_eq!(
+            err.kind,
+            TrackerErrorKind::ValidationError(
+                "multiple info_hash not allowed on announce".to_string()
+            )
+        );
+        assert!(swarms(&tracker).is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreachable_peers_not_advertised() {
+        use crate::connectivity::ConnectFuture;
+
+        // only accepts connections on port 1000
+        struct StubConnector;
+        impl Connector for StubConnector {
+            fn connect(&self, addr: SocketAddr) -> ConnectFuture {
+                Box::pin(std::future::ready(addr.port() == 1000))
+            }
+        }
+
+        let tracker = Tracker::new(test_opt(&["--check-connectable"]))
+            .with_connector(Arc::new(StubConnector));
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst\
+                &ip=192.168.0.1&port=1000",
+                REMOTE,
+            )
+            .unwrap();
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst\
+                &ip=192.168.0.2&port=2000",
+                REMOTE,
+            )
+            .unwrap();
+
+        // let the spawned checks run
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+
+        let response = tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=cbcdefghijklmnopqrst\
+                &ip=192.168.0.3&port=3000",
+                REMOTE,
+            )
+            .unwrap();
+        let ports: Vec<u16> = dict_peers(&response).iter().map(|peer| peer.port).collect();
+        assert_eq!(ports, vec![1000]);
+    }
+
+    #[tokio::test]
+    async fn unreachable_peers_checked_again() {
+        use crate::connectivity::ConnectFuture;
+
+        // refuses the first connection, as if the client wasn't listening yet
+        struct StubConnector(AtomicBool);
+        impl Connector for StubConnector {
+            fn connect(&self, _addr: SocketAddr) -> ConnectFuture {
+                Box::pin(std::future::ready(self.0.swap(true, Ordering::SeqCst)))
+            }
+        }
+
+        let opt = test_opt(&[
+            "--check-connectable",
+            "--exclude-requester",
+            "--connect-retry-interval",
+            "0",
+        ]);
+        let tracker =
+            Tracker::new(opt).with_connector(Arc::new(StubConnector(AtomicBool::new(false))));
+        let announce = |peer_id: &str, port: u16| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}&ip=192.168.0.{}&port={}",
+                peer_id,
+                port / 1000,
+                port
+            );
+            tracker.announce(&query, REMOTE).unwrap()
+        };
+        let ports = |response: &TrackerResponse| -> Vec<u16> {
+            dict_peers(response).iter().map(|peer| peer.port).collect()
+        };
+
+        announce("abcdefghijklmnopqrst", 1000);
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert!(ports(&announce("cbcdefghijklmnopqrst", 3000)).is_empty());
+
+        // the failure has expired, so the next announce checks the peer again
+        announce("abcdefghijklmnopqrst", 1000);
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(ports(&announce("cbcdefghijklmnopqrst", 3000)), vec![1000]);
+    }
+
+    #[test]
+    fn connect_checks_without_runtime() {
+        use crate::connectivity::ConnectFuture;
+
+        struct StubConnector;
+        impl Connector for StubConnector {
+            fn connect(&self, _addr: SocketAddr) -> ConnectFuture {
+                Box::pin(std::future::ready(true))
+            }
+        }
+
+        let announce = |tracker: &Tracker, peer_id: &str, ip: &str| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}&ip={}&port=1000",
+                peer_id, ip
+            );
+            let response = tracker.announce(&query, REMOTE).unwrap();
+            dict_peers(&response).len()
+        };
+
+        // no checks are run, and so no peers are handed out, but announcing doesn't panic
+        let tracker = Tracker::new(test_opt(&["--check-connectable", "--exclude-requester"]))
+            .with_connector(Arc::new(StubConnector));
+        announce(&tracker, "abcdefghijklmnopqrst", "192.168.0.1");
+        assert_eq!(announce(&tracker, "bbcdefghijklmnopqrst", "192.168.0.2"), 0);
+
+        // with a runtime given later, checks run there
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let tracker = Tracker::new(test_opt(&["--check-connectable", "--exclude-requester"]))
+            .with_connector(Arc::new(StubConnector))
+            .with_runtime(runtime.handle().clone());
+        announce(&tracker, "abcdefghijklmnopqrst", "192.168.0.1");
+        runtime.block_on(async { tokio::time::delay_for(Duration::from_millis(10)).await });
+        assert_eq!(announce(&tracker, "bbcdefghijklmnopqrst", "192.168.0.2"), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_announces() {
+        let tracker = Arc::new(Tracker::new(test_opt(&[])));
+        let tasks: Vec<_> = (0..50u16)
+            .map(|n| {
+                let tracker = tracker.clone();
+                tokio::spawn(async move {
+                    for round in 0..5 {
+                        let query = format!(
+                            "info_hash=abcdefghijklmnopqrst&peer_id={:020}&port={}",
+                            n,
+                            1000 + n
+                        );
+                        tracker.announce(&query, REMOTE).unwrap();
+                        // let the other announces interleave with this one
+                        tokio::time::delay_for(Duration::from_millis(round)).await;
+                    }
+                })
+            })
+            .collect();
+
+        // everything runs on the single test thread, so this only finishes in time if no announce
+        // ever blocks it
+        let all = async {
+            for task in tasks {
+                task.await.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), all)
+            .await
+            .expect("announces blocked the runtime");
+
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        assert_eq!(tracker.swarm_counts(&info_hash), (0, 50));
+    }
+
+    #[test]
+    fn geo_aware_peers() {
+        // 10.0.0.0/8 is in europe, everything else is in the US
+        struct StubGeo;
+        impl GeoLookup for StubGeo {
+            fn region(&self, ip: IpAddr) -> Option<Region> {
+                match ip {
+                    IpAddr::V4(ip) if ip.octets()[0] == 10 => Some("eu".into()),
+                    _ => Some("us".into()),
+                }
+            }
+        }
+
+        let tracker = Tracker::new(test_opt(&["--geo-aware", "--trust-ip-param"]))
+            .with_geo(Arc::new(StubGeo));
+        let ips = ["20.0.0.1", "10.0.0.1", "20.0.0.2", "10.0.0.2", "20.0.0.3"];
+        for (n, ip) in ips.iter().enumerate() {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={:020}&ip={}&port=1000",
+                n, ip
+            );
+            tracker.announce(&query, REMOTE).unwrap();
+        }
+
+        let announce = |numwant: u32| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={:020}&ip=10.0.0.1&port=1000&numwant={}",
+                1, numwant
+            );
+            let response = tracker.announce(&query, REMOTE).unwrap();
+            let mut ips: Vec<_> = dict_peers(&response).iter().map(|p| p.ip).collect();
+            ips.sort();
+            ips
+        };
+        for _ in 0..20 {
+            let eu: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+            assert_eq!(announce(2), eu);
+
+            // once the region runs out, the rest comes from anywhere
+            let ips = announce(3);
+            assert_eq!(ips[..2], eu[..]);
+            assert_eq!(ips[2].to_string()[..3], *"20.");
+        }
+    }
+
+    #[test]
+    fn unknown_event() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst\
+            &ip=192.168.0.1&port=1000&event=keepalive";
+
+        let req = TrackerRequest::from_query_string(query, false).unwrap();
+        assert!(matches!(req.event, Some(ClientEvent::Unknown)));
+
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert_eq!(response.incomplete, 1);
+        assert_eq!(tracker.stats().completed, 0);
+    }
+
+    #[test]
+    fn export_json() {
+        let tracker = Tracker::new(test_opt(&["--trust-ip-param"]));
+        for query in [
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&ip=10.0.0.1&port=1000\
+            &left=0",
+            "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&ip=10.0.0.2&port=2000\
+            &left=10",
+            "info_hash=bbcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&ip=::1&port=1000",
+        ] {
+            tracker.announce(query, REMOTE).unwrap();
+        }
+
+        let exported: serde_json::Value = serde_json::from_str(&tracker.export_json()).unwrap();
+        assert_eq!(
+            exported,
+            serde_json::json!({
+                "6162636465666768696a6b6c6d6e6f7071727374": [
+                    {
+                        "ip": "10.0.0.1",
+                        "port": 1000,
+                        "peer_id_hex": "6162636465666768696a6b6c6d6e6f7071727374",
+                        "seeder": true,
+                        "last_seen": 0,
+                    },
+                    {
+                        "ip": "10.0.0.2",
+                        "port": 2000,
+                        "peer_id_hex": "6262636465666768696a6b6c6d6e6f7071727374",
+                        "seeder": false,
+                        "last_seen": 0,
+                    },
+                ],
+                "6262636465666768696a6b6c6d6e6f7071727374": [
+                    {
+                        "ip": "::1",
+                        "port": 1000,
+                        "peer_id_hex": "6162636465666768696a6b6c6d6e6f7071727374",
+                        "seeder": false,
+                        "last_seen": 0,
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn import_json() {
+        let tracker = Tracker::new(test_opt(&["--trust-ip-param"]));
+        for query in [
+            "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&ip=10.0.0.1&port=1000\
+            &left=0",
+            "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&ip=10.0.0.2&port=2000\
+            &left=10",
+            "info_hash=bbcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&ip=::1&port=1000",
+        ] {
+            tracker.announce(query, REMOTE).unwrap();
+        }
+
+        let exported = tracker.export_json();
+        let imported = Tracker::new(test_opt(&[]));
+        assert_eq!(imported.import_json(&exported).unwrap(), 3);
+        assert_eq!(imported.export_json(), exported);
+
+        // a peer at the same address is only replaced by one seen more recently
+        let info_hash = "6162636465666768696a6b6c6d6e6f7071727374";
+        let peer = |peer_id: &str, last_seen: u64| {
+            serde_json::json!({
+                info_hash: [{
+                    "ip": "10.0.0.1",
+                    "port": 1000,
+                    "peer_id_hex": hex::encode(peer_id),
+                    "seeder": false,
+                    "last_seen": last_seen,
+                }]
+            })
+            .to_string()
+        };
+        let addrs = |tracker: &Tracker| {
+            let torrents = swarms(tracker);
+            let hash = InfoHash(*b"abcdefghijklmnopqrst");
+            let mut peers: Vec<_> = torrents[&hash]
+                .peers
+                .iter()
+                .map(|(p, s)| (*p, s.seeder))
+                .collect();
+            peers.sort_by_key(|(peer, _)| peer.addr());
+            peers
+        };
+        imported
+            .import_json(&peer("abcdefghijklmnopqrst", 60))
+            .unwrap();
+        assert!(addrs(&imported)[0].1, "older peer replaced a newer one");
+        imported
+            .import_json(&peer("cbcdefghijklmnopqrst", 0))
+            .unwrap();
+        let peers = addrs(&imported);
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].0.peer_id, PeerId(*b"cbcdefghijklmnopqrst"));
+        assert!(!peers[0].1);
+
+        // nothing is imported from an invalid document
+        let invalid = serde_json::json!({
+            "6262636465666768696a6b6c6d6e6f7071727374": [],
+            "abc": [],
+        });
+        assert!(imported.import_json(&invalid.to_string()).is_err());
+        assert!(imported.import_json("[]").is_err());
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let path = std::env::temp_dir().join(format!("tracker-snapshot-{}", std::process::id()));
+        let tracker = Tracker::new(test_opt(&["--trust-ip-param"]));
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst\
+                &ip=192.168.0.1&port=1000",
+                REMOTE,
+            )
+            .unwrap();
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&ip=::1&port=1000\
+                &left=0&event=completed",
+                REMOTE,
+            )
+            .unwrap();
+        tracker.save_snapshot(&path).unwrap();
+
+        let restored = Tracker::new(test_opt(&[]));
+        restored.load_snapshot(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        assert_eq!(restored.stats().completed, 1);
+        assert_eq!(restored.swarm_counts(&info_hash), (1, 1));
+
+        let torrents = swarms(&restored);
+        let mut restored_peers: Vec<_> = torrents[&info_hash].peers.keys().copied().collect();
+        let mut peers: Vec<_> = swarms(&tracker)[&info_hash].peers.keys().copied().collect();
+        restored_peers.sort_by_key(|peer| peer.addr());
+        peers.sort_by_key(|peer| peer.addr());
+        assert_eq!(restored_peers, peers);
+    }
+
+    #[test]
+    fn health_endpoints() {
+        let get = |tracker: &Tracker, path: &str| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            tracker.handle_session(req, REMOTE_ADDR).status()
+        };
+
+        // nothing to load, so ready right away
+        let tracker = Tracker::new(test_opt(&[]));
+        assert_eq!(get(&tracker, "/healthz"), StatusCode::OK);
+        assert_eq!(get(&tracker, "/readyz"), StatusCode::OK);
+
+        // alive, but not ready until the snapshot has been loaded
+        let path = std::env::temp_dir().join(format!("tracker-ready-{}", std::process::id()));
+        let tracker = Tracker::new(test_opt(&["--snapshot", path.to_str().unwrap()]));
+        assert_eq!(get(&tracker, "/healthz"), StatusCode::OK);
+        assert_eq!(get(&tracker, "/readyz"), StatusCode::SERVICE_UNAVAILABLE);
+        // announces still work in the meantime
+        let announce = "/announce?info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst\
+            &port=1000";
+        assert_eq!(get(&tracker, announce), StatusCode::OK);
+
+        tracker.save_snapshot(&path).unwrap();
+        tracker.load_snapshot(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(get(&tracker, "/readyz"), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn browser_paths() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let get = |path: &str| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            tracker.handle_session(req, REMOTE_ADDR)
+        };
+
+        let response = get("/favicon.ico");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+
+        let response = get("/robots.txt");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"User-agent: *\nDisallow: /\n");
+    }
+
+    #[test]
+    fn seed_from_file() {
+        let path = std::env::temp_dir().join(format!("tracker-seeds-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# bootstrap peers\n\
+             6162636465666768696a6b6c6d6e6f7071727374 10.0.0.1 6881\n\
+             \n\
+             6162636465666768696a6b6c6d6e6f7071727374 ::1 6882\n\
+             6262636465666768696a6b6c6d6e6f7071727374 10.0.0.2 6881\n",
+        )
+        .unwrap();
+
+        let tracker = Tracker::new(test_opt(&["--peer-timeout", "60"]));
+        let seeded = tracker.seed_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(seeded, 3);
+
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let torrents = swarms(&tracker);
+        let mut addrs: Vec<_> = torrents[&info_hash].peers.keys().map(Peer::addr).collect();
+        addrs.sort();
+        assert_eq!(
+            addrs,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:6882".parse().unwrap()
+            ]
+        );
+        assert_eq!(torrents[&InfoHash(*b"bbcdefghijklmnopqrst")].peers.len(), 1);
+        drop(torrents);
+
+        // seeded peers that never announce go stale like any other
+        tracker.reap_expired(Instant::now() + Duration::from_secs(61));
+        assert!(swarms(&tracker).is_empty());
+    }
+
+    #[test]
+    fn seed_from_file_invalid_line() {
+        let path = std::env::temp_dir().join(format!("tracker-bad-seeds-{}", std::process::id()));
+        fs::write(&path, "6162636465666768696a6b6c6d6e6f7071727374 10.0.0.1\n").unwrap();
+
+        let tracker = Tracker::new(test_opt(&[]));
+        let err = tracker.seed_from_file(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(swarms(&tracker).is_empty());
+    }
+
+    #[test]
+    fn merge_trackers() {
+        let ours = Tracker::new(test_opt(&["--trust-ip-param"]));
+        let theirs = Tracker::new(test_opt(&["--trust-ip-param"]));
+        let announce = |tracker: &Tracker, peer: &str| {
+            let query = format!("info_hash=abcdefghijklmnopqrst&port=1000&{}", peer);
+            tracker.announce(&query, REMOTE).unwrap();
+        };
+
+        announce(&ours, "peer_id=abcdefghijklmnopqrst&ip=10.0.0.1");
+        announce(&ours, "peer_id=bbcdefghijklmnopqrst&ip=10.0.0.2");
+        // the same peer as 10.0.0.2 above, but it has since finished and picked a new peer_id
+        announce(
+            &theirs,
+            "peer_id=xbcdefghijklmnopqrst&ip=10.0.0.2&left=0&event=completed",
+        );
+        announce(&theirs, "peer_id=cbcdefghijklmnopqrst&ip=10.0.0.3");
+        let query = "info_hash=bbcdefghijklmnopqrst&peer_id=dbcdefghijklmnopqrst&port=1000";
+        theirs.announce(query, REMOTE).unwrap();
+
+        ours.merge(&theirs);
+
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        assert_eq!(ours.swarm_counts(&info_hash), (1, 2));
+        assert_eq!(
+            ours.swarm_counts(&InfoHash(*b"bbcdefghijklmnopqrst")),
+            (0, 1)
+        );
+        assert_eq!(ours.stats().completed, 1);
+
+        let torrents = swarms(&ours);
+        let swarm = &torrents[&info_hash];
+        assert_eq!(swarm.completed, 1);
+        let mut peer_ids: Vec<_> = swarm.peers.keys().map(|p| p.peer_id).collect();
+        peer_ids.sort();
+        assert_eq!(
+            peer_ids,
+            vec![
+                PeerId(*b"abcdefghijklmnopqrst"),
+                PeerId(*b"cbcdefghijklmnopqrst"),
+                PeerId(*b"xbcdefghijklmnopqrst"),
+            ]
+        );
+    }
+
+    #[test]
+    fn dry_run_announce() {
+        let tracker = Tracker::new(test_opt(&["--allow-dry-run"]));
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst\
+                &ip=192.168.0.1&port=1000",
+                REMOTE,
+            )
+            .unwrap();
+
+        let response = tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&ip=192.168.0.2\
+                &port=1000&left=0&event=completed&dryrun=1",
+                REMOTE,
+            )
+            .unwrap();
+        assert_eq!(dict_peers(&response).len(), 1);
+        assert_eq!((response.complete, response.incomplete), (0, 1));
+
+        // the dry run didn't register the peer or count the completion
+        let stats = tracker.stats();
+        assert_eq!(stats.peers, 1);
+        assert_eq!(stats.completed, 0);
+    }
+
+    #[test]
+    fn dry_run_disabled() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let err = tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&ip=192.168.0.1\
+                &port=1000&dryrun=1",
+                REMOTE,
+            )
+            .unwrap_err();
+
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        assert!(swarms(&tracker).is_empty());
+    }
+
+    #[test]
+    fn uri_too_long() {
+        let tracker = Tracker::new(test_opt(&["--max-uri-length", "200"]));
+        let padding = "&numwant=50".repeat(20);
+        let uri = format!(
+            "/announce?info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst\
+             &ip=192.168.0.1&port=1000{}",
+            padding
+        );
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+
+        assert_eq!(
+            tracker.handle_session(req, REMOTE_ADDR).status(),
+            StatusCode::URI_TOO_LONG
+        );
+        assert!(swarms(&tracker).is_empty());
+    }
+
+    #[test]
+    fn ip_param_trust() {
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&ip=10.1.2.3\
+            &port=1000";
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let stored_ips = |tracker: &Tracker| -> Vec<IpAddr> {
+            let torrents = swarms(tracker);
+            torrents[&info_hash].peers.keys().map(|p| p.ip).collect()
+        };
+
+        // by default the ip param is ignored in favor of where the request came from
+        let tracker = Tracker::new(test_opt(&[]));
+        tracker.announce(query, REMOTE).unwrap();
+        assert_eq!(stored_ips(&tracker), vec![REMOTE]);
+
+        let tracker = Tracker::new(test_opt(&["--trust-ip-param"]));
+        tracker.announce(query, REMOTE).unwrap();
+        assert_eq!(
+            stored_ips(&tracker),
+            vec!["10.1.2.3".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn dual_stack_peer() {
+        let tracker = Tracker::new(test_opt(&["--trust-ip-param"]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000\
+            &ipv4=10.0.0.1&ipv6=2001:db8::1";
+        tracker.announce(query, REMOTE).unwrap();
+
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&port=2000\
+            &compact=1";
+        let response = tracker.announce(query, REMOTE).unwrap();
+        let peers = match response.peers {
+            PeerList::Compact(peers) => decode_compact_peers(&peers.0).unwrap(),
+            _ => panic!("expected a compact response"),
+        };
+        let peers6 = decode_compact_peers6(&response.peers6.unwrap().0).unwrap();
+        assert!(peers.contains(&(Ipv4Addr::new(10, 0, 0, 1), 1000)));
+        assert!(peers.contains(&(Ipv4Addr::LOCALHOST, 1000)));
+        assert_eq!(peers6, vec![("2001:db8::1".parse().unwrap(), 1000)]);
+
+        // untrusted, the extra addresses are ignored just like `ip`
+        let tracker = Tracker::new(test_opt(&[]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000\
+            &ipv4=10.0.0.1&ipv6=2001:db8::1";
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert_eq!(dict_peers(&response).len(), 1);
+        assert_eq!(dict_peers(&response)[0].ip, REMOTE);
+    }
+
+    #[test]
+    fn ipv4_mapped_source_is_ipv4() {
+        let tracker = Tracker::new(test_opt(&[]));
+        let mapped = "::ffff:127.0.0.1".parse().unwrap();
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000",
+                mapped,
+            )
+            .unwrap();
+
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let torrents = swarms(&tracker);
+        let stored: Vec<IpAddr> = torrents[&info_hash].peers.keys().map(|p| p.ip).collect();
+        assert_eq!(stored, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        drop(torrents);
+
+        let response = tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&port=2000&compact=1",
+                "::1".parse().unwrap(),
+            )
+            .unwrap();
+        match response.peers {
+            PeerList::Compact(peers) => assert_eq!(peers.0, vec![127, 0, 0, 1, 0x03, 0xe8]),
+            _ => panic!("expected a compact response"),
+        }
+        // the requester itself is the only IPv6 peer
+        assert_eq!(response.peers6.unwrap().0.len(), 18);
+    }
+
+    #[test]
+    fn peer_ip_precedence() {
+        let connection: IpAddr = "192.0.2.1".parse().unwrap();
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let param: IpAddr = "198.51.100.1".parse().unwrap();
+        let trusting = test_opt(&["--trusted-proxy", "10.0.0.1", "--trusted-proxy", "10.0.0.2"]);
+        let trusting_all = test_opt(&["--trusted-proxy", "10.0.0.2", "--trust-ip-param"]);
+        let untrusting = test_opt(&["--trust-ip-param"]);
+        let resolve = |param, forwarded_for, connection, opt| {
+            resolve_peer_ip(param, forwarded_for, connection, opt).to_string()
+        };
+
+        for (param, forwarded_for, connection, opt, expected) in [
+            // nothing but the connection
+            (None, None, connection, &untrusting, "192.0.2.1"),
+            (None, None, proxy, &trusting, "10.0.0.2"),
+            // a trusted proxy is taken at its word, but anyone else isn't
+            (None, Some("203.0.113.5"), proxy, &trusting, "203.0.113.5"),
+            (
+                None,
+                Some("203.0.113.5"),
+                connection,
+                &trusting,
+                "192.0.2.1",
+            ),
+            (None, Some("203.0.113.5"), proxy, &untrusting, "10.0.0.2"),
+            // past a chain of our own proxies, and no further
+            (
+                None,
+                Some("1.1.1.1, 203.0.113.5, 10.0.0.1"),
+                proxy,
+                &trusting,
+                "203.0.113.5",
+            ),
+            (None, Some("10.0.0.1"), proxy, &trusting, "10.0.0.1"),
+            (
+                None,
+                Some("203.0.113.5, unknown"),
+                proxy,
+                &trusting,
+                "10.0.0.2",
+            ),
+            (
+                None,
+                Some("::ffff:203.0.113.5"),
+                proxy,
+                &trusting,
+                "203.0.113.5",
+            ),
+            // a trusted ip param wins over everything, an untrusted one is ignored
+            (
+                Some(param),
+                Some("203.0.113.5"),
+                proxy,
+                &trusting_all,
+                "198.51.100.1",
+            ),
+            (Some(param), None, connection, &untrusting, "198.51.100.1"),
+            (
+                Some(param),
+                Some("203.0.113.5"),
+                proxy,
+                &trusting,
+                "203.0.113.5",
+            ),
+            (Some(param), None, connection, &trusting, "192.0.2.1"),
+        ] {
+            let resolved = resolve(param, forwarded_for, connection, opt);
+            assert_eq!(
+                resolved, expected,
+                "{:?} {:?} {}",
+                param, forwarded_for, connection
+            );
+        }
+
+        // the header makes it from the request to the swarm
+        let tracker = Tracker::new(test_opt(&["--trusted-proxy", "127.0.0.1"]));
+        let req = Request::get(
+            "/announce?info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000",
+        )
+        .header("X-Forwarded-For", "203.0.113.5")
+        .body(Body::empty())
+        .unwrap();
+        assert_eq!(
+            tracker.handle_session(req, REMOTE_ADDR).status(),
+            StatusCode::OK
+        );
+        let swarm = &swarms(&tracker)[&InfoHash(*b"abcdefghijklmnopqrst")];
+        let ips: Vec<String> = swarm.peers.keys().map(|peer| peer.ip.to_string()).collect();
+        assert_eq!(ips, ["203.0.113.5"]);
+    }
+
+    #[test]
+    fn single_peer_swarm() {
+        let tracker = Tracker::new(test_opt(&["--exclude-requester"]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000";
+        // alone in the swarm, the requester gets nobody back
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert!(dict_peers(&response).is_empty());
+        let response = tracker
+            .announce(&format!("{}&compact=1", query), REMOTE)
+            .unwrap();
+        match response.peers {
+            PeerList::Compact(peers) => assert!(peers.0.is_empty()),
+            _ => panic!("expected a compact response"),
+        }
+
+        // with one other peer, that peer is the whole list
+        let other = "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&port=2000";
+        let response = tracker.announce(other, REMOTE).unwrap();
+        let peers = dict_peers(&response);
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer_id, PeerId(*b"abcdefghijklmnopqrst"));
+        assert_eq!(peers[0].port, 1000);
+        let response = tracker
+            .announce(&format!("{}&compact=1", other), REMOTE)
+            .unwrap();
+        match response.peers {
+            PeerList::Compact(peers) => assert_eq!(peers.0, vec![127, 0, 0, 1, 0x03, 0xe8]),
+            _ => panic!("expected a compact response"),
+        }
+    }
+
+    #[test]
+    fn dual_stack_host_returned_once() {
+        let tracker = Tracker::new(test_opt(&["--trust-ip-param", "--collapse-dual-stack"]));
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&ip=10.0.0.1\
+            &ipv6=2001:db8::1&port=1000";
+        tracker.announce(query, REMOTE).unwrap();
+        let host_ips = |requester: &str| {
+            let query = "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&port=2000";
+            let response = tracker.announce(query, requester.parse().unwrap()).unwrap();
+            dict_peers(&response)
+                .iter()
+                .filter(|peer| peer.peer_id == PeerId(*b"abcdefghijklmnopqrst"))
+                .map(|peer| peer.ip.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(host_ips("10.0.0.2"), ["10.0.0.1"]);
+        assert_eq!(host_ips("2001:db8::2"), ["2001:db8::1"]);
+
+        // without the flag, the host is there twice
+        let tracker = Tracker::new(test_opt(&["--trust-ip-param"]));
+        tracker.announce(query, REMOTE).unwrap();
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&port=2000";
+        let response = tracker.announce(query, REMOTE).unwrap();
+        assert_eq!(dict_peers(&response).len(), 3);
+    }
+
+    #[test]
+    fn address_family_filter() {
+        let tracker = Tracker::new(test_opt(&["--trust-ip-param", "--match-address-family"]));
+        for (peer_id, ip) in [
+            ("abcdefghijklmnopqrst", "10.0.0.1"),
+            ("bbcdefghijklmnopqrst", "2001:db8::1"),
+        ] {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id={}&ip={}&port=1000",
+                peer_id, ip
+            );
+            tracker.announce(&query, REMOTE).unwrap();
+        }
+        let announce = |query: &str, ip: &str| {
+            let query = format!(
+                "info_hash=abcdefghijklmnopqrst&peer_id=cbcdefghijklmnopqrst&port=2000{}",
+                query
+            );
+            let response = tracker.announce(&query, ip.parse().unwrap()).unwrap();
+            let mut ips: Vec<String> = dict_peers(&response)
+                .iter()
+                .map(|peer| peer.ip.to_string())
+                .collect();
+            ips.sort();
+            ips
         };
 
-        let tracker = Tracker::new(opt);
-        tracker.handle_session(req.body(Body::empty()).unwrap());
+        // an IPv6-only client only gets IPv6 peers (including itself)
+        assert_eq!(announce("", "2001:db8::2"), ["2001:db8::1", "2001:db8::2"]);
+        // but one that's also reachable over IPv4 gets both
+        assert_eq!(
+            announce("&ipv4=10.0.0.3", "2001:db8::2").len(),
+            4,
+            "dual-stack clients get every peer"
+        );
+        // and a client can always ask for a family itself
+        assert_eq!(
+            announce("&family=ipv4", "2001:db8::2"),
+            ["10.0.0.1", "10.0.0.3"]
+        );
+
+        // without the flag only the explicit param filters
+        let tracker = Tracker::new(test_opt(&[]));
+        tracker
+            .announce(
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&port=1000",
+                REMOTE,
+            )
+            .unwrap();
+        let query = "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&port=1000";
+        let response = tracker.announce(query, "::1".parse().unwrap()).unwrap();
+        assert_eq!(dict_peers(&response).len(), 2);
+        let query = format!("{}&family=ipv6", query);
+        let response = tracker.announce(&query, "::1".parse().unwrap()).unwrap();
+        assert_eq!(dict_peers(&response).len(), 1);
+        assert_eq!(
+            dict_peers(&response)[0].ip,
+            "::1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    // Collects everything a tracing subscriber writes, so tests can inspect the log output.
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn anonymized_logs() {
+        let tracker = Tracker::new(test_opt(&["--anonymize-ips", "--trust-ip-param"]));
+        let logs = LogCapture::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let query =
+                "info_hash=abcdefghijklmnopqrst&peer_id=abcdefghijklmnopqrst&ip=192.168.5.77\
+                &port=1000";
+            tracker.announce(query, REMOTE).unwrap();
+            let query = "info_hash=abcdefghijklmnopqrst&peer_id=bbcdefghijklmnopqrst&port=1000";
+            tracker
+                .announce(query, "2001:db8:1:2:3:4:5:6".parse().unwrap())
+                .unwrap();
+        });
+
+        let logged = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logged.contains("ip=192.168.5.0"), "{}", logged);
+        assert!(logged.contains("ip=2001:db8:1::"), "{}", logged);
+        assert!(!logged.contains("192.168.5.77"));
+        assert!(!logged.contains("3:4:5:6"));
+
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let torrents = swarms(&tracker);
+        let mut stored: Vec<String> = torrents[&info_hash]
+            .peers
+            .keys()
+            .map(|p| p.ip.to_string())
+            .collect();
+        stored.sort();
+        assert_eq!(stored, vec!["192.168.5.77", "2001:db8:1:2:3:4:5:6"]);
     }
 }
@@ -0,0 +1,152 @@
+//! Works out which client software a peer runs from its peer_id, going by the two common
+//! conventions for encoding it. Azureus-style ids start with `-`, a two letter client code, four
+//! version characters and another `-`, like `-qB4500-`. Shadow-style ids start with a client
+//! letter followed by up to five version digits padded with `-`, like `T03I--`.
+use super::PeerId;
+
+use std::fmt;
+
+/// A client and the version of it that generated a peer_id.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientInfo {
+    pub name: &'static str,
+    // dotted version numbers, like "4.5.0.0"
+    pub version: String,
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.version)
+    }
+}
+
+// A few common clients using Azureus-style peer_ids
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("qB", "qBittorrent"),
+    ("TR", "Transmission"),
+    ("UT", "\u{b5}Torrent"),
+    ("WW", "WebTorrent"),
+];
+
+// Clients using Shadow-style peer_ids
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT BitTorrent"),
+];
+
+// Shadow-style version digits are base 64
+const SHADOW_DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz.-";
+
+/// Identifies the client that generated `peer_id`, or returns None if it doesn't follow either
+/// convention or names a client we don't know.
+pub fn identify(peer_id: &PeerId) -> Option<ClientInfo> {
+    let id = &peer_id.0;
+    if id[0] == b'-' && id[7] == b'-' {
+        azureus(id)
+    } else {
+        shadow(id)
+    }
+}
+
+fn azureus(id: &[u8; 20]) -> Option<ClientInfo> {
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(code, _)| code.as_bytes() == &id[1..3])?
+        .1;
+    let version = &id[3..7];
+    if !version.iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let version: Vec<String> = version.iter().map(|&c| (c as char).to_string()).collect();
+    Some(ClientInfo {
+        name,
+        version: version.join("."),
+    })
+}
+
+fn shadow(id: &[u8; 20]) -> Option<ClientInfo> {
+    let name = SHADOW_CLIENTS.iter().find(|(code, _)| *code == id[0])?.1;
+    let digits = id[1..6].iter().position(|&c| c == b'-')?;
+    if digits == 0 || id[1 + digits..6].iter().any(|&c| c != b'-') {
+        return None;
+    }
+    let version: Vec<String> = id[1..1 + digits]
+        .iter()
+        .map(|&c| {
+            SHADOW_DIGITS
+                .iter()
+                .position(|&d| d == c)
+                .map(|n| n.to_string())
+        })
+        .collect::<Option<_>>()?;
+    Some(ClientInfo {
+        name,
+        version: version.join("."),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn label(peer_id: &[u8; 20]) -> Option<String> {
+        identify(&PeerId(*peer_id)).map(|info| info.to_string())
+    }
+
+    #[test]
+    fn azureus_style() {
+        assert_eq!(
+            identify(&PeerId(*b"-qB4500-abcdefghijkl")),
+            Some(ClientInfo {
+                name: "qBittorrent",
+                version: "4.5.0.0".to_string(),
+            })
+        );
+        assert_eq!(
+            label(b"-TR2940-abcdefghijkl").as_deref(),
+            Some("Transmission 2.9.4.0")
+        );
+        assert_eq!(
+            label(b"-LT1270-abcdefghijkl").as_deref(),
+            Some("libtorrent 1.2.7.0")
+        );
+        assert_eq!(
+            label(b"-lt0D80-abcdefghijkl").as_deref(),
+            Some("rTorrent 0.D.8.0")
+        );
+    }
+
+    #[test]
+    fn shadow_style() {
+        assert_eq!(
+            label(b"T03I--00abcdefghijkl").as_deref(),
+            Some("BitTornado 0.3.18")
+        );
+        assert_eq!(
+            label(b"S58B-----abcdefghijk").as_deref(),
+            Some("Shadow 5.8.11")
+        );
+    }
+
+    #[test]
+    fn unrecognized() {
+        assert_eq!(label(b"-XX1234-abcdefghijkl"), None);
+        assert_eq!(label(b"-qB4.5.-abcdefghijkl"), None);
+        assert_eq!(label(b"abcdefghijklmnopqrst"), None);
+        assert_eq!(label(b"T-------abcdefghijkl"), None);
+        assert_eq!(label(b"Tabcdefghijklmnopqrs"), None);
+        assert_eq!(label(&[0; 20]), None);
+    }
+}
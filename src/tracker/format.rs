@@ -0,0 +1,421 @@
+//! The formats responses can be written in. Clients speak bencode, while dashboards and scripts
+//! would rather have JSON. Response types only derive `Serialize` once, and are rendered in
+//! whichever format the endpoint and the client's `Accept` header settle on.
+use super::bencode;
+
+use hyper::header::ACCEPT;
+use hyper::{Body, Request};
+use serde::{ser, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Bencode,
+    Json,
+}
+
+impl Format {
+    /// The format `req` asks for in its `Accept` header, or `default` if it doesn't name one.
+    pub fn negotiate(req: &Request<Body>, default: Self) -> Self {
+        let accept = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or_default();
+        // the media ranges the client accepts, leaving out the ones it refuses with q=0
+        let accepted: Vec<&str> = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let media_type = params.next()?.trim();
+                let refused = params.any(|param| match param.split_once('=') {
+                    Some((name, q)) if name.trim().eq_ignore_ascii_case("q") => {
+                        q.trim().parse::<f32>() == Ok(0.0)
+                    }
+                    _ => false,
+                });
+                Some(media_type).filter(|_| !refused)
+            })
+            .collect();
+        let accepts = |media_type| accepted.iter().any(|r| r.eq_ignore_ascii_case(media_type));
+        if accepts("application/json") {
+            Self::Json
+        } else if accepts("application/x-bencode") {
+            Self::Bencode
+        } else {
+            default
+        }
+    }
+
+    /// The `Content-Type` of a response in this format. Bencode has no registered media type, and
+    /// clients don't look for one.
+    pub fn content_type(self) -> Option<&'static str> {
+        match self {
+            Self::Bencode => None,
+            Self::Json => Some("application/json"),
+        }
+    }
+
+    pub fn render<T: Serialize>(self, value: &T) -> Vec<u8> {
+        match self {
+            Self::Bencode => bencode(value),
+            Self::Json => {
+                serde_json::to_vec(&HexBinary(value)).expect("responses are always serializable")
+            }
+        }
+    }
+}
+
+/// Serializes the value it wraps with every byte string written as hex instead, for formats that
+/// have no byte strings. Without it serde_json would write binary fields (info_hashes, peer_ids,
+/// compact peers) as arrays of numbers, and text fields are left alone, so a JSON string always
+/// tells which of the two it is. `Format::Json` renders through this, and so should anyone
+/// serializing a response to JSON themselves.
+pub struct HexBinary<'a, T: ?Sized>(pub &'a T);
+
+impl<T: Serialize + ?Sized> Serialize for HexBinary<'_, T> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(HexSerializer(serializer))
+    }
+}
+
+// Passes everything through to the serializer it wraps, except for byte strings. Values nested in
+// compound types are wrapped in `HexBinary` again on the way through.
+struct HexSerializer<S>(S);
+
+macro_rules! forward {
+    ($($method:ident($type:ty),)*) => {
+        $(
+            fn $method(self, value: $type) -> Result<Self::Ok, Self::Error> {
+                self.0.$method(value)
+            }
+        )*
+    };
+}
+
+impl<S: ser::Serializer> ser::Serializer for HexSerializer<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = HexSerializer<S::SerializeSeq>;
+    type SerializeTuple = HexSerializer<S::SerializeTuple>;
+    type SerializeTupleStruct = HexSerializer<S::SerializeTupleStruct>;
+    type SerializeTupleVariant = HexSerializer<S::SerializeTupleVariant>;
+    type SerializeMap = HexSerializer<S::SerializeMap>;
+    type SerializeStruct = HexSerializer<S::SerializeStruct>;
+    type SerializeStructVariant = HexSerializer<S::SerializeStructVariant>;
+
+    forward! {
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_i128(i128),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_unit_struct(&'static str),
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<Self::Ok, Self::Error> {
+        self.0.serialize_str(&hex::encode(value))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        self.0.serialize_none()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        self.0.serialize_some(&HexBinary(value))
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        self.0.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.0.serialize_unit_variant(name, index, variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.0.serialize_newtype_struct(name, &HexBinary(value))
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.0
+            .serialize_newtype_variant(name, index, variant, &HexBinary(value))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        self.0.serialize_seq(len).map(HexSerializer)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        self.0.serialize_tuple(len).map(HexSerializer)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        self.0.serialize_tuple_struct(name, len).map(HexSerializer)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        self.0
+            .serialize_tuple_variant(name, index, variant, len)
+            .map(HexSerializer)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        self.0.serialize_map(len).map(HexSerializer)
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        self.0.serialize_struct(name, len).map(HexSerializer)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        self.0
+            .serialize_struct_variant(name, index, variant, len)
+            .map(HexSerializer)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.0.is_human_readable()
+    }
+}
+
+// The compound serializers that only take elements, one impl each.
+macro_rules! forward_elements {
+    ($($trait:ident::$method:ident,)*) => {
+        $(
+            impl<S: ser::$trait> ser::$trait for HexSerializer<S> {
+                type Ok = S::Ok;
+                type Error = S::Error;
+
+                fn $method<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+                    self.0.$method(&HexBinary(value))
+                }
+
+                fn end(self) -> Result<S::Ok, S::Error> {
+                    self.0.end()
+                }
+            }
+        )*
+    };
+}
+
+forward_elements! {
+    SerializeSeq::serialize_element,
+    SerializeTuple::serialize_element,
+    SerializeTupleStruct::serialize_field,
+    SerializeTupleVariant::serialize_field,
+}
+
+impl<S: ser::SerializeMap> ser::SerializeMap for HexSerializer<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), S::Error> {
+        self.0.serialize_key(&HexBinary(key))
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.0.serialize_value(&HexBinary(value))
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.0.end()
+    }
+}
+
+// Structs and struct variants take named fields, which can also be skipped.
+macro_rules! forward_fields {
+    ($($trait:ident,)*) => {
+        $(
+            impl<S: ser::$trait> ser::$trait for HexSerializer<S> {
+                type Ok = S::Ok;
+                type Error = S::Error;
+
+                fn serialize_field<T: Serialize + ?Sized>(
+                    &mut self,
+                    key: &'static str,
+                    value: &T,
+                ) -> Result<(), S::Error> {
+                    self.0.serialize_field(key, &HexBinary(value))
+                }
+
+                fn skip_field(&mut self, key: &'static str) -> Result<(), S::Error> {
+                    self.0.skip_field(key)
+                }
+
+                fn end(self) -> Result<S::Ok, S::Error> {
+                    self.0.end()
+                }
+            }
+        )*
+    };
+}
+
+forward_fields! {
+    SerializeStruct,
+    SerializeStructVariant,
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{InfoHash, PeerList, TrackerResponse};
+    use super::*;
+    use serde_json::Value as JsonValue;
+    use std::collections::HashMap;
+
+    #[test]
+    fn tracker_response() {
+        let response = TrackerResponse {
+            interval: 1800,
+            min_interval: Some(60),
+            complete: 1,
+            incomplete: 2,
+            downloaded: None,
+            external_ip: None,
+            peers: PeerList::compact(&[]).0,
+            peers6: None,
+            warning_message: Some("hi".to_string()),
+        };
+
+        assert_eq!(
+            Format::Bencode.render(&response),
+            &b"d8:completei1e10:incompletei2e8:intervali1800e12:min intervali60e5:peers0:\
+                15:warning message2:hie"[..]
+        );
+        let json: JsonValue = serde_json::from_slice(&Format::Json.render(&response)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "complete": 1,
+                "incomplete": 2,
+                "interval": 1800,
+                "min interval": 60,
+                "peers": "",
+                "warning message": "hi",
+            })
+        );
+    }
+
+    #[test]
+    fn json_keeps_types() {
+        #[derive(Serialize)]
+        struct Fields {
+            seeder: bool,
+            left: u64,
+            name: String,
+            info_hash: InfoHash,
+            nested: Option<Vec<InfoHash>>,
+            files: HashMap<InfoHash, u32>,
+        }
+        let info_hash = InfoHash(*b"abcdefghijklmnopqrst");
+        let fields = Fields {
+            seeder: true,
+            left: u64::MAX,
+            name: "text".to_string(),
+            info_hash,
+            nested: Some(vec![info_hash]),
+            files: vec![(info_hash, 1)].into_iter().collect(),
+        };
+
+        let json: JsonValue = serde_json::from_slice(&Format::Json.render(&fields)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "seeder": true,
+                "left": u64::MAX,
+                "name": "text",
+                // hex even though the bytes are valid UTF-8
+                "info_hash": "6162636465666768696a6b6c6d6e6f7071727374",
+                // including inside other values and as map keys
+                "nested": ["6162636465666768696a6b6c6d6e6f7071727374"],
+                "files": { "6162636465666768696a6b6c6d6e6f7071727374": 1 },
+            })
+        );
+        // bencode still gets the raw bytes
+        let expected = b"9:info_hash20:abcdefghijklmnopqrst";
+        assert!(Format::Bencode
+            .render(&fields)
+            .windows(expected.len())
+            .any(|window| window == expected));
+    }
+
+    #[test]
+    fn negotiate() {
+        let request = |accept: Option<&str>| {
+            let mut req = Request::get("/stats");
+            if let Some(accept) = accept {
+                req = req.header(ACCEPT, accept);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        let negotiate = |accept, default| Format::negotiate(&request(accept), default);
+
+        assert_eq!(negotiate(None, Format::Json), Format::Json);
+        assert_eq!(negotiate(None, Format::Bencode), Format::Bencode);
+        assert_eq!(negotiate(Some("*/*"), Format::Bencode), Format::Bencode);
+        assert_eq!(
+            negotiate(Some("text/html, application/json;q=0.9"), Format::Bencode),
+            Format::Json
+        );
+        assert_eq!(
+            negotiate(Some("application/x-bencode"), Format::Json),
+            Format::Bencode
+        );
+        // q=0 means the client won't take that type at all
+        assert_eq!(
+            negotiate(Some("application/json;q=0"), Format::Bencode),
+            Format::Bencode
+        );
+        assert_eq!(
+            negotiate(
+                Some("application/json; q=0.0, application/x-bencode"),
+                Format::Json
+            ),
+            Format::Bencode
+        );
+    }
+}
@@ -0,0 +1,623 @@
+//! Where the tracker keeps its swarms. Storage sits behind the `PeerStore` trait so that the
+//! in-memory map can be swapped for something like Redis or an SQL database, which would let swarms
+//! survive a restart and be shared by several tracker nodes.
+use super::{lock, unix_time, InfoHash, Peer, PeerState, ScrapeFile, Swarm};
+use crate::geo::Region;
+
+use rand::seq::{IteratorRandom, SliceRandom};
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A summary of a torrent's swarm, without its peers.
+#[derive(Copy, Clone, Debug)]
+pub struct SwarmInfo {
+    // every peer in the swarm, including observers
+    pub peers: usize,
+    pub seeders: usize,
+    pub leechers: usize,
+    // number of 'completed' events received for this torrent
+    pub completed: u32,
+    // when the last 'completed' event was received
+    pub last_completed: Option<SystemTime>,
+    // when the first peer registered for this torrent
+    pub created: Instant,
+    // when we last received an announce for this torrent
+    pub last_activity: Instant,
+    // peers that joined or left within the churn window
+    pub churn: usize,
+    // moving average of announces per second
+    pub announce_rate: f64,
+}
+
+impl SwarmInfo {
+    pub fn scrape(&self) -> ScrapeFile {
+        ScrapeFile {
+            complete: self.seeders,
+            downloaded: self.completed,
+            incomplete: self.leechers,
+            name: None,
+            last_completed: self.last_completed.map(unix_time),
+        }
+    }
+}
+
+/// How `PeerStore::sample` picks peers when there are more to choose from than it needs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Selection {
+    // a random sample in a random order, so that load is spread over the whole swarm
+    Random,
+    // the peers we heard from most recently, so that repeated requests get the same answer
+    Recent,
+}
+
+/// Keeps track of the peers in every swarm. Implement this to keep swarms somewhere other than in
+/// memory, like Redis or an SQL database, and hand the store to `Tracker::with_store`.
+///
+/// A store only has to implement the methods announces need: `register`, `remove`, `sample`,
+/// `stats` and `reap`. The others have defaults built on those, or that do without: a store that
+/// doesn't count joins and completions reports no churn or completions, one that can't list its
+/// swarms leaves them out of `/stats` and snapshots, and so on. `InMemoryStore` implements them
+/// all.
+///
+/// Every method takes `&self` and returns owned values: a store does its own locking, and nothing
+/// it returns borrows from it. So callers can never hold a store's lock across an `.await`, and a
+/// single store can be shared by all of the server's tasks.
+///
+/// Methods are called synchronously while a request is served, from the runtime's own threads,
+/// so by default they must not block. A store that waits on the network or disk must return true
+/// from `may_block`: the server then serves every request on tokio's blocking thread pool (with
+/// `spawn_blocking`), where each one holds a pool thread until it's done. Such a store is best
+/// kept fast anyway, by answering from a local cache and writing through in the background.
+pub trait PeerStore: Send + Sync {
+    /// Records an announce from `peer`. `update` is given the state we have for the peer, if any,
+    /// and returns its new state.
+    fn register(
+        &self,
+        info_hash: InfoHash,
+        peer: Peer,
+        update: &dyn Fn(Option<&PeerState>) -> PeerState,
+    );
+
+    /// Forgets `peer`, which left the swarm at `now`, and its torrent if no peers are left.
+    /// Returns whether we knew the peer.
+    fn remove(&self, info_hash: &InfoHash, peer: &Peer, now: Instant) -> bool;
+
+    /// Picks up to `count` peers of a torrent for which `eligible` returns true, chosen as
+    /// `selection` says. Peers in `region` are picked before any others. `count` may be more than
+    /// the swarm has, up to `usize::MAX` for all of them.
+    fn sample(
+        &self,
+        info_hash: &InfoHash,
+        count: usize,
+        selection: Selection,
+        region: Option<&Region>,
+        eligible: &dyn Fn(&Peer, &PeerState) -> bool,
+    ) -> Vec<Peer>;
+
+    /// Summarizes a torrent's swarm as of `now`, if it has one.
+    fn stats(&self, info_hash: &InfoHash, now: Instant) -> Option<SwarmInfo>;
+
+    /// Forgets peers that haven't announced within `timeout` and leaving peers whose time is up,
+    /// counting them as leaving, along with any swarm left without peers.
+    fn reap(&self, now: Instant, timeout: Duration);
+
+    /// Whether calls may block on I/O.
+    fn may_block(&self) -> bool {
+        false
+    }
+
+    /// Sets `peer` aside as leaving the swarm: it's no longer handed out or counted, and is
+    /// forgotten by the first `reap` from `until` on, unless it registers again before then and
+    /// picks up its old state. Returns whether we knew the peer.
+    ///
+    /// By default the peer is removed right away.
+    fn leave(&self, info_hash: &InfoHash, peer: &Peer, until: Instant) -> bool {
+        let _ = until;
+        self.remove(info_hash, peer, Instant::now())
+    }
+
+    /// Forgets a torrent along with all of its peers. Returns whether we knew the torrent.
+    ///
+    /// By default torrents can't be forgotten, and this returns false.
+    fn remove_swarm(&self, info_hash: &InfoHash) -> bool {
+        let _ = info_hash;
+        false
+    }
+
+    /// Counts a peer joining a torrent's swarm at `now`, for its churn. Not counted by default.
+    fn join(&self, info_hash: &InfoHash, now: Instant) {
+        let _ = (info_hash, now);
+    }
+
+    /// Counts a completed download of a torrent at `now`, if the torrent has a swarm. Not counted
+    /// by default.
+    fn complete(&self, info_hash: &InfoHash, now: SystemTime) {
+        let _ = (info_hash, now);
+    }
+
+    /// Counts an announce to a torrent at `now`, for its announce rate. Not counted by default.
+    fn announced(&self, info_hash: &InfoHash, now: Instant) {
+        let _ = (info_hash, now);
+    }
+
+    /// Returns the state of a single peer in a torrent's swarm. By default the swarm is searched
+    /// with `sample`.
+    fn peer(&self, info_hash: &InfoHash, peer: &Peer) -> Option<PeerState> {
+        peer_states(self, info_hash, &|other| other == peer)
+            .pop()
+            .map(|(_, state)| state)
+    }
+
+    /// Summarizes every swarm as of `now`. By default no swarms are listed.
+    fn all_stats(&self, now: Instant) -> Vec<(InfoHash, SwarmInfo)> {
+        let _ = now;
+        Vec::new()
+    }
+
+    /// Returns how many torrents have a swarm, by default as counted by `all_stats`.
+    fn torrent_count(&self) -> usize {
+        self.all_stats(Instant::now()).len()
+    }
+
+    /// Returns how many peers are registered across every swarm, by default as counted by
+    /// `all_stats`.
+    fn peer_count(&self) -> usize {
+        let swarms = self.all_stats(Instant::now());
+        swarms.iter().map(|(_, info)| info.peers).sum()
+    }
+
+    /// Returns a copy of a torrent's swarm. By default swarms can't be copied out, and this
+    /// returns None.
+    fn swarm(&self, info_hash: &InfoHash) -> Option<Swarm> {
+        let _ = info_hash;
+        None
+    }
+
+    /// Returns a copy of every swarm, by default none of them.
+    fn swarms(&self) -> Vec<(InfoHash, Swarm)> {
+        Vec::new()
+    }
+
+    /// Returns up to `limit` of a torrent's peers, skipping the first `offset`, along with how
+    /// many peers it has in all. Peers are in the order of their address, so that consecutive
+    /// pages don't overlap as long as the swarm doesn't change. By default the whole swarm is
+    /// gone through with `sample`.
+    fn peer_page(
+        &self,
+        info_hash: &InfoHash,
+        offset: usize,
+        limit: usize,
+    ) -> Option<(usize, Vec<(Peer, PeerState)>)> {
+        self.stats(info_hash, Instant::now())?;
+        let mut peers = peer_states(self, info_hash, &|_| true);
+        peers.sort_unstable_by_key(|(peer, _)| (peer.addr(), peer.peer_id));
+        let total = peers.len();
+        Some((total, peers.into_iter().skip(offset).take(limit).collect()))
+    }
+
+    /// Replaces every swarm with `swarms`. Ignored by default, for stores that keep their swarms
+    /// across restarts on their own.
+    fn restore(&self, swarms: Vec<(InfoHash, Swarm)>) {
+        let _ = swarms;
+    }
+
+    /// Folds `swarms` into the ones we already have, see `Swarm::merge`. Ignored by default, for
+    /// stores that are already shared by every node.
+    fn merge(&self, swarms: Vec<(InfoHash, Swarm)>) {
+        let _ = swarms;
+    }
+}
+
+// The peers of a torrent that `wanted` returns true for, along with their state, found with
+// `PeerStore::sample`.
+fn peer_states<S: PeerStore + ?Sized>(
+    store: &S,
+    info_hash: &InfoHash,
+    wanted: &dyn Fn(&Peer) -> bool,
+) -> Vec<(Peer, PeerState)> {
+    // `sample` only hands out peers, so their state is picked up on the way past
+    let found = RefCell::new(Vec::new());
+    store.sample(
+        info_hash,
+        usize::MAX,
+        Selection::Recent,
+        None,
+        &|peer, state| {
+            if wanted(peer) {
+                found.borrow_mut().push((*peer, state.clone()));
+            }
+            wanted(peer)
+        },
+    );
+    found.into_inner()
+}
+
+/// Keeps every swarm in a map in memory, which is lost when the tracker exits unless it's saved
+/// to a snapshot.
+#[derive(Default)]
+pub struct InMemoryStore {
+    // TODO: replace with a concurrent hashmap for finer grained locking?
+    torrents: Mutex<HashMap<InfoHash, Swarm>>,
+}
+
+impl PeerStore for InMemoryStore {
+    fn register(
+        &self,
+        info_hash: InfoHash,
+        peer: Peer,
+        update: &dyn Fn(Option<&PeerState>) -> PeerState,
+    ) {
+        let mut torrents = lock(&self.torrents);
+        let reclaimed = torrents
+            .get_mut(&info_hash)
+            .and_then(|swarm| swarm.leaving.remove(&peer))
+            .map(|(state, _)| state);
+        let previous = reclaimed.as_ref().or_else(|| {
+            torrents
+                .get(&info_hash)
+                .and_then(|swarm| swarm.peers.get(&peer))
+        });
+        let state = update(previous);
+        let now = state.last_seen;
+
+        let swarm = torrents
+            .entry(info_hash) // we identify a torrent by its info_hash
+            .or_insert_with(|| Swarm::new(now)); // create a mapping for new torrents
+        swarm.peers.insert(peer, state); // track all the peers participating in this torrent
+        swarm.last_activity = now;
+    }
+
+    fn remove(&self, info_hash: &InfoHash, peer: &Peer, now: Instant) -> bool {
+        let mut torrents = lock(&self.torrents);
+        let swarm = match torrents.get_mut(info_hash) {
+            Some(swarm) => swarm,
+            None => return false,
+        };
+        let removed = swarm.peers.remove(peer).is_some();
+        if removed {
+            swarm.record_churn(now);
+        }
+        if swarm.peers.is_empty() && swarm.leaving.is_empty() {
+            torrents.remove(info_hash);
+        }
+        removed
+    }
+
+    fn leave(&self, info_hash: &InfoHash, peer: &Peer, until: Instant) -> bool {
+        let mut torrents = lock(&self.torrents);
+        let swarm = match torrents.get_mut(info_hash) {
+            Some(swarm) => swarm,
+            None => return false,
+        };
+        match swarm.peers.remove(peer) {
+            Some(state) => {
+                swarm.leaving.insert(*peer, (state, until));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_swarm(&self, info_hash: &InfoHash) -> bool {
+        lock(&self.torrents).remove(info_hash).is_some()
+    }
+
+    fn join(&self, info_hash: &InfoHash, now: Instant) {
+        let mut torrents = lock(&self.torrents);
+        if let Some(swarm) = torrents.get_mut(info_hash) {
+            swarm.record_churn(now);
+        }
+    }
+
+    fn complete(&self, info_hash: &InfoHash, now: SystemTime) {
+        let mut torrents = lock(&self.torrents);
+        if let Some(swarm) = torrents.get_mut(info_hash) {
+            swarm.completed += 1;
+            swarm.last_completed = Some(now);
+        }
+    }
+
+    fn announced(&self, info_hash: &InfoHash, now: Instant) {
+        let mut torrents = lock(&self.torrents);
+        if let Some(swarm) = torrents.get_mut(info_hash) {
+            swarm.record_announce(now);
+        }
+    }
+
+    fn sample(
+        &self,
+        info_hash: &InfoHash,
+        count: usize,
+        selection: Selection,
+        region: Option<&Region>,
+        eligible: &dyn Fn(&Peer, &PeerState) -> bool,
+    ) -> Vec<Peer> {
+        let torrents = lock(&self.torrents);
+        let mut rng = rand::thread_rng();
+        let swarm = match torrents.get(info_hash) {
+            Some(swarm) => swarm,
+            None => return vec![],
+        };
+
+        // we can copy these out or return the MutexGuard
+        // since these borrow from the `torrents` MutexGuard we are not allowed to return
+        // references without also holding the lock.
+        let (nearby, elsewhere): (Vec<_>, Vec<_>) = swarm
+            .peers
+            .iter()
+            .filter(|(peer, state)| eligible(peer, state))
+            .partition(|(_, state)| region.is_some() && state.region.as_ref() == region);
+
+        // choose_multiple allocates room for as many peers as we ask for, so never ask for
+        // more than there are
+        let mut amount = swarm.peers.len().min(count);
+        let mut peers = Vec::with_capacity(amount);
+        for mut candidates in [nearby, elsewhere] {
+            let chosen = match selection {
+                Selection::Random => {
+                    let amount = amount.min(candidates.len());
+                    // the sample keeps the map's order whenever it takes every candidate, which
+                    // would hand the first peers in the map out first every time
+                    let mut chosen = candidates.into_iter().choose_multiple(&mut rng, amount);
+                    chosen.shuffle(&mut rng);
+                    chosen
+                }
+                Selection::Recent => {
+                    // break ties on the address so the order never depends on the map's
+                    candidates.sort_by_key(|(peer, state)| {
+                        (Reverse(state.last_seen), peer.addr(), peer.peer_id)
+                    });
+                    candidates.truncate(amount);
+                    candidates
+                }
+            };
+            amount -= chosen.len();
+            peers.extend(chosen.into_iter().map(|(peer, _)| *peer));
+        }
+        peers
+    }
+
+    fn peer(&self, info_hash: &InfoHash, peer: &Peer) -> Option<PeerState> {
+        let torrents = lock(&self.torrents);
+        torrents.get(info_hash)?.peers.get(peer).cloned()
+    }
+
+    fn stats(&self, info_hash: &InfoHash, now: Instant) -> Option<SwarmInfo> {
+        let torrents = lock(&self.torrents);
+        torrents.get(info_hash).map(|swarm| swarm.info(now))
+    }
+
+    fn all_stats(&self, now: Instant) -> Vec<(InfoHash, SwarmInfo)> {
+        let torrents = lock(&self.torrents);
+        torrents
+            .iter()
+            .map(|(info_hash, swarm)| (*info_hash, swarm.info(now)))
+            .collect()
+    }
+
+    fn torrent_count(&self) -> usize {
+        lock(&self.torrents).len()
+    }
+
+    fn peer_count(&self) -> usize {
+        let torrents = lock(&self.torrents);
+        torrents.values().map(|swarm| swarm.peers.len()).sum()
+    }
+
+    fn reap(&self, now: Instant, timeout: Duration) {
+        let mut torrents = lock(&self.torrents);
+        torrents.retain(|_, swarm| {
+            let before = swarm.peers.len() + swarm.leaving.len();
+            swarm
+                .peers
+                .retain(|_, state| now.duration_since(state.last_seen) < timeout);
+            swarm.leaving.retain(|_, (_, until)| now < *until);
+            for _ in swarm.peers.len() + swarm.leaving.len()..before {
+                swarm.record_churn(now);
+            }
+            !swarm.peers.is_empty() || !swarm.leaving.is_empty()
+        });
+    }
+
+    fn swarm(&self, info_hash: &InfoHash) -> Option<Swarm> {
+        lock(&self.torrents).get(info_hash).cloned()
+    }
+
+    fn swarms(&self) -> Vec<(InfoHash, Swarm)> {
+        let torrents = lock(&self.torrents);
+        torrents
+            .iter()
+            .map(|(info_hash, swarm)| (*info_hash, swarm.clone()))
+            .collect()
+    }
+
+    fn peer_page(
+        &self,
+        info_hash: &InfoHash,
+        offset: usize,
+        limit: usize,
+    ) -> Option<(usize, Vec<(Peer, PeerState)>)> {
+        let torrents = lock(&self.torrents);
+        let swarm = torrents.get(info_hash)?;
+        // only the page is copied out, however big the swarm is
+        let mut peers: Vec<_> = swarm.peers.iter().collect();
+        peers.sort_unstable_by_key(|(peer, _)| (peer.addr(), peer.peer_id));
+        let page = peers
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(peer, state)| (*peer, state.clone()))
+            .collect();
+        Some((swarm.peers.len(), page))
+    }
+
+    fn restore(&self, swarms: Vec<(InfoHash, Swarm)>) {
+        *lock(&self.torrents) = swarms.into_iter().collect();
+    }
+
+    fn merge(&self, swarms: Vec<(InfoHash, Swarm)>) {
+        let mut torrents = lock(&self.torrents);
+        for (info_hash, swarm) in swarms {
+            match torrents.get_mut(&info_hash) {
+                Some(ours) => ours.merge(&swarm),
+                None => {
+                    torrents.insert(info_hash, swarm);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{PeerId, Transfer};
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+
+    fn leecher(now: Instant) -> PeerState {
+        PeerState {
+            seeder: false,
+            observer: false,
+            last_seen: now,
+            region: None,
+            left: None,
+            uploaded: Transfer::default(),
+            downloaded: Transfer::default(),
+        }
+    }
+
+    fn peer(port: u16) -> Peer {
+        Peer {
+            peer_id: PeerId([b'a'; 20]),
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+        }
+    }
+
+    // only implements the methods a store has to, and gets the others from the trait
+    struct MinimalStore(InMemoryStore);
+
+    impl PeerStore for MinimalStore {
+        fn register(
+            &self,
+            info_hash: InfoHash,
+            peer: Peer,
+            update: &dyn Fn(Option<&PeerState>) -> PeerState,
+        ) {
+            self.0.register(info_hash, peer, update)
+        }
+
+        fn remove(&self, info_hash: &InfoHash, peer: &Peer, now: Instant) -> bool {
+            self.0.remove(info_hash, peer, now)
+        }
+
+        fn sample(
+            &self,
+            info_hash: &InfoHash,
+            count: usize,
+            selection: Selection,
+            region: Option<&Region>,
+            eligible: &dyn Fn(&Peer, &PeerState) -> bool,
+        ) -> Vec<Peer> {
+            self.0.sample(info_hash, count, selection, region, eligible)
+        }
+
+        fn stats(&self, info_hash: &InfoHash, now: Instant) -> Option<SwarmInfo> {
+            self.0.stats(info_hash, now)
+        }
+
+        fn reap(&self, now: Instant, timeout: Duration) {
+            self.0.reap(now, timeout)
+        }
+    }
+
+    #[test]
+    fn default_methods() {
+        let store = MinimalStore(InMemoryStore::default());
+        let info_hash = InfoHash([b'a'; 20]);
+        let now = Instant::now();
+        for port in (1000..1005).rev() {
+            store.register(info_hash, peer(port), &|_| leecher(now));
+        }
+
+        let state = store.peer(&info_hash, &peer(1002)).unwrap();
+        assert_eq!(state.last_seen, now);
+        assert!(store.peer(&info_hash, &peer(2000)).is_none());
+        let page = |store: &dyn PeerStore| {
+            let (total, peers) = store.peer_page(&info_hash, 1, 2).unwrap();
+            let ports: Vec<u16> = peers.iter().map(|(peer, _)| peer.port).collect();
+            (total, ports)
+        };
+        assert_eq!(page(&store), (5, vec![1001, 1002]));
+        assert_eq!(page(&store), page(&store.0));
+        assert!(store.peer_page(&InfoHash([b'b'; 20]), 0, 10).is_none());
+
+        // leaving peers are removed right away
+        assert!(store.leave(&info_hash, &peer(1000), now + Duration::from_secs(60)));
+        assert!(store.peer(&info_hash, &peer(1000)).is_none());
+        assert!(!store.remove_swarm(&info_hash));
+        assert_eq!(store.stats(&info_hash, now).unwrap().peers, 4);
+    }
+
+    #[test]
+    fn remove_last_peer() {
+        let store = InMemoryStore::default();
+        let info_hash = InfoHash([b'a'; 20]);
+        let now = Instant::now();
+        store.register(info_hash, peer(1000), &|_| leecher(now));
+        store.register(info_hash, peer(1001), &|_| leecher(now));
+
+        assert!(store.remove(&info_hash, &peer(1000), now));
+        assert!(!store.remove(&info_hash, &peer(1000), now));
+        assert_eq!(store.stats(&info_hash, now).unwrap().leechers, 1);
+
+        // the swarm goes away with its last peer
+        assert!(store.remove(&info_hash, &peer(1001), now));
+        assert!(store.stats(&info_hash, now).is_none());
+    }
+
+    #[test]
+    fn reaped_peers_leave() {
+        let store = InMemoryStore::default();
+        let info_hash = InfoHash([b'a'; 20]);
+        let start = Instant::now();
+        let timeout = Duration::from_secs(60);
+        store.register(info_hash, peer(1000), &|_| leecher(start));
+        store.register(info_hash, peer(1001), &|_| leecher(start));
+        store.register(info_hash, peer(1002), &|_| leecher(start + timeout));
+
+        let now = start + timeout + Duration::from_secs(1);
+        store.reap(now, timeout);
+        let info = store.stats(&info_hash, now).unwrap();
+        assert_eq!((info.peers, info.churn), (1, 2));
+    }
+
+    #[test]
+    fn poisoned_lock() {
+        let store = Arc::new(InMemoryStore::default());
+        let info_hash = InfoHash([b'a'; 20]);
+        let now = Instant::now();
+        store.register(info_hash, peer(1000), &|_| leecher(now));
+
+        // a thread panicking while it holds the lock poisons it
+        let poisoner = store.clone();
+        let result = std::thread::spawn(move || {
+            let _torrents = poisoner.torrents.lock().unwrap();
+            panic!("oops");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(store.torrents.is_poisoned());
+
+        store.register(info_hash, peer(1001), &|_| leecher(now));
+        assert_eq!(store.stats(&info_hash, now).unwrap().leechers, 2);
+        assert_eq!(store.all_stats(now).len(), 1);
+        let sample = store.sample(&info_hash, 50, Selection::Random, None, &|_, _| true);
+        assert_eq!(sample.len(), 2);
+    }
+}
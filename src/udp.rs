@@ -0,0 +1,327 @@
+//! The packet format of the UDP tracker protocol (BEP 15), with the request string and options
+//! trailer of BEP 41.
+//!
+//! There's no UDP listener yet, this only reads and writes the packets. Every number is big
+//! endian. An announce request is 98 bytes, optionally followed by BEP 41 options:
+//!
+//! ```text
+//! connection_id (8) | action = 1 (4) | transaction_id (4) | info_hash (20) | peer_id (20) |
+//! downloaded (8) | left (8) | uploaded (8) | event (4) | ip (4) | key (4) | num_want (4) |
+//! port (2) | options...
+//! ```
+//!
+//! The response lists peers in the address family of the socket the request came in on: 6 bytes
+//! per IPv4 peer, and 18 bytes per IPv6 peer.
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Magic constant a client starts its connect request with.
+pub const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+
+const ACTION_ANNOUNCE: u32 = 1;
+const ANNOUNCE_LEN: usize = 98;
+
+// BEP 41 option types. Every other type is followed by a length byte, so it can be skipped.
+const OPTION_END: u8 = 0x0;
+const OPTION_NOP: u8 = 0x1;
+const OPTION_URL_DATA: u8 = 0x2;
+
+#[derive(Debug, PartialEq)]
+pub enum PacketError {
+    // The packet ends before all of its fields.
+    TooShort,
+    // The packet is for another action than the one we're reading.
+    WrongAction(u32),
+    // A BEP 41 option runs past the end of the packet.
+    TruncatedOption,
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TooShort => write!(f, "packet is too short"),
+            Self::WrongAction(action) => write!(f, "unexpected action {}", action),
+            Self::TruncatedOption => write!(f, "option runs past the end of the packet"),
+        }
+    }
+}
+
+impl Error for PacketError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    None,
+    Completed,
+    Started,
+    Stopped,
+}
+
+impl Event {
+    fn from_u32(event: u32) -> Self {
+        // like over HTTP, events we don't know are regular announces
+        match event {
+            1 => Self::Completed,
+            2 => Self::Started,
+            3 => Self::Stopped,
+            _ => Self::None,
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            Self::None => 0,
+            Self::Completed => 1,
+            Self::Started => 2,
+            Self::Stopped => 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnnounceRequest {
+    pub connection_id: u64,
+    pub transaction_id: u32,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    pub event: Event,
+    // The address the client wants to be reached at, or unspecified for the packet's source. This
+    // field only fits IPv4 addresses, so IPv6 clients are always reached at their source address.
+    pub ip: Option<Ipv4Addr>,
+    pub key: u32,
+    // Negative means the tracker's default.
+    pub num_want: i32,
+    pub port: u16,
+    // BEP 41: the path and query of the tracker's URL, like `/announce?auth=123`, put back
+    // together from every URLData option.
+    pub request_string: Vec<u8>,
+}
+
+impl AnnounceRequest {
+    pub fn from_bytes(packet: &[u8]) -> Result<Self, PacketError> {
+        if packet.len() < ANNOUNCE_LEN {
+            return Err(PacketError::TooShort);
+        }
+        let mut reader = Reader(packet);
+        let connection_id = reader.u64();
+        let action = reader.u32();
+        if action != ACTION_ANNOUNCE {
+            return Err(PacketError::WrongAction(action));
+        }
+        let req = Self {
+            connection_id,
+            transaction_id: reader.u32(),
+            info_hash: reader.array(),
+            peer_id: reader.array(),
+            downloaded: reader.u64(),
+            left: reader.u64(),
+            uploaded: reader.u64(),
+            event: Event::from_u32(reader.u32()),
+            ip: Some(Ipv4Addr::from(reader.u32())).filter(|ip| !ip.is_unspecified()),
+            key: reader.u32(),
+            num_want: reader.u32() as i32,
+            port: reader.u16(),
+            request_string: read_options(reader.0)?,
+        };
+        Ok(req)
+    }
+
+    /// Writes the request as a client would send it, with its request string in as many URLData
+    /// options as it takes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(ANNOUNCE_LEN + self.request_string.len());
+        packet.extend_from_slice(&self.connection_id.to_be_bytes());
+        packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        packet.extend_from_slice(&self.transaction_id.to_be_bytes());
+        packet.extend_from_slice(&self.info_hash);
+        packet.extend_from_slice(&self.peer_id);
+        packet.extend_from_slice(&self.downloaded.to_be_bytes());
+        packet.extend_from_slice(&self.left.to_be_bytes());
+        packet.extend_from_slice(&self.uploaded.to_be_bytes());
+        packet.extend_from_slice(&self.event.to_u32().to_be_bytes());
+        let ip = self.ip.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        packet.extend_from_slice(&ip.octets());
+        packet.extend_from_slice(&self.key.to_be_bytes());
+        packet.extend_from_slice(&self.num_want.to_be_bytes());
+        packet.extend_from_slice(&self.port.to_be_bytes());
+        if !self.request_string.is_empty() {
+            for chunk in self.request_string.chunks(usize::from(u8::MAX)) {
+                packet.push(OPTION_URL_DATA);
+                packet.push(chunk.len() as u8);
+                packet.extend_from_slice(chunk);
+            }
+            packet.push(OPTION_END);
+        }
+        packet
+    }
+}
+
+/// Reads the BEP 41 options after an announce, returning the request string they carry.
+fn read_options(mut options: &[u8]) -> Result<Vec<u8>, PacketError> {
+    let mut request_string = Vec::new();
+    while let Some((&kind, rest)) = options.split_first() {
+        options = rest;
+        match kind {
+            OPTION_END => break,
+            OPTION_NOP => continue,
+            _ => {
+                let (&len, rest) = options.split_first().ok_or(PacketError::TruncatedOption)?;
+                let len = usize::from(len);
+                if rest.len() < len {
+                    return Err(PacketError::TruncatedOption);
+                }
+                if kind == OPTION_URL_DATA {
+                    request_string.extend_from_slice(&rest[..len]);
+                }
+                options = &rest[len..];
+            }
+        }
+    }
+    Ok(request_string)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnnounceResponse {
+    pub transaction_id: u32,
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl AnnounceResponse {
+    /// Writes the response to a request that came in over IPv6 if `ipv6`, or else over IPv4.
+    /// Peers in the other family are left out, since the format can't mix the two.
+    pub fn to_bytes(&self, ipv6: bool) -> Vec<u8> {
+        let entry_len = if ipv6 { 18 } else { 6 };
+        let mut packet = Vec::with_capacity(20 + self.peers.len() * entry_len);
+        packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        packet.extend_from_slice(&self.transaction_id.to_be_bytes());
+        packet.extend_from_slice(&self.interval.to_be_bytes());
+        packet.extend_from_slice(&self.leechers.to_be_bytes());
+        packet.extend_from_slice(&self.seeders.to_be_bytes());
+        for peer in &self.peers {
+            match (peer.ip(), ipv6) {
+                (IpAddr::V4(ip), false) => packet.extend_from_slice(&ip.octets()),
+                (IpAddr::V6(ip), true) => packet.extend_from_slice(&ip.octets()),
+                _ => continue,
+            }
+            packet.extend_from_slice(&peer.port().to_be_bytes());
+        }
+        packet
+    }
+}
+
+/// Reads fixed size fields off the front of a packet that's already known to be long enough.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let (field, rest) = self.0.split_at(N);
+        self.0 = rest;
+        field.try_into().expect("split_at returned N bytes")
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.array())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_be_bytes(self.array())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_be_bytes(self.array())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    fn announce() -> AnnounceRequest {
+        AnnounceRequest {
+            connection_id: 0x1122_3344_5566_7788,
+            transaction_id: 42,
+            info_hash: *b"abcdefghijklmnopqrst",
+            peer_id: *b"-XX0001-abcdefghijkl",
+            downloaded: 1 << 33,
+            left: 100,
+            uploaded: 5,
+            event: Event::Started,
+            ip: None,
+            key: 7,
+            num_want: -1,
+            port: 6881,
+            request_string: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn ipv6_announce() {
+        let mut req = announce();
+        // long enough to take two URLData options
+        req.request_string = format!("/announce?auth={}", "x".repeat(300)).into_bytes();
+        let packet = req.to_bytes();
+        assert_eq!(packet.len(), ANNOUNCE_LEN + 2 + 255 + 2 + 60 + 1);
+        assert_eq!(AnnounceRequest::from_bytes(&packet), Ok(req));
+
+        let peers: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:6881".parse().unwrap(),
+            "10.0.0.1:6882".parse().unwrap(),
+            "[2001:db8::2]:51413".parse().unwrap(),
+        ];
+        let response = AnnounceResponse {
+            transaction_id: 42,
+            interval: 1800,
+            leechers: 2,
+            seeders: 1,
+            peers,
+        };
+        let packet = response.to_bytes(true);
+        assert_eq!(&packet[..4], &ACTION_ANNOUNCE.to_be_bytes());
+        assert_eq!(&packet[4..8], &42u32.to_be_bytes());
+        let entries: Vec<SocketAddr> = packet[20..]
+            .chunks(18)
+            .map(|entry| {
+                let ip: [u8; 16] = entry[..16].try_into().unwrap();
+                let port = u16::from_be_bytes([entry[16], entry[17]]);
+                SocketAddr::new(Ipv6Addr::from(ip).into(), port)
+            })
+            .collect();
+        assert_eq!(entries, [response.peers[0], response.peers[2]]);
+
+        // and the IPv4 socket gets the other one, in 6 bytes
+        assert_eq!(&response.to_bytes(false)[20..], &[10, 0, 0, 1, 0x1a, 0xe2]);
+    }
+
+    #[test]
+    fn announce_options() {
+        let base = announce().to_bytes();
+        let with = |options: &[u8]| AnnounceRequest::from_bytes(&[&base[..], options].concat());
+
+        // NOPs are skipped, and unknown options are skipped over by their length
+        let req = with(b"\x01\x02\x05/anno\x01\x07\x02ab\x02\x04unce\x00\x02\x03xyz").unwrap();
+        assert_eq!(req.request_string, b"/announce");
+        // nothing after the end option is read
+        assert_eq!(with(b"\x00\xff").unwrap().request_string, b"");
+
+        assert_eq!(with(b"\x02\x05/ann"), Err(PacketError::TruncatedOption));
+        assert_eq!(with(b"\x02"), Err(PacketError::TruncatedOption));
+        assert_eq!(
+            AnnounceRequest::from_bytes(&base[..ANNOUNCE_LEN - 1]),
+            Err(PacketError::TooShort)
+        );
+        let mut scrape = base;
+        scrape[11] = 2;
+        assert_eq!(
+            AnnounceRequest::from_bytes(&scrape),
+            Err(PacketError::WrongAction(2))
+        );
+    }
+}
@@ -0,0 +1,80 @@
+//! Runs the tracker binary's subcommands the way a user would.
+use bittorrent::metainfo::OwnedMetaInfo;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command, Output};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("cli-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn tracker(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_tracker"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn create() {
+    let dir = temp_dir("create");
+    let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    let path = dir.join("data.bin");
+    fs::write(&path, &data).unwrap();
+    let output = dir.join("out.torrent");
+
+    tracker(&[
+        "create",
+        path.to_str().unwrap(),
+        "--announce",
+        "http://tracker.example.org:6969/announce",
+        "--piece-length",
+        "32768",
+        "--output",
+        output.to_str().unwrap(),
+    ]);
+
+    let metainfo = OwnedMetaInfo::from_bytes(&fs::read(&output).unwrap()).unwrap();
+    assert_eq!(
+        metainfo.announce(),
+        Some("http://tracker.example.org:6969/announce")
+    );
+    assert_eq!(metainfo.info.name, "data.bin");
+    assert_eq!(metainfo.info.length, Some(300_000));
+    assert_eq!(metainfo.info.piece_length, 32768);
+    assert!(metainfo.verify_data(&dir).unwrap().is_complete());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn info() {
+    let fixture = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/single-file.torrent"
+    );
+    let output = tracker(&["info", fixture]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "info_hash: 7c0264b957a42157bdb2b8f09ea9f678d75d34d9",
+            "name: release-notes.txt",
+            "size: 97.7 KiB (100000 bytes)",
+            "pieces: 4 of 32.0 KiB (32768 bytes)",
+            "announce: http://tracker.example.org:6969/announce",
+            "announce: udp://backup.example.net:1337/announce",
+        ]
+    );
+}